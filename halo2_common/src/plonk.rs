@@ -13,6 +13,7 @@ use std::ops::{Add, AddAssign, Mul, MulAssign, Neg, Sub, SubAssign};
 pub mod circuit;
 pub mod error;
 pub mod keygen;
+pub mod lagrange;
 pub mod lookup;
 pub mod permutation;
 pub mod shuffle;
@@ -1,9 +1,11 @@
 use super::{lookup, permutation, shuffle, Error, Queries};
 use crate::circuit::layouter::SyncDeps;
 use crate::circuit::{Layouter, Region, Value};
+use crate::helpers::{SerdeFormat, SerdePrimeField};
 use crate::plonk::Assigned;
-use core::cmp::max;
-use core::ops::{Add, Mul};
+use blake2b_simd::Params as Blake2bParams;
+use core::cmp::{max, min};
+use core::ops::{Add, Mul, Range};
 use halo2_middleware::circuit::{
     Advice, AdviceQueryMid, Any, ChallengeMid, ColumnMid, ColumnType, ConstraintSystemV2Backend,
     ExpressionMid, Fixed, FixedQueryMid, GateV2Backend, Instance, InstanceQueryMid,
@@ -12,12 +14,14 @@ use halo2_middleware::ff::Field;
 use halo2_middleware::metadata;
 use halo2_middleware::poly::Rotation;
 use sealed::SealedPhase;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::io;
 use std::iter::{Product, Sum};
 use std::{
     convert::TryFrom,
-    ops::{Neg, Sub},
+    ops::{BitOr, Neg, Sub},
 };
 
 mod compress_selectors;
@@ -99,6 +103,19 @@ impl<C: ColumnType> Column<C> {
     }
 }
 
+impl Column<Instance> {
+    /// Returns an expression querying this instance column at `rotation`. The query
+    /// index is left unassigned until the expression is registered in a constraint
+    /// system.
+    pub fn query_at<F: Field>(&self, rotation: Rotation) -> Expression<F> {
+        Expression::Instance(InstanceQuery {
+            index: None,
+            column_index: self.index,
+            rotation,
+        })
+    }
+}
+
 impl<C: ColumnType> Ord for Column<C> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         // This ordering is consensus-critical! The layouters rely on deterministic column
@@ -204,10 +221,62 @@ impl TryFrom<Column<Any>> for Column<Instance> {
     }
 }
 
+/// Columns of a `Column<Any>` list, grouped by column type.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SplitColumns {
+    /// Advice columns, in their original order
+    pub advice: Vec<Column<Advice>>,
+    /// Fixed columns, in their original order
+    pub fixed: Vec<Column<Fixed>>,
+    /// Instance columns, in their original order
+    pub instance: Vec<Column<Instance>>,
+}
+
+/// Splits `columns` by column type, preserving the relative order within each type.
+pub fn split_columns(columns: &[Column<Any>]) -> SplitColumns {
+    let mut split = SplitColumns::default();
+    for column in columns.iter().copied() {
+        if let Ok(column) = Column::<Advice>::try_from(column) {
+            split.advice.push(column);
+        } else if let Ok(column) = Column::<Fixed>::try_from(column) {
+            split.fixed.push(column);
+        } else if let Ok(column) = Column::<Instance>::try_from(column) {
+            split.instance.push(column);
+        }
+    }
+    split
+}
+
+/// Returns the constraint `cell - k`, which is zero exactly when `cell` equals `k`.
+pub fn equals_constant<F: Field>(cell: Expression<F>, k: F) -> Expression<F> {
+    cell - Expression::Constant(k)
+}
+
+/// Returns the constraint `cell * (1 - cell)`, which is zero exactly when `cell` is
+/// either 0 or 1.
+pub fn is_boolean<F: Field>(cell: Expression<F>) -> Expression<F> {
+    cell.clone() * (Expression::Constant(F::ONE) - cell)
+}
+
+/// Returns the constraint `acc[next] - (acc[cur] + value)`, which is zero exactly when
+/// the accumulator column `acc` at the next row holds its value at the current row plus
+/// `value`.
+pub fn running_sum<F: Field>(acc: Column<Advice>, value: Expression<F>) -> Expression<F> {
+    acc.next() - (acc.cur() + value)
+}
+
+/// Returns the constraint `acc[next] - acc[cur] * value`, which is zero exactly when the
+/// accumulator column `acc` at the next row holds its value at the current row times
+/// `value`.
+pub fn running_product<F: Field>(acc: Column<Advice>, value: Expression<F>) -> Expression<F> {
+    acc.next() - acc.cur() * value
+}
+
 // TODO: Move sealed phase to frontend, and always use u8 in middleware and backend
 pub mod sealed {
     /// Phase of advice column
     #[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct Phase(pub u8);
 
     impl Phase {
@@ -315,6 +384,7 @@ impl SealedPhase for super::ThirdPhase {
 /// }
 /// ```
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Selector(pub usize, bool);
 
 impl Selector {
@@ -341,7 +411,8 @@ impl Selector {
 }
 
 /// Query of fixed column at a certain relative location
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FixedQuery {
     /// Query index
     pub index: Option<usize>,
@@ -364,7 +435,8 @@ impl FixedQuery {
 }
 
 /// Query of advice column at a certain relative location
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AdviceQuery {
     /// Query index
     pub index: Option<usize>,
@@ -394,7 +466,8 @@ impl AdviceQuery {
 }
 
 /// Query of instance column at a certain relative location
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InstanceQuery {
     /// Query index
     pub index: Option<usize>,
@@ -447,6 +520,7 @@ impl TableColumn {
 
 /// A challenge squeezed from transcript after advice columns at the phase have been committed.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Challenge {
     pub index: usize,
     pub(crate) phase: u8,
@@ -675,7 +749,8 @@ pub trait Circuit<F: Field> {
 // TODO: Create two types from this, one with selector for the frontend (this way we can move the
 // Layouter traits, Region and Selector to frontend).  And one without selector for the backend.
 /// Low-degree expression representing an identity that must hold over the committed columns.
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Expression<F> {
     /// This is a constant polynomial
     Constant(F),
@@ -699,6 +774,128 @@ pub enum Expression<F> {
     Scaled(Box<Expression<F>>, F),
 }
 
+/// The kind of an [`Expression`] node, without its contents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ExpressionKind {
+    /// A constant polynomial
+    Constant,
+    /// A virtual selector
+    Selector,
+    /// A fixed column query
+    Fixed,
+    /// An advice column query
+    Advice,
+    /// An instance column query
+    Instance,
+    /// A challenge
+    Challenge,
+    /// A negated polynomial
+    Negated,
+    /// The sum of two polynomials
+    Sum,
+    /// The product of two polynomials
+    Product,
+    /// A scaled polynomial
+    Scaled,
+}
+
+/// A node on the path along which an expression reaches its degree, as returned by
+/// [`Expression::max_degree_path`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DegreeStep {
+    /// Kind of the node
+    pub kind: ExpressionKind,
+    /// Degree contributed by the node. For a product this is the degree of the operand
+    /// that is not followed by the path, for a leaf it is the degree of the leaf itself.
+    pub degree: usize,
+}
+
+/// A rewrite rule for [`Expression::apply_rules`]. Subexpressions with the same
+/// [`Expression::template_identifier`] as `pattern` are replaced by `replacement`, where
+/// each advice column of the pattern stands for the advice column found at the same
+/// position in the matched subexpression.
+#[derive(Clone, Debug)]
+pub struct RewriteRule<F> {
+    /// Expression to match
+    pub pattern: Expression<F>,
+    /// Expression to substitute, over the advice columns of `pattern`
+    pub replacement: Expression<F>,
+}
+
+/// Number of field operations performed when evaluating an expression once, as
+/// returned by [`Expression::op_counts`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpCounts {
+    /// Number of additions
+    pub additions: usize,
+    /// Number of multiplications, including scalings by a constant
+    pub multiplications: usize,
+    /// Number of negations
+    pub negations: usize,
+}
+
+/// Sides of the lookup arguments on which a column is queried, as returned by
+/// [`ConstraintSystem::lookup_role`]. Roles combine as bit flags with `|`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LookupRole(u8);
+
+impl LookupRole {
+    /// The column is not queried by any lookup
+    pub const NONE: LookupRole = LookupRole(0);
+    /// The column is queried by the input expressions of a lookup
+    pub const INPUT: LookupRole = LookupRole(1);
+    /// The column is queried by the table expressions of a lookup
+    pub const TABLE: LookupRole = LookupRole(2);
+
+    /// Returns whether all the roles of `other` are included in this role.
+    pub fn contains(&self, other: LookupRole) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns whether the column is used as a lookup input.
+    pub fn is_input(&self) -> bool {
+        self.contains(LookupRole::INPUT)
+    }
+
+    /// Returns whether the column is used as a lookup table.
+    pub fn is_table(&self) -> bool {
+        self.contains(LookupRole::TABLE)
+    }
+}
+
+impl BitOr for LookupRole {
+    type Output = LookupRole;
+    fn bitor(self, rhs: LookupRole) -> LookupRole {
+        LookupRole(self.0 | rhs.0)
+    }
+}
+
+/// The operation performed by an [`ArithGate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ArithOp<F> {
+    /// `out` is an input wire carrying the value of a leaf expression
+    Input(Expression<F>),
+    /// `out = lhs + rhs`
+    Add,
+    /// `out = lhs * rhs`
+    Mul,
+}
+
+/// A gate of the arithmetic circuit produced by [`Expression::lower_to_gates`].
+///
+/// For [`ArithOp::Input`] gates `lhs` and `rhs` are unused and equal to `out`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArithGate<F> {
+    /// Operation of the gate
+    pub op: ArithOp<F>,
+    /// Left operand wire
+    pub lhs: usize,
+    /// Right operand wire
+    pub rhs: usize,
+    /// Output wire
+    pub out: usize,
+}
+
 impl<F> From<Expression<F>> for ExpressionMid<F> {
     fn from(val: Expression<F>) -> Self {
         match val {
@@ -743,7 +940,556 @@ impl<F> From<Expression<F>> for ExpressionMid<F> {
     }
 }
 
+impl<F> From<ExpressionMid<F>> for Expression<F> {
+    fn from(val: ExpressionMid<F>) -> Self {
+        match val {
+            ExpressionMid::Constant(c) => Expression::Constant(c),
+            ExpressionMid::Fixed(FixedQueryMid {
+                column_index,
+                rotation,
+            }) => Expression::Fixed(FixedQuery {
+                index: None,
+                column_index,
+                rotation,
+            }),
+            ExpressionMid::Advice(AdviceQueryMid {
+                column_index,
+                rotation,
+                phase,
+            }) => Expression::Advice(AdviceQuery {
+                index: None,
+                column_index,
+                rotation,
+                phase: sealed::Phase(phase),
+            }),
+            ExpressionMid::Instance(InstanceQueryMid {
+                column_index,
+                rotation,
+            }) => Expression::Instance(InstanceQuery {
+                index: None,
+                column_index,
+                rotation,
+            }),
+            ExpressionMid::Challenge(c) => Expression::Challenge(c.into()),
+            ExpressionMid::Negated(e) => Expression::Negated(Box::new((*e).into())),
+            ExpressionMid::Sum(lhs, rhs) => {
+                Expression::Sum(Box::new((*lhs).into()), Box::new((*rhs).into()))
+            }
+            ExpressionMid::Product(lhs, rhs) => {
+                Expression::Product(Box::new((*lhs).into()), Box::new((*rhs).into()))
+            }
+            ExpressionMid::Scaled(e, c) => Expression::Scaled(Box::new((*e).into()), c),
+        }
+    }
+}
+
+/// Writes an `ExpressionMid` to a buffer as a tagged prefix encoding, with field
+/// elements serialized according to `format`.
+pub(crate) fn write_expression_mid<F: SerdePrimeField, W: io::Write>(
+    expr: &ExpressionMid<F>,
+    writer: &mut W,
+    format: SerdeFormat,
+) -> io::Result<()> {
+    match expr {
+        ExpressionMid::Constant(c) => {
+            writer.write_all(&[0])?;
+            c.write(writer, format)
+        }
+        ExpressionMid::Fixed(q) => {
+            writer.write_all(&[1])?;
+            writer.write_all(&(q.column_index as u32).to_le_bytes())?;
+            writer.write_all(&q.rotation.0.to_le_bytes())
+        }
+        ExpressionMid::Advice(q) => {
+            writer.write_all(&[2, q.phase])?;
+            writer.write_all(&(q.column_index as u32).to_le_bytes())?;
+            writer.write_all(&q.rotation.0.to_le_bytes())
+        }
+        ExpressionMid::Instance(q) => {
+            writer.write_all(&[3])?;
+            writer.write_all(&(q.column_index as u32).to_le_bytes())?;
+            writer.write_all(&q.rotation.0.to_le_bytes())
+        }
+        ExpressionMid::Challenge(c) => {
+            writer.write_all(&[4, c.phase])?;
+            writer.write_all(&(c.index as u32).to_le_bytes())
+        }
+        ExpressionMid::Negated(e) => {
+            writer.write_all(&[5])?;
+            write_expression_mid(e, writer, format)
+        }
+        ExpressionMid::Sum(lhs, rhs) => {
+            writer.write_all(&[6])?;
+            write_expression_mid(lhs, writer, format)?;
+            write_expression_mid(rhs, writer, format)
+        }
+        ExpressionMid::Product(lhs, rhs) => {
+            writer.write_all(&[7])?;
+            write_expression_mid(lhs, writer, format)?;
+            write_expression_mid(rhs, writer, format)
+        }
+        ExpressionMid::Scaled(e, c) => {
+            writer.write_all(&[8])?;
+            write_expression_mid(e, writer, format)?;
+            c.write(writer, format)
+        }
+    }
+}
+
+/// Longest argument name accepted by [`read_argument`], in bytes.
+const MAX_ARGUMENT_NAME_LEN: u64 = 1 << 16;
+
+/// Deepest expression nesting accepted by [`read_expression_mid`].
+const MAX_EXPRESSION_DEPTH: usize = 1 << 10;
+
+/// Writes an argument's name followed by its expression columns, each lowered to
+/// `ExpressionMid`. Shared by the lookup and shuffle argument serializers. Arguments
+/// that [`read_argument`] would reject, with a name longer than `MAX_ARGUMENT_NAME_LEN`
+/// bytes or an expression deeper than `MAX_EXPRESSION_DEPTH`, are refused as invalid
+/// input before anything is written.
+pub(crate) fn write_argument<F: SerdePrimeField, W: io::Write>(
+    name: &str,
+    columns: [&[Expression<F>]; 2],
+    writer: &mut W,
+    format: SerdeFormat,
+) -> io::Result<()> {
+    if name.len() as u64 > MAX_ARGUMENT_NAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("argument name of {} bytes is too long", name.len()),
+        ));
+    }
+    if let Some(expr) = columns
+        .iter()
+        .flat_map(|exprs| exprs.iter())
+        .find(|expr| expr.depth() > MAX_EXPRESSION_DEPTH)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "expression of depth {} is nested deeper than {MAX_EXPRESSION_DEPTH}",
+                expr.depth()
+            ),
+        ));
+    }
+
+    writer.write_all(&(name.len() as u32).to_le_bytes())?;
+    writer.write_all(name.as_bytes())?;
+    for exprs in columns {
+        writer.write_all(&(exprs.len() as u32).to_le_bytes())?;
+        for expr in exprs {
+            write_expression_mid(&expr.clone().into(), writer, format)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads an argument written by [`write_argument`]. The returned expressions carry
+/// no query indices. Names longer than `MAX_ARGUMENT_NAME_LEN` bytes and expressions
+/// nested deeper than `MAX_EXPRESSION_DEPTH` are rejected as invalid data.
+#[allow(clippy::type_complexity)]
+pub(crate) fn read_argument<F: SerdePrimeField, R: io::Read>(
+    reader: &mut R,
+    format: SerdeFormat,
+) -> io::Result<(String, [Vec<Expression<F>>; 2])> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let name_len = u32::from_le_bytes(len) as u64;
+    if name_len > MAX_ARGUMENT_NAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("argument name of {name_len} bytes is too long"),
+        ));
+    }
+    let mut name = Vec::new();
+    io::Read::read_to_end(&mut io::Read::take(&mut *reader, name_len), &mut name)?;
+    if name.len() as u64 != name_len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let name =
+        String::from_utf8(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    let mut columns = [vec![], vec![]];
+    for exprs in columns.iter_mut() {
+        reader.read_exact(&mut len)?;
+        for _ in 0..u32::from_le_bytes(len) {
+            exprs.push(read_expression_mid(reader, format)?.into());
+        }
+    }
+    Ok((name, columns))
+}
+
+/// Reads an `ExpressionMid` written by [`write_expression_mid`]. Expressions nested
+/// deeper than `MAX_EXPRESSION_DEPTH` are rejected as invalid data. The encoding is
+/// parsed with an explicit stack, so malformed input cannot overflow the call stack.
+pub(crate) fn read_expression_mid<F: SerdePrimeField, R: io::Read>(
+    reader: &mut R,
+    format: SerdeFormat,
+) -> io::Result<ExpressionMid<F>> {
+    fn read_u8<R: io::Read>(reader: &mut R) -> io::Result<u8> {
+        let mut buf = [0u8; 1];
+        reader.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+    fn read_u32<R: io::Read>(reader: &mut R) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+    fn read_rotation<R: io::Read>(reader: &mut R) -> io::Result<Rotation> {
+        Ok(Rotation(read_u32(reader)? as i32))
+    }
+
+    // Operations whose operands are still being read.
+    enum Pending<F> {
+        Negated,
+        Sum,
+        SumRhs(ExpressionMid<F>),
+        Product,
+        ProductRhs(ExpressionMid<F>),
+        Scaled,
+    }
+
+    let mut pending: Vec<Pending<F>> = Vec::new();
+    loop {
+        if pending.len() >= MAX_EXPRESSION_DEPTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expression nested deeper than {MAX_EXPRESSION_DEPTH}"),
+            ));
+        }
+        let mut expr = match read_u8(reader)? {
+            0 => ExpressionMid::Constant(F::read(reader, format)?),
+            1 => ExpressionMid::Fixed(FixedQueryMid {
+                column_index: read_u32(reader)? as usize,
+                rotation: read_rotation(reader)?,
+            }),
+            2 => {
+                let phase = read_u8(reader)?;
+                ExpressionMid::Advice(AdviceQueryMid {
+                    column_index: read_u32(reader)? as usize,
+                    rotation: read_rotation(reader)?,
+                    phase,
+                })
+            }
+            3 => ExpressionMid::Instance(InstanceQueryMid {
+                column_index: read_u32(reader)? as usize,
+                rotation: read_rotation(reader)?,
+            }),
+            4 => {
+                let phase = read_u8(reader)?;
+                ExpressionMid::Challenge(ChallengeMid {
+                    index: read_u32(reader)? as usize,
+                    phase,
+                })
+            }
+            tag @ 5..=8 => {
+                pending.push(match tag {
+                    5 => Pending::Negated,
+                    6 => Pending::Sum,
+                    7 => Pending::Product,
+                    _ => Pending::Scaled,
+                });
+                continue;
+            }
+            tag => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown expression tag {}", tag),
+                ))
+            }
+        };
+
+        // Complete the operations whose last operand is `expr`.
+        loop {
+            expr = match pending.pop() {
+                None => return Ok(expr),
+                Some(Pending::Negated) => ExpressionMid::Negated(Box::new(expr)),
+                Some(Pending::Sum) => {
+                    pending.push(Pending::SumRhs(expr));
+                    break;
+                }
+                Some(Pending::SumRhs(lhs)) => ExpressionMid::Sum(Box::new(lhs), Box::new(expr)),
+                Some(Pending::Product) => {
+                    pending.push(Pending::ProductRhs(expr));
+                    break;
+                }
+                Some(Pending::ProductRhs(lhs)) => {
+                    ExpressionMid::Product(Box::new(lhs), Box::new(expr))
+                }
+                Some(Pending::Scaled) => {
+                    ExpressionMid::Scaled(Box::new(expr), F::read(reader, format)?)
+                }
+            };
+        }
+    }
+}
+
+/// A computation over an [`Expression`], driven by [`Expression::accept`]. Leaves are
+/// mapped to an `Output` by the corresponding method, and the outputs of the operands
+/// of each inner node are combined by the method of its operation.
+pub trait ExpressionVisitor<F: Field> {
+    /// The result of visiting an expression.
+    type Output;
+
+    /// Visits a constant.
+    fn constant(&mut self, scalar: F) -> Self::Output;
+
+    /// Visits a selector. Selectors are replaced by fixed columns when the constraint
+    /// system is compressed, so by default this panics.
+    fn selector(&mut self, _selector: Selector) -> Self::Output {
+        panic!("this visitor does not handle selectors")
+    }
+
+    /// Visits a fixed column query.
+    fn fixed(&mut self, query: FixedQuery) -> Self::Output;
+
+    /// Visits an advice column query.
+    fn advice(&mut self, query: AdviceQuery) -> Self::Output;
+
+    /// Visits an instance column query.
+    fn instance(&mut self, query: InstanceQuery) -> Self::Output;
+
+    /// Visits a challenge.
+    fn challenge(&mut self, challenge: Challenge) -> Self::Output;
+
+    /// Negates the output of the operand.
+    fn negated(&mut self, a: Self::Output) -> Self::Output;
+
+    /// Adds the outputs of the operands.
+    fn sum(&mut self, a: Self::Output, b: Self::Output) -> Self::Output;
+
+    /// Multiplies the outputs of the operands.
+    fn product(&mut self, a: Self::Output, b: Self::Output) -> Self::Output;
+
+    /// Scales the output of the operand by `scalar`.
+    fn scaled(&mut self, a: Self::Output, scalar: F) -> Self::Output;
+}
+
+/// Visitor calling the closures passed to [`Expression::evaluate`].
+struct ClosureVisitor<'a, C, S, FQ, AQ, IQ, Ch, N, Su, P, Sc> {
+    constant: &'a C,
+    selector_column: &'a S,
+    fixed_column: &'a FQ,
+    advice_column: &'a AQ,
+    instance_column: &'a IQ,
+    challenge: &'a Ch,
+    negated: &'a N,
+    sum: &'a Su,
+    product: &'a P,
+    scaled: &'a Sc,
+}
+
+impl<'a, F, T, C, S, FQ, AQ, IQ, Ch, N, Su, P, Sc> ExpressionVisitor<F>
+    for ClosureVisitor<'a, C, S, FQ, AQ, IQ, Ch, N, Su, P, Sc>
+where
+    F: Field,
+    C: Fn(F) -> T,
+    S: Fn(Selector) -> T,
+    FQ: Fn(FixedQuery) -> T,
+    AQ: Fn(AdviceQuery) -> T,
+    IQ: Fn(InstanceQuery) -> T,
+    Ch: Fn(Challenge) -> T,
+    N: Fn(T) -> T,
+    Su: Fn(T, T) -> T,
+    P: Fn(T, T) -> T,
+    Sc: Fn(T, F) -> T,
+{
+    type Output = T;
+
+    fn constant(&mut self, scalar: F) -> T {
+        (self.constant)(scalar)
+    }
+
+    fn selector(&mut self, selector: Selector) -> T {
+        (self.selector_column)(selector)
+    }
+
+    fn fixed(&mut self, query: FixedQuery) -> T {
+        (self.fixed_column)(query)
+    }
+
+    fn advice(&mut self, query: AdviceQuery) -> T {
+        (self.advice_column)(query)
+    }
+
+    fn instance(&mut self, query: InstanceQuery) -> T {
+        (self.instance_column)(query)
+    }
+
+    fn challenge(&mut self, challenge: Challenge) -> T {
+        (self.challenge)(challenge)
+    }
+
+    fn negated(&mut self, a: T) -> T {
+        (self.negated)(a)
+    }
+
+    fn sum(&mut self, a: T, b: T) -> T {
+        (self.sum)(a, b)
+    }
+
+    fn product(&mut self, a: T, b: T) -> T {
+        (self.product)(a, b)
+    }
+
+    fn scaled(&mut self, a: T, scalar: F) -> T {
+        (self.scaled)(a, scalar)
+    }
+}
+
+/// Visitor delegating to `inner`, but calling it only once per distinct selector,
+/// query or challenge and cloning the cached output for the following occurrences.
+struct LeafCache<V, T> {
+    inner: V,
+    selectors: HashMap<Selector, T>,
+    fixed: HashMap<FixedQuery, T>,
+    advice: HashMap<AdviceQuery, T>,
+    instance: HashMap<InstanceQuery, T>,
+    challenges: HashMap<Challenge, T>,
+}
+
+impl<F: Field, T: Clone, V: ExpressionVisitor<F, Output = T>> ExpressionVisitor<F>
+    for LeafCache<V, T>
+{
+    type Output = T;
+
+    fn constant(&mut self, scalar: F) -> T {
+        self.inner.constant(scalar)
+    }
+
+    fn selector(&mut self, selector: Selector) -> T {
+        let inner = &mut self.inner;
+        self.selectors
+            .entry(selector)
+            .or_insert_with(|| inner.selector(selector))
+            .clone()
+    }
+
+    fn fixed(&mut self, query: FixedQuery) -> T {
+        let inner = &mut self.inner;
+        self.fixed
+            .entry(query)
+            .or_insert_with(|| inner.fixed(query))
+            .clone()
+    }
+
+    fn advice(&mut self, query: AdviceQuery) -> T {
+        let inner = &mut self.inner;
+        self.advice
+            .entry(query)
+            .or_insert_with(|| inner.advice(query))
+            .clone()
+    }
+
+    fn instance(&mut self, query: InstanceQuery) -> T {
+        let inner = &mut self.inner;
+        self.instance
+            .entry(query)
+            .or_insert_with(|| inner.instance(query))
+            .clone()
+    }
+
+    fn challenge(&mut self, challenge: Challenge) -> T {
+        let inner = &mut self.inner;
+        self.challenges
+            .entry(challenge)
+            .or_insert_with(|| inner.challenge(challenge))
+            .clone()
+    }
+
+    fn negated(&mut self, a: T) -> T {
+        self.inner.negated(a)
+    }
+
+    fn sum(&mut self, a: T, b: T) -> T {
+        self.inner.sum(a, b)
+    }
+
+    fn product(&mut self, a: T, b: T) -> T {
+        self.inner.product(a, b)
+    }
+
+    fn scaled(&mut self, a: T, scalar: F) -> T {
+        self.inner.scaled(a, scalar)
+    }
+}
+
+/// Evaluates each of `exprs` using the provided closures, returning the same values as
+/// calling [`Expression::evaluate`] on each of them. The results of the leaf closures
+/// are cached across the whole batch, keyed on the selector, query or challenge, so
+/// that a leaf shared by several expressions is computed only once and cloned after.
+#[allow(clippy::too_many_arguments)]
+pub fn evaluate_many<F: Field, T: Clone>(
+    exprs: &[Expression<F>],
+    constant: &impl Fn(F) -> T,
+    selector_column: &impl Fn(Selector) -> T,
+    fixed_column: &impl Fn(FixedQuery) -> T,
+    advice_column: &impl Fn(AdviceQuery) -> T,
+    instance_column: &impl Fn(InstanceQuery) -> T,
+    challenge: &impl Fn(Challenge) -> T,
+    negated: &impl Fn(T) -> T,
+    sum: &impl Fn(T, T) -> T,
+    product: &impl Fn(T, T) -> T,
+    scaled: &impl Fn(T, F) -> T,
+) -> Vec<T> {
+    let mut visitor = LeafCache {
+        inner: ClosureVisitor {
+            constant,
+            selector_column,
+            fixed_column,
+            advice_column,
+            instance_column,
+            challenge,
+            negated,
+            sum,
+            product,
+            scaled,
+        },
+        selectors: HashMap::new(),
+        fixed: HashMap::new(),
+        advice: HashMap::new(),
+        instance: HashMap::new(),
+        challenges: HashMap::new(),
+    };
+    exprs.iter().map(|expr| expr.accept(&mut visitor)).collect()
+}
+
 impl<F: Field> Expression<F> {
+    /// Returns a query of the fixed column `column_index` at `rotation`. The query
+    /// `index` stays `None` until the expression is passed through
+    /// [`Expression::query_cells`], e.g. when it is used in a gate.
+    pub fn fixed(column_index: usize, rotation: Rotation) -> Self {
+        Expression::Fixed(FixedQuery {
+            index: None,
+            column_index,
+            rotation,
+        })
+    }
+
+    /// Returns a query of the advice column `column_index` of the given `phase` at
+    /// `rotation`. The query `index` stays `None` until the expression is passed
+    /// through [`Expression::query_cells`], e.g. when it is used in a gate.
+    pub fn advice(column_index: usize, rotation: Rotation, phase: u8) -> Self {
+        Expression::Advice(AdviceQuery {
+            index: None,
+            column_index,
+            rotation,
+            phase: sealed::Phase(phase),
+        })
+    }
+
+    /// Returns a query of the instance column `column_index` at `rotation`. The query
+    /// `index` stays `None` until the expression is passed through
+    /// [`Expression::query_cells`], e.g. when it is used in a gate.
+    pub fn instance(column_index: usize, rotation: Rotation) -> Self {
+        Expression::Instance(InstanceQuery {
+            index: None,
+            column_index,
+            rotation,
+        })
+    }
+
     /// Make side effects
     pub fn query_cells(&mut self, cells: &mut VirtualCells<'_, F>) {
         match self {
@@ -799,6 +1545,37 @@ impl<F: Field> Expression<F> {
         };
     }
 
+    /// Walks this expression bottom-up, combining the results of `visitor` on the
+    /// leaves with its operations on the inner nodes.
+    pub fn accept<V: ExpressionVisitor<F>>(&self, visitor: &mut V) -> V::Output {
+        match self {
+            Expression::Constant(scalar) => visitor.constant(*scalar),
+            Expression::Selector(selector) => visitor.selector(*selector),
+            Expression::Fixed(query) => visitor.fixed(*query),
+            Expression::Advice(query) => visitor.advice(*query),
+            Expression::Instance(query) => visitor.instance(*query),
+            Expression::Challenge(value) => visitor.challenge(*value),
+            Expression::Negated(a) => {
+                let a = a.accept(visitor);
+                visitor.negated(a)
+            }
+            Expression::Sum(a, b) => {
+                let a = a.accept(visitor);
+                let b = b.accept(visitor);
+                visitor.sum(a, b)
+            }
+            Expression::Product(a, b) => {
+                let a = a.accept(visitor);
+                let b = b.accept(visitor);
+                visitor.product(a, b)
+            }
+            Expression::Scaled(a, f) => {
+                let a = a.accept(visitor);
+                visitor.scaled(a, *f)
+            }
+        }
+    }
+
     /// Evaluate the polynomial using the provided closures to perform the
     /// operations.
     #[allow(clippy::too_many_arguments)]
@@ -815,98 +1592,207 @@ impl<F: Field> Expression<F> {
         product: &impl Fn(T, T) -> T,
         scaled: &impl Fn(T, F) -> T,
     ) -> T {
-        match self {
-            Expression::Constant(scalar) => constant(*scalar),
-            Expression::Selector(selector) => selector_column(*selector),
-            Expression::Fixed(query) => fixed_column(*query),
-            Expression::Advice(query) => advice_column(*query),
-            Expression::Instance(query) => instance_column(*query),
-            Expression::Challenge(value) => challenge(*value),
-            Expression::Negated(a) => {
-                let a = a.evaluate(
-                    constant,
-                    selector_column,
-                    fixed_column,
-                    advice_column,
-                    instance_column,
-                    challenge,
-                    negated,
-                    sum,
-                    product,
-                    scaled,
-                );
-                negated(a)
-            }
-            Expression::Sum(a, b) => {
-                let a = a.evaluate(
-                    constant,
-                    selector_column,
-                    fixed_column,
-                    advice_column,
-                    instance_column,
-                    challenge,
-                    negated,
-                    sum,
-                    product,
-                    scaled,
-                );
-                let b = b.evaluate(
-                    constant,
-                    selector_column,
-                    fixed_column,
-                    advice_column,
-                    instance_column,
-                    challenge,
-                    negated,
-                    sum,
-                    product,
-                    scaled,
-                );
-                sum(a, b)
-            }
-            Expression::Product(a, b) => {
-                let a = a.evaluate(
-                    constant,
-                    selector_column,
-                    fixed_column,
-                    advice_column,
-                    instance_column,
-                    challenge,
-                    negated,
-                    sum,
-                    product,
-                    scaled,
-                );
-                let b = b.evaluate(
-                    constant,
-                    selector_column,
-                    fixed_column,
-                    advice_column,
-                    instance_column,
-                    challenge,
-                    negated,
-                    sum,
-                    product,
-                    scaled,
-                );
-                product(a, b)
-            }
-            Expression::Scaled(a, f) => {
-                let a = a.evaluate(
-                    constant,
-                    selector_column,
-                    fixed_column,
-                    advice_column,
-                    instance_column,
-                    challenge,
-                    negated,
-                    sum,
-                    product,
-                    scaled,
-                );
-                scaled(a, *f)
+        self.accept(&mut ClosureVisitor {
+            constant,
+            selector_column,
+            fixed_column,
+            advice_column,
+            instance_column,
+            challenge,
+            negated,
+            sum,
+            product,
+            scaled,
+        })
+    }
+
+    /// Evaluate the polynomial using the provided closures to perform the operations,
+    /// like [`Expression::evaluate`], but walking the tree with an explicit work stack
+    /// instead of recursion, so that arbitrarily deep expressions can be evaluated.
+    /// Operands are visited in the same order as in `evaluate`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn evaluate_iter<T>(
+        &self,
+        constant: &impl Fn(F) -> T,
+        selector_column: &impl Fn(Selector) -> T,
+        fixed_column: &impl Fn(FixedQuery) -> T,
+        advice_column: &impl Fn(AdviceQuery) -> T,
+        instance_column: &impl Fn(InstanceQuery) -> T,
+        challenge: &impl Fn(Challenge) -> T,
+        negated: &impl Fn(T) -> T,
+        sum: &impl Fn(T, T) -> T,
+        product: &impl Fn(T, T) -> T,
+        scaled: &impl Fn(T, F) -> T,
+    ) -> T {
+        enum Task<'a, F> {
+            Visit(&'a Expression<F>),
+            Negated,
+            Sum,
+            Product,
+            Scaled(F),
+        }
+
+        let mut tasks = vec![Task::Visit(self)];
+        let mut values: Vec<T> = Vec::new();
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Visit(expr) => match expr {
+                    Expression::Constant(scalar) => values.push(constant(*scalar)),
+                    Expression::Selector(selector) => values.push(selector_column(*selector)),
+                    Expression::Fixed(query) => values.push(fixed_column(*query)),
+                    Expression::Advice(query) => values.push(advice_column(*query)),
+                    Expression::Instance(query) => values.push(instance_column(*query)),
+                    Expression::Challenge(value) => values.push(challenge(*value)),
+                    Expression::Negated(a) => {
+                        tasks.push(Task::Negated);
+                        tasks.push(Task::Visit(a));
+                    }
+                    Expression::Sum(a, b) => {
+                        tasks.push(Task::Sum);
+                        tasks.push(Task::Visit(b));
+                        tasks.push(Task::Visit(a));
+                    }
+                    Expression::Product(a, b) => {
+                        tasks.push(Task::Product);
+                        tasks.push(Task::Visit(b));
+                        tasks.push(Task::Visit(a));
+                    }
+                    Expression::Scaled(a, f) => {
+                        tasks.push(Task::Scaled(*f));
+                        tasks.push(Task::Visit(a));
+                    }
+                },
+                Task::Negated => {
+                    let a = values.pop().unwrap();
+                    values.push(negated(a));
+                }
+                Task::Sum => {
+                    let b = values.pop().unwrap();
+                    let a = values.pop().unwrap();
+                    values.push(sum(a, b));
+                }
+                Task::Product => {
+                    let b = values.pop().unwrap();
+                    let a = values.pop().unwrap();
+                    values.push(product(a, b));
+                }
+                Task::Scaled(f) => {
+                    let a = values.pop().unwrap();
+                    values.push(scaled(a, f));
+                }
             }
         }
+        values.pop().unwrap()
+    }
+
+    /// Evaluate the polynomial using the provided closures to perform the operations,
+    /// like [`Expression::evaluate`], but evaluating each distinct subexpression only
+    /// once. Subexpressions are identified structurally, queries by their column and
+    /// rotation and operations by their operands, and the value of a repeated
+    /// subexpression is cloned from the first time it was computed. The tree is walked
+    /// with an explicit stack, so this does not overflow the call stack on deep
+    /// expressions.
+    #[allow(clippy::too_many_arguments)]
+    pub fn evaluate_cse<T: Clone>(
+        &self,
+        constant: &impl Fn(F) -> T,
+        selector_column: &impl Fn(Selector) -> T,
+        fixed_column: &impl Fn(FixedQuery) -> T,
+        advice_column: &impl Fn(AdviceQuery) -> T,
+        instance_column: &impl Fn(InstanceQuery) -> T,
+        challenge: &impl Fn(Challenge) -> T,
+        negated: &impl Fn(T) -> T,
+        sum: &impl Fn(T, T) -> T,
+        product: &impl Fn(T, T) -> T,
+        scaled: &impl Fn(T, F) -> T,
+    ) -> T {
+        enum Task<'a, F> {
+            Visit(&'a Expression<F>),
+            Negated,
+            Sum,
+            Product,
+            Scaled(F),
+        }
+
+        let mut memo = CseMemo {
+            ids: HashMap::new(),
+            values: Vec::new(),
+        };
+        let mut tasks = vec![Task::Visit(self)];
+        let mut ids: Vec<usize> = Vec::new();
+        while let Some(task) = tasks.pop() {
+            let id = match task {
+                Task::Visit(expr) => match expr {
+                    Expression::Constant(scalar) => {
+                        memo.intern(CseKey::Constant(*scalar), |_| constant(*scalar))
+                    }
+                    Expression::Selector(selector) => {
+                        memo.intern(CseKey::Selector(*selector), |_| selector_column(*selector))
+                    }
+                    Expression::Fixed(query) => memo
+                        .intern(CseKey::Fixed(query.column_index, query.rotation), |_| {
+                            fixed_column(*query)
+                        }),
+                    Expression::Advice(query) => memo.intern(
+                        CseKey::Advice(query.column_index, query.rotation, query.phase),
+                        |_| advice_column(*query),
+                    ),
+                    Expression::Instance(query) => memo
+                        .intern(CseKey::Instance(query.column_index, query.rotation), |_| {
+                            instance_column(*query)
+                        }),
+                    Expression::Challenge(value) => {
+                        memo.intern(CseKey::Challenge(*value), |_| challenge(*value))
+                    }
+                    Expression::Negated(a) => {
+                        tasks.push(Task::Negated);
+                        tasks.push(Task::Visit(a));
+                        continue;
+                    }
+                    Expression::Sum(a, b) => {
+                        tasks.push(Task::Sum);
+                        tasks.push(Task::Visit(b));
+                        tasks.push(Task::Visit(a));
+                        continue;
+                    }
+                    Expression::Product(a, b) => {
+                        tasks.push(Task::Product);
+                        tasks.push(Task::Visit(b));
+                        tasks.push(Task::Visit(a));
+                        continue;
+                    }
+                    Expression::Scaled(a, f) => {
+                        tasks.push(Task::Scaled(*f));
+                        tasks.push(Task::Visit(a));
+                        continue;
+                    }
+                },
+                Task::Negated => {
+                    let a = ids.pop().unwrap();
+                    memo.intern(CseKey::Negated(a), |values| negated(values[a].clone()))
+                }
+                Task::Sum => {
+                    let b = ids.pop().unwrap();
+                    let a = ids.pop().unwrap();
+                    memo.intern(CseKey::Sum(a, b), |values| {
+                        sum(values[a].clone(), values[b].clone())
+                    })
+                }
+                Task::Product => {
+                    let b = ids.pop().unwrap();
+                    let a = ids.pop().unwrap();
+                    memo.intern(CseKey::Product(a, b), |values| {
+                        product(values[a].clone(), values[b].clone())
+                    })
+                }
+                Task::Scaled(f) => {
+                    let a = ids.pop().unwrap();
+                    memo.intern(CseKey::Scaled(a, f), |values| scaled(values[a].clone(), f))
+                }
+            };
+            ids.push(id);
+        }
+        memo.values.swap_remove(ids.pop().unwrap())
     }
 
     /// Evaluate the polynomial lazily using the provided closures to perform the
@@ -1037,6 +1923,15 @@ impl<F: Field> Expression<F> {
     }
 
     fn write_identifier<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.write_identifier_with(writer, &mut |column_index| column_index.to_string())
+    }
+
+    /// Writes the identifier of this expression, naming advice columns with `advice_column`.
+    fn write_identifier_with<W: std::io::Write>(
+        &self,
+        writer: &mut W,
+        advice_column: &mut impl FnMut(usize) -> String,
+    ) -> std::io::Result<()> {
         match self {
             Expression::Constant(scalar) => write!(writer, "{scalar:?}"),
             Expression::Selector(selector) => write!(writer, "selector[{}]", selector.0),
@@ -1051,8 +1946,16 @@ impl<F: Field> Expression<F> {
                 write!(
                     writer,
                     "advice[{}][{}]",
-                    query.column_index, query.rotation.0
-                )
+                    advice_column(query.column_index),
+                    query.rotation.0
+                )?;
+                // Advice columns of different phases are distinct cells even if they have
+                // the same index. The phase is omitted for the first phase so that the
+                // identifiers of single-phase circuits are unchanged.
+                if query.phase != FirstPhase.to_sealed() {
+                    write!(writer, "[{}]", query.phase.0)?;
+                }
+                Ok(())
             }
             Expression::Instance(query) => {
                 write!(
@@ -1066,25 +1969,25 @@ impl<F: Field> Expression<F> {
             }
             Expression::Negated(a) => {
                 writer.write_all(b"(-")?;
-                a.write_identifier(writer)?;
+                a.write_identifier_with(writer, advice_column)?;
                 writer.write_all(b")")
             }
             Expression::Sum(a, b) => {
                 writer.write_all(b"(")?;
-                a.write_identifier(writer)?;
+                a.write_identifier_with(writer, advice_column)?;
                 writer.write_all(b"+")?;
-                b.write_identifier(writer)?;
+                b.write_identifier_with(writer, advice_column)?;
                 writer.write_all(b")")
             }
             Expression::Product(a, b) => {
                 writer.write_all(b"(")?;
-                a.write_identifier(writer)?;
+                a.write_identifier_with(writer, advice_column)?;
                 writer.write_all(b"*")?;
-                b.write_identifier(writer)?;
+                b.write_identifier_with(writer, advice_column)?;
                 writer.write_all(b")")
             }
             Expression::Scaled(a, f) => {
-                a.write_identifier(writer)?;
+                a.write_identifier_with(writer, advice_column)?;
                 write!(writer, "*{f:?}")
             }
         }
@@ -1099,6 +2002,67 @@ impl<F: Field> Expression<F> {
         String::from_utf8(cursor.into_inner()).unwrap()
     }
 
+    /// Identifier for the shape of this expression. It matches [`Expression::identifier`]
+    /// except that advice column indices are replaced by the placeholders `$0`, `$1`, ...
+    /// in order of first occurrence, so expressions of the same shape over different
+    /// advice columns share a template identifier.
+    pub fn template_identifier(&self) -> String {
+        let mut advice_columns: Vec<usize> = Vec::new();
+        let mut placeholder = |column_index: usize| {
+            let position = advice_columns
+                .iter()
+                .position(|&column| column == column_index)
+                .unwrap_or_else(|| {
+                    advice_columns.push(column_index);
+                    advice_columns.len() - 1
+                });
+            format!("${position}")
+        };
+
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        self.write_identifier_with(&mut cursor, &mut placeholder)
+            .unwrap();
+        String::from_utf8(cursor.into_inner()).unwrap()
+    }
+
+    /// Renders this expression in infix notation as its [`std::fmt::Display`]
+    /// implementation does, but naming the queried columns and challenges with the
+    /// provided closures. Queries at a non-zero rotation are suffixed with `@rot`.
+    pub fn render(
+        &self,
+        name_advice: &impl Fn(usize) -> String,
+        name_fixed: &impl Fn(usize) -> String,
+        name_instance: &impl Fn(usize) -> String,
+        name_challenge: &impl Fn(usize) -> String,
+    ) -> String {
+        let with_rotation = |name: String, rotation: Rotation| match rotation.0 {
+            0 => name,
+            rotation => format!("{name}@{rotation}"),
+        };
+        let leaf = |expr: &Expression<F>, f: &mut std::fmt::Formatter<'_>| match expr {
+            Expression::Fixed(query) => write!(
+                f,
+                "{}",
+                with_rotation(name_fixed(query.column_index), query.rotation)
+            ),
+            Expression::Advice(query) => write!(
+                f,
+                "{}",
+                with_rotation(name_advice(query.column_index), query.rotation)
+            ),
+            Expression::Instance(query) => write!(
+                f,
+                "{}",
+                with_rotation(name_instance(query.column_index), query.rotation)
+            ),
+            Expression::Challenge(challenge) => {
+                write!(f, "{}", name_challenge(challenge.index()))
+            }
+            _ => fmt_leaf(expr, f),
+        };
+        Infix { expr: self, leaf }.to_string()
+    }
+
     /// Compute the degree of this polynomial
     pub fn degree(&self) -> usize {
         match self {
@@ -1115,552 +2079,1962 @@ impl<F: Field> Expression<F> {
         }
     }
 
-    /// Approximate the computational complexity of this expression.
-    pub fn complexity(&self) -> usize {
-        match self {
-            Expression::Constant(_) => 0,
-            Expression::Selector(_) => 1,
-            Expression::Fixed(_) => 1,
-            Expression::Advice(_) => 1,
-            Expression::Instance(_) => 1,
-            Expression::Challenge(_) => 0,
-            Expression::Negated(poly) => poly.complexity() + 5,
-            Expression::Sum(a, b) => a.complexity() + b.complexity() + 15,
-            Expression::Product(a, b) => a.complexity() + b.complexity() + 30,
-            Expression::Scaled(poly, _) => poly.complexity() + 30,
-        }
-    }
-
-    /// Square this expression.
-    pub fn square(self) -> Self {
-        self.clone() * self
-    }
-
-    /// Returns whether or not this expression contains a simple `Selector`.
-    fn contains_simple_selector(&self) -> bool {
+    /// Compute the degree of this polynomial, treating queries to the columns in
+    /// `constant_columns`, given as `(column_type, column_index)`, as constants of
+    /// degree zero. Advice columns are matched by index alone, whatever the phase given
+    /// in their column type.
+    pub fn degree_excluding(&self, constant_columns: &HashSet<(Any, usize)>) -> usize {
+        // Advice column indices are unique across phases, so the phase is normalised away.
+        let constant_columns: HashSet<(Any, usize)> = constant_columns
+            .iter()
+            .map(|(column_type, column_index)| match column_type {
+                Any::Advice(_) => (Any::advice(), *column_index),
+                _ => (*column_type, *column_index),
+            })
+            .collect();
+        let query_degree = |column_type: Any, column_index: usize| {
+            usize::from(!constant_columns.contains(&(column_type, column_index)))
+        };
         self.evaluate(
-            &|_| false,
-            &|selector| selector.is_simple(),
-            &|_| false,
-            &|_| false,
-            &|_| false,
-            &|_| false,
+            &|_| 0,
+            &|_| 1,
+            &|query| query_degree(Any::Fixed, query.column_index),
+            &|query| query_degree(Any::advice(), query.column_index),
+            &|query| query_degree(Any::Instance, query.column_index),
+            &|_| 0,
             &|a| a,
-            &|a, b| a || b,
-            &|a, b| a || b,
+            &max,
+            &|a, b| a + b,
             &|a, _| a,
         )
     }
 
-    /// Extracts a simple selector from this gate, if present
-    fn extract_simple_selector(&self) -> Option<Selector> {
-        let op = |a, b| match (a, b) {
-            (Some(a), None) | (None, Some(a)) => Some(a),
-            (Some(_), Some(_)) => panic!("two simple selectors cannot be in the same expression"),
-            _ => None,
-        };
-
-        self.evaluate(
-            &|_| None,
-            &|selector| {
-                if selector.is_simple() {
-                    Some(selector)
-                } else {
-                    None
-                }
+    /// Returns the path from the root of this expression down to a leaf along which
+    /// its degree accumulates. At every node the operand of highest degree is followed,
+    /// so the degrees contributed by the steps add up to [`Expression::degree`].
+    pub fn max_degree_path(&self) -> Vec<DegreeStep> {
+        // Computes the degree of every node once, bottom-up, together with its path
+        // listed from the leaf up, so that each node only pushes its own step.
+        let step = |kind, degree| DegreeStep { kind, degree };
+        let leaf = |kind, degree| (degree, vec![step(kind, degree)]);
+        let (_, mut path) = self.evaluate(
+            &|_| leaf(ExpressionKind::Constant, 0),
+            &|_| leaf(ExpressionKind::Selector, 1),
+            &|_| leaf(ExpressionKind::Fixed, 1),
+            &|_| leaf(ExpressionKind::Advice, 1),
+            &|_| leaf(ExpressionKind::Instance, 1),
+            &|_| leaf(ExpressionKind::Challenge, 0),
+            &|(degree, mut path)| {
+                path.push(step(ExpressionKind::Negated, 0));
+                (degree, path)
             },
-            &|_| None,
-            &|_| None,
-            &|_| None,
-            &|_| None,
-            &|a| a,
-            &op,
-            &op,
-            &|a, _| a,
-        )
+            &|a, b| {
+                let (degree, mut path) = if a.0 >= b.0 { a } else { b };
+                path.push(step(ExpressionKind::Sum, 0));
+                (degree, path)
+            },
+            &|a, b| {
+                let ((followed, mut path), other) = if a.0 >= b.0 { (a, b.0) } else { (b, a.0) };
+                path.push(step(ExpressionKind::Product, other));
+                (followed + other, path)
+            },
+            &|(degree, mut path), _| {
+                path.push(step(ExpressionKind::Scaled, 0));
+                (degree, path)
+            },
+        );
+        path.reverse();
+        path
     }
-}
 
-impl<F: std::fmt::Debug> std::fmt::Debug for Expression<F> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    /// Returns the value of this expression if it is statically a constant, i.e. if it
+    /// only combines constants, or if it is multiplied by a constant zero. Selectors,
+    /// column queries and challenges are not constant.
+    pub fn as_constant(&self) -> Option<F> {
         match self {
-            Expression::Constant(scalar) => f.debug_tuple("Constant").field(scalar).finish(),
-            Expression::Selector(selector) => f.debug_tuple("Selector").field(selector).finish(),
-            // Skip enum variant and print query struct directly to maintain backwards compatibility.
-            Expression::Fixed(query) => {
-                let mut debug_struct = f.debug_struct("Fixed");
-                match query.index {
-                    None => debug_struct.field("query_index", &query.index),
-                    Some(idx) => debug_struct.field("query_index", &idx),
-                };
-                debug_struct
-                    .field("column_index", &query.column_index)
-                    .field("rotation", &query.rotation)
-                    .finish()
-            }
-            Expression::Advice(query) => {
-                let mut debug_struct = f.debug_struct("Advice");
-                match query.index {
-                    None => debug_struct.field("query_index", &query.index),
-                    Some(idx) => debug_struct.field("query_index", &idx),
-                };
-                debug_struct
-                    .field("column_index", &query.column_index)
-                    .field("rotation", &query.rotation);
-                // Only show advice's phase if it's not in first phase.
-                if query.phase != FirstPhase.to_sealed() {
-                    debug_struct.field("phase", &query.phase);
-                }
-                debug_struct.finish()
-            }
-            Expression::Instance(query) => {
-                let mut debug_struct = f.debug_struct("Instance");
-                match query.index {
-                    None => debug_struct.field("query_index", &query.index),
-                    Some(idx) => debug_struct.field("query_index", &idx),
-                };
-                debug_struct
-                    .field("column_index", &query.column_index)
-                    .field("rotation", &query.rotation)
-                    .finish()
-            }
-            Expression::Challenge(challenge) => {
-                f.debug_tuple("Challenge").field(challenge).finish()
-            }
-            Expression::Negated(poly) => f.debug_tuple("Negated").field(poly).finish(),
-            Expression::Sum(a, b) => f.debug_tuple("Sum").field(a).field(b).finish(),
-            Expression::Product(a, b) => f.debug_tuple("Product").field(a).field(b).finish(),
-            Expression::Scaled(poly, scalar) => {
-                f.debug_tuple("Scaled").field(poly).field(scalar).finish()
-            }
+            Expression::Constant(scalar) => Some(*scalar),
+            Expression::Negated(a) => a.as_constant().map(|a| -a),
+            Expression::Sum(a, b) => Some(a.as_constant()? + b.as_constant()?),
+            Expression::Product(a, b) => match (a.as_constant(), b.as_constant()) {
+                (Some(a), Some(b)) => Some(a * b),
+                (Some(zero), _) | (_, Some(zero)) if zero == F::ZERO => Some(F::ZERO),
+                _ => None,
+            },
+            Expression::Scaled(_, f) if *f == F::ZERO => Some(F::ZERO),
+            Expression::Scaled(a, f) => a.as_constant().map(|a| a * f),
+            _ => None,
         }
     }
-}
 
-impl<F: Field> Neg for Expression<F> {
-    type Output = Expression<F>;
-    fn neg(self) -> Self::Output {
-        Expression::Negated(Box::new(self))
+    /// Returns whether this expression is statically a constant, see
+    /// [`Expression::as_constant`].
+    pub fn is_constant(&self) -> bool {
+        self.as_constant().is_some()
     }
-}
 
-impl<F: Field> Add for Expression<F> {
-    type Output = Expression<F>;
-    fn add(self, rhs: Expression<F>) -> Expression<F> {
-        if self.contains_simple_selector() || rhs.contains_simple_selector() {
-            panic!("attempted to use a simple selector in an addition");
-        }
-        Expression::Sum(Box::new(self), Box::new(rhs))
+    /// Returns whether this expression is statically the constant zero, see
+    /// [`Expression::as_constant`].
+    pub fn is_zero(&self) -> bool {
+        self.as_constant() == Some(F::ZERO)
     }
-}
 
-impl<F: Field> Sub for Expression<F> {
-    type Output = Expression<F>;
-    fn sub(self, rhs: Expression<F>) -> Expression<F> {
-        if self.contains_simple_selector() || rhs.contains_simple_selector() {
-            panic!("attempted to use a simple selector in a subtraction");
-        }
-        Expression::Sum(Box::new(self), Box::new(-rhs))
+    /// Returns whether this expression has degree exactly one.
+    pub fn is_degree_one(&self) -> bool {
+        self.degree() == 1
     }
-}
 
-impl<F: Field> Mul for Expression<F> {
-    type Output = Expression<F>;
-    fn mul(self, rhs: Expression<F>) -> Expression<F> {
-        if self.contains_simple_selector() && rhs.contains_simple_selector() {
-            panic!("attempted to multiply two expressions containing simple selectors");
-        }
-        Expression::Product(Box::new(self), Box::new(rhs))
+    /// If this expression is affine in its column queries with numeric coefficients,
+    /// returns the coefficient of each queried `(column, rotation)`, in order of first
+    /// occurrence. Terms whose coefficients cancel out are omitted, as is the constant
+    /// term. Returns `None` if the expression multiplies queries together or involves
+    /// selectors or challenges.
+    pub fn linear_terms(&self) -> Option<Vec<(Column<Any>, Rotation, F)>> {
+        self.linear_form().map(|(_, terms)| {
+            terms
+                .into_iter()
+                .filter(|(_, _, coeff)| !bool::from(coeff.is_zero()))
+                .collect()
+        })
     }
-}
 
-impl<F: Field> Mul<F> for Expression<F> {
-    type Output = Expression<F>;
-    fn mul(self, rhs: F) -> Expression<F> {
-        Expression::Scaled(Box::new(self), rhs)
-    }
-}
+    /// Returns the constant term and the column terms of this expression, if it is
+    /// affine with numeric coefficients.
+    #[allow(clippy::type_complexity)]
+    fn linear_form(&self) -> Option<(F, Vec<(Column<Any>, Rotation, F)>)> {
+        fn scale<F: Field>(
+            (constant, terms): (F, Vec<(Column<Any>, Rotation, F)>),
+            factor: F,
+        ) -> (F, Vec<(Column<Any>, Rotation, F)>) {
+            let terms = terms
+                .into_iter()
+                .map(|(column, rotation, coeff)| (column, rotation, coeff * factor))
+                .collect();
+            (constant * factor, terms)
+        }
 
-impl<F: Field> Sum<Self> for Expression<F> {
-    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
-        iter.reduce(|acc, x| acc + x)
-            .unwrap_or(Expression::Constant(F::ZERO))
+        match self {
+            Expression::Constant(c) => Some((*c, vec![])),
+            Expression::Selector(_) | Expression::Challenge(_) => None,
+            Expression::Fixed(query) => Some((
+                F::ZERO,
+                vec![(
+                    Column::new(query.column_index, Any::Fixed),
+                    query.rotation,
+                    F::ONE,
+                )],
+            )),
+            Expression::Advice(query) => Some((
+                F::ZERO,
+                vec![(
+                    Column::new(query.column_index, Any::Advice(Advice::new(query.phase.0))),
+                    query.rotation,
+                    F::ONE,
+                )],
+            )),
+            Expression::Instance(query) => Some((
+                F::ZERO,
+                vec![(
+                    Column::new(query.column_index, Any::Instance),
+                    query.rotation,
+                    F::ONE,
+                )],
+            )),
+            Expression::Negated(a) => Some(scale(a.linear_form()?, -F::ONE)),
+            Expression::Scaled(a, f) => Some(scale(a.linear_form()?, *f)),
+            Expression::Sum(a, b) => {
+                let (a_constant, mut terms) = a.linear_form()?;
+                let (b_constant, b_terms) = b.linear_form()?;
+                for (column, rotation, coeff) in b_terms {
+                    match terms
+                        .iter_mut()
+                        .find(|(c, r, _)| *c == column && *r == rotation)
+                    {
+                        Some((_, _, existing)) => *existing += coeff,
+                        None => terms.push((column, rotation, coeff)),
+                    }
+                }
+                Some((a_constant + b_constant, terms))
+            }
+            Expression::Product(a, b) => {
+                let a = a.linear_form()?;
+                let b = b.linear_form()?;
+                if a.1.is_empty() {
+                    Some(scale(b, a.0))
+                } else if b.1.is_empty() {
+                    Some(scale(a, b.0))
+                } else {
+                    None
+                }
+            }
+        }
     }
-}
 
-impl<F: Field> Product<Self> for Expression<F> {
-    fn product<I: Iterator<Item = Self>>(iter: I) -> Self {
-        iter.reduce(|acc, x| acc * x)
-            .unwrap_or(Expression::Constant(F::ONE))
+    /// Lowers this expression into a sequence of arithmetic gates, numbering wires from
+    /// `next_wire` onwards. Leaves become input wires, while negations and scalings are
+    /// lowered into multiplications by a constant input. Returns the output wire together
+    /// with the gates, in evaluation order.
+    pub fn lower_to_gates(&self, next_wire: &mut usize) -> (usize, Vec<ArithGate<F>>) {
+        let mut gates = Vec::new();
+        let out = self.lower_into(next_wire, &mut gates);
+        (out, gates)
+    }
+
+    fn lower_into(&self, next_wire: &mut usize, gates: &mut Vec<ArithGate<F>>) -> usize {
+        fn push<F>(
+            gates: &mut Vec<ArithGate<F>>,
+            next_wire: &mut usize,
+            op: ArithOp<F>,
+            operands: Option<(usize, usize)>,
+        ) -> usize {
+            let out = *next_wire;
+            *next_wire += 1;
+            let (lhs, rhs) = operands.unwrap_or((out, out));
+            gates.push(ArithGate { op, lhs, rhs, out });
+            out
+        }
+
+        match self {
+            Expression::Negated(a) => {
+                let a = a.lower_into(next_wire, gates);
+                let minus_one = Expression::Constant(-F::ONE);
+                let c = push(gates, next_wire, ArithOp::Input(minus_one), None);
+                push(gates, next_wire, ArithOp::Mul, Some((a, c)))
+            }
+            Expression::Sum(a, b) => {
+                let a = a.lower_into(next_wire, gates);
+                let b = b.lower_into(next_wire, gates);
+                push(gates, next_wire, ArithOp::Add, Some((a, b)))
+            }
+            Expression::Product(a, b) => {
+                let a = a.lower_into(next_wire, gates);
+                let b = b.lower_into(next_wire, gates);
+                push(gates, next_wire, ArithOp::Mul, Some((a, b)))
+            }
+            Expression::Scaled(a, f) => {
+                let a = a.lower_into(next_wire, gates);
+                let scalar = Expression::Constant(*f);
+                let c = push(gates, next_wire, ArithOp::Input(scalar), None);
+                push(gates, next_wire, ArithOp::Mul, Some((a, c)))
+            }
+            leaf => push(gates, next_wire, ArithOp::Input(leaf.clone()), None),
+        }
     }
-}
 
-/// Represents an index into a vector where each entry corresponds to a distinct
-/// point that polynomials are queried at.
-#[derive(Copy, Clone, Debug)]
-pub(crate) struct PointIndex(pub usize);
+    /// Rewrites this expression bottom-up with `rules`. At each node, after its operands
+    /// have been rewritten, the first rule whose pattern has the same template identifier
+    /// as the node is applied once. The query indices of the returned expression are
+    /// reset.
+    pub fn apply_rules(&self, rules: &[RewriteRule<F>]) -> Expression<F> {
+        let templates: Vec<String> = rules
+            .iter()
+            .map(|rule| rule.pattern.template_identifier())
+            .collect();
+        let mut expr = self.apply_rules_with(rules, &templates);
+        expr.clear_query_indices();
+        expr
+    }
+
+    fn apply_rules_with(&self, rules: &[RewriteRule<F>], templates: &[String]) -> Expression<F> {
+        fn advice_leaves<F: Field>(expr: &Expression<F>, leaves: &mut Vec<AdviceQuery>) {
+            match expr {
+                Expression::Advice(query) => leaves.push(*query),
+                Expression::Negated(a) | Expression::Scaled(a, _) => advice_leaves(a, leaves),
+                Expression::Sum(a, b) | Expression::Product(a, b) => {
+                    advice_leaves(a, leaves);
+                    advice_leaves(b, leaves);
+                }
+                _ => (),
+            }
+        }
 
-/// A "virtual cell" is a PLONK cell that has been queried at a particular relative offset
-/// within a custom gate.
-#[derive(Clone, Debug)]
-pub struct VirtualCell {
-    pub column: Column<Any>,
-    pub rotation: Rotation,
-}
+        let node = match self {
+            Expression::Negated(a) => {
+                Expression::Negated(Box::new(a.apply_rules_with(rules, templates)))
+            }
+            Expression::Sum(a, b) => Expression::Sum(
+                Box::new(a.apply_rules_with(rules, templates)),
+                Box::new(b.apply_rules_with(rules, templates)),
+            ),
+            Expression::Product(a, b) => Expression::Product(
+                Box::new(a.apply_rules_with(rules, templates)),
+                Box::new(b.apply_rules_with(rules, templates)),
+            ),
+            Expression::Scaled(a, f) => {
+                Expression::Scaled(Box::new(a.apply_rules_with(rules, templates)), *f)
+            }
+            leaf => leaf.clone(),
+        };
 
-impl<Col: Into<Column<Any>>> From<(Col, Rotation)> for VirtualCell {
-    fn from((column, rotation): (Col, Rotation)) -> Self {
-        VirtualCell {
-            column: column.into(),
-            rotation,
-        }
-    }
-}
+        let template = node.template_identifier();
+        let rule = match templates.iter().position(|t| *t == template) {
+            Some(position) => &rules[position],
+            None => return node,
+        };
 
-/// An individual polynomial constraint.
-///
-/// These are returned by the closures passed to `ConstraintSystem::create_gate`.
-#[derive(Debug)]
-pub struct Constraint<F: Field> {
-    name: String,
-    poly: Expression<F>,
-}
+        // Both expressions have the same shape, so their advice leaves correspond.
+        let (mut from, mut to) = (vec![], vec![]);
+        advice_leaves(&rule.pattern, &mut from);
+        advice_leaves(&node, &mut to);
+        let columns: HashMap<usize, AdviceQuery> = from
+            .iter()
+            .map(|query| query.column_index)
+            .zip(to)
+            .collect();
 
-impl<F: Field> From<Expression<F>> for Constraint<F> {
-    fn from(poly: Expression<F>) -> Self {
-        Constraint {
-            name: "".to_string(),
-            poly,
-        }
+        rule.replacement.evaluate(
+            &|constant| Expression::Constant(constant),
+            &|selector| Expression::Selector(selector),
+            &|query| Expression::Fixed(query),
+            &|query| match columns.get(&query.column_index) {
+                Some(matched) => Expression::Advice(AdviceQuery {
+                    column_index: matched.column_index,
+                    phase: matched.phase,
+                    ..query
+                }),
+                None => Expression::Advice(query),
+            },
+            &|query| Expression::Instance(query),
+            &|challenge| Expression::Challenge(challenge),
+            &|a| Expression::Negated(Box::new(a)),
+            &|a, b| Expression::Sum(Box::new(a), Box::new(b)),
+            &|a, b| Expression::Product(Box::new(a), Box::new(b)),
+            &|a, f| Expression::Scaled(Box::new(a), f),
+        )
     }
-}
 
-impl<F: Field, S: AsRef<str>> From<(S, Expression<F>)> for Constraint<F> {
-    fn from((name, poly): (S, Expression<F>)) -> Self {
-        Constraint {
-            name: name.as_ref().to_string(),
-            poly,
+    /// Returns an equivalent expression obtained by repeatedly simplifying it and
+    /// factoring common operands out of sums of products, until the number of nodes
+    /// stops shrinking or a small iteration cap is reached. The result evaluates
+    /// identically to `self`; its query indices are reset.
+    pub fn optimize(&self) -> Expression<F> {
+        const MAX_ITERATIONS: usize = 4;
+
+        let mut expr = self.clone();
+        expr.clear_query_indices();
+        for _ in 0..MAX_ITERATIONS {
+            let next = expr.simplify().factor_common();
+            if next.num_nodes() >= expr.num_nodes() {
+                break;
+            }
+            expr = next;
         }
+        expr
     }
-}
 
-impl<F: Field> From<Expression<F>> for Vec<Constraint<F>> {
-    fn from(poly: Expression<F>) -> Self {
-        vec![Constraint {
-            name: "".to_string(),
-            poly,
-        }]
-    }
-}
+    /// Returns an equivalent expression where constant subexpressions are evaluated,
+    /// additive zeros and multiplicative ones are dropped, products and scalings by zero
+    /// collapse to `Constant(0)`, products by a constant become scalings and double
+    /// negations are removed. The rewrite is applied bottom-up and is idempotent.
+    pub fn simplify(&self) -> Expression<F> {
+        use Expression::*;
 
-/// A set of polynomial constraints with a common selector.
-///
-/// ```
-/// use halo2_common::{plonk::{Constraints, Expression}};
-/// use halo2_middleware::poly::Rotation;
-/// use halo2curves::pasta::Fp;
-/// # use halo2_common::plonk::ConstraintSystem;
-///
-/// # let mut meta = ConstraintSystem::<Fp>::default();
-/// let a = meta.advice_column();
-/// let b = meta.advice_column();
-/// let c = meta.advice_column();
-/// let s = meta.selector();
-///
-/// meta.create_gate("foo", |meta| {
-///     let next = meta.query_advice(a, Rotation::next());
-///     let a = meta.query_advice(a, Rotation::cur());
-///     let b = meta.query_advice(b, Rotation::cur());
-///     let c = meta.query_advice(c, Rotation::cur());
-///     let s_ternary = meta.query_selector(s);
-///
-///     let one_minus_a = Expression::Constant(Fp::one()) - a.clone();
-///
-///     Constraints::with_selector(
-///         s_ternary,
-///         std::array::IntoIter::new([
-///             ("a is boolean", a.clone() * one_minus_a.clone()),
-///             ("next == a ? b : c", next - (a * b + one_minus_a * c)),
-///         ]),
-///     )
-/// });
-/// ```
-///
-/// Note that the use of `std::array::IntoIter::new` is only necessary if you need to
-/// support Rust 1.51 or 1.52. If your minimum supported Rust version is 1.53 or greater,
-/// you can pass an array directly.
-#[derive(Debug)]
-pub struct Constraints<F: Field, C: Into<Constraint<F>>, Iter: IntoIterator<Item = C>> {
-    selector: Expression<F>,
-    constraints: Iter,
-}
+        let scale = |expr: Expression<F>, f: F| expr.scale_folded(f, true);
 
-impl<F: Field, C: Into<Constraint<F>>, Iter: IntoIterator<Item = C>> Constraints<F, C, Iter> {
-    /// Constructs a set of constraints that are controlled by the given selector.
-    ///
-    /// Each constraint `c` in `iterator` will be converted into the constraint
-    /// `selector * c`.
-    pub fn with_selector(selector: Expression<F>, constraints: Iter) -> Self {
-        Constraints {
-            selector,
-            constraints,
+        match self {
+            Negated(a) => match a.simplify() {
+                Constant(c) => Constant(-c),
+                Negated(a) => *a,
+                a => Negated(Box::new(a)),
+            },
+            Sum(a, b) => match (a.simplify(), b.simplify()) {
+                (Constant(a), Constant(b)) => Constant(a + b),
+                (Constant(c), e) | (e, Constant(c)) if c == F::ZERO => e,
+                (a, b) => Sum(Box::new(a), Box::new(b)),
+            },
+            Product(a, b) => match (a.simplify(), b.simplify()) {
+                (Constant(c), e) | (e, Constant(c)) => scale(e, c),
+                (a, b) => Product(Box::new(a), Box::new(b)),
+            },
+            Scaled(a, f) => scale(a.simplify(), *f),
+            leaf => leaf.clone(),
         }
     }
-}
 
-fn apply_selector_to_constraint<F: Field, C: Into<Constraint<F>>>(
-    (selector, c): (Expression<F>, C),
-) -> Constraint<F> {
-    let constraint: Constraint<F> = c.into();
-    Constraint {
-        name: constraint.name,
-        poly: selector * constraint.poly,
+    /// Scales this expression by `f`, multiplying a constant directly and merging `f`
+    /// into an outer scaling rather than nesting a new one. If `drop_trivial` is set, a
+    /// zero scalar gives the constant zero and a unit scalar leaves the expression as is.
+    /// Shared by [`Expression::simplify`] and [`Expression::fold_scalars`], which call it
+    /// on expressions they have already processed.
+    fn scale_folded(self, f: F, drop_trivial: bool) -> Expression<F> {
+        match self {
+            Expression::Constant(c) => Expression::Constant(c * f),
+            _ if drop_trivial && f == F::ZERO => Expression::Constant(F::ZERO),
+            expr if drop_trivial && f == F::ONE => expr,
+            Expression::Scaled(a, g) => a.scale_folded(g * f, drop_trivial),
+            expr => Expression::Scaled(Box::new(expr), f),
+        }
     }
-}
 
-type ApplySelectorToConstraint<F, C> = fn((Expression<F>, C)) -> Constraint<F>;
-type ConstraintsIterator<F, C, I> = std::iter::Map<
-    std::iter::Zip<std::iter::Repeat<Expression<F>>, I>,
-    ApplySelectorToConstraint<F, C>,
->;
+    /// Returns an equivalent expression where scalar factors are merged: nested scalings
+    /// are combined into one, products with a constant operand become scalings, and the
+    /// scalings of both operands of a product are hoisted into a single scaling of the
+    /// product. Each merge replaces several multiplications by a single field
+    /// multiplication, which lowers [`Expression::complexity`].
+    pub fn fold_scalars(&self) -> Expression<F> {
+        use Expression::*;
 
-impl<F: Field, C: Into<Constraint<F>>, Iter: IntoIterator<Item = C>> IntoIterator
-    for Constraints<F, C, Iter>
-{
-    type Item = Constraint<F>;
-    type IntoIter = ConstraintsIterator<F, C, Iter::IntoIter>;
+        let scale = |expr: Expression<F>, f: F| expr.scale_folded(f, false);
 
-    fn into_iter(self) -> Self::IntoIter {
-        std::iter::repeat(self.selector)
-            .zip(self.constraints)
-            .map(apply_selector_to_constraint)
+        match self {
+            Negated(a) => Negated(Box::new(a.fold_scalars())),
+            Sum(a, b) => Sum(Box::new(a.fold_scalars()), Box::new(b.fold_scalars())),
+            Product(a, b) => match (a.fold_scalars(), b.fold_scalars()) {
+                (Constant(c), e) | (e, Constant(c)) => scale(e, c),
+                (Scaled(a, f), Scaled(b, g)) => scale(Product(a, b), f * g),
+                (Scaled(a, f), b) => scale(Product(a, Box::new(b)), f),
+                (a, Scaled(b, g)) => scale(Product(Box::new(a), b), g),
+                (a, b) => Product(Box::new(a), Box::new(b)),
+            },
+            Scaled(a, f) => scale(a.fold_scalars(), *f),
+            leaf => leaf.clone(),
+        }
     }
-}
-
-/// Gate
-#[derive(Clone, Debug)]
-pub struct Gate<F: Field> {
-    name: String,
-    constraint_names: Vec<String>,
-    polys: Vec<Expression<F>>,
-    /// We track queried selectors separately from other cells, so that we can use them to
-    /// trigger debug checks on gates.
-    queried_selectors: Vec<Selector>,
-    queried_cells: Vec<VirtualCell>,
-}
 
-impl<F: Field> Gate<F> {
-    /// Returns the gate name.
-    pub fn name(&self) -> &str {
-        self.name.as_str()
-    }
+    /// Returns an equivalent expression where every negation is pushed down to the
+    /// leaves: the negation of a sum becomes the sum of the negations, the negation of
+    /// a product negates its left factor, the negation of a scaling negates the scalar
+    /// and double negations cancel. Negated constants are folded, so only negations of
+    /// queries and challenges remain. In particular `-(a + b)` and `-a + -b` normalize
+    /// to the same tree.
+    pub fn push_negations(&self) -> Expression<F> {
+        use Expression::*;
+
+        // Returns the negation of `expr` with negations pushed to the leaves.
+        fn negate<F: Field>(expr: &Expression<F>) -> Expression<F> {
+            match expr {
+                Constant(c) => Constant(-*c),
+                Negated(a) => a.push_negations(),
+                Sum(a, b) => Sum(Box::new(negate(a)), Box::new(negate(b))),
+                Product(a, b) => Product(Box::new(negate(a)), Box::new(b.push_negations())),
+                Scaled(a, f) => Scaled(Box::new(a.push_negations()), -*f),
+                leaf => Negated(Box::new(leaf.clone())),
+            }
+        }
 
-    /// Returns the name of the constraint at index `constraint_index`.
-    pub fn constraint_name(&self, constraint_index: usize) -> &str {
-        self.constraint_names[constraint_index].as_str()
+        match self {
+            Negated(a) => negate(a),
+            Sum(a, b) => Sum(Box::new(a.push_negations()), Box::new(b.push_negations())),
+            Product(a, b) => Product(Box::new(a.push_negations()), Box::new(b.push_negations())),
+            Scaled(a, f) => Scaled(Box::new(a.push_negations()), *f),
+            leaf => leaf.clone(),
+        }
     }
 
-    /// Returns constraints of this gate
-    pub fn polynomials(&self) -> &[Expression<F>] {
-        &self.polys
+    /// Rewrites `a * b + a * c` into `a * (b + c)` (and likewise for a shared right
+    /// operand), bottom-up. Operands are compared by identifier.
+    fn factor_common(&self) -> Expression<F> {
+        use Expression::*;
+        match self {
+            Negated(a) => Negated(Box::new(a.factor_common())),
+            Scaled(a, f) => Scaled(Box::new(a.factor_common()), *f),
+            Product(a, b) => Product(Box::new(a.factor_common()), Box::new(b.factor_common())),
+            Sum(a, b) => match (a.factor_common(), b.factor_common()) {
+                (Product(a, b), Product(c, d)) if a.identifier() == c.identifier() => {
+                    Product(a, Box::new(Sum(b, d)))
+                }
+                (Product(a, b), Product(c, d)) if b.identifier() == d.identifier() => {
+                    Product(Box::new(Sum(a, c)), b)
+                }
+                (a, b) => Sum(Box::new(a), Box::new(b)),
+            },
+            leaf => leaf.clone(),
+        }
     }
 
-    pub fn queried_selectors(&self) -> &[Selector] {
-        &self.queried_selectors
+    /// Counts the field operations performed when evaluating this expression once. The
+    /// tree is walked with an explicit stack, so this does not overflow the call stack on
+    /// deep expressions.
+    pub fn op_counts(&self) -> OpCounts {
+        let mut counts = OpCounts::default();
+        let mut stack = vec![self];
+        while let Some(expr) = stack.pop() {
+            match expr {
+                Expression::Negated(a) => {
+                    counts.negations += 1;
+                    stack.push(a);
+                }
+                Expression::Sum(a, b) => {
+                    counts.additions += 1;
+                    stack.push(a);
+                    stack.push(b);
+                }
+                Expression::Product(a, b) => {
+                    counts.multiplications += 1;
+                    stack.push(a);
+                    stack.push(b);
+                }
+                Expression::Scaled(a, _) => {
+                    counts.multiplications += 1;
+                    stack.push(a);
+                }
+                _ => (),
+            }
+        }
+        counts
     }
 
-    pub fn queried_cells(&self) -> &[VirtualCell] {
-        &self.queried_cells
-    }
-}
+    /// Returns the identifiers of the subexpressions (including query leaves) that occur
+    /// more than once in this expression, with their number of occurrences. The result is
+    /// sorted by count in descending order, ties broken by first occurrence.
+    pub fn common_subexpressions(&self) -> Vec<(String, usize)> {
+        fn visit<F: Field>(
+            expr: &Expression<F>,
+            counts: &mut HashMap<String, usize>,
+            order: &mut Vec<String>,
+        ) {
+            match expr {
+                Expression::Negated(a) | Expression::Scaled(a, _) => visit(a, counts, order),
+                Expression::Sum(a, b) | Expression::Product(a, b) => {
+                    visit(a, counts, order);
+                    visit(b, counts, order);
+                }
+                _ => (),
+            }
+            let identifier = expr.identifier();
+            let count = counts.entry(identifier.clone()).or_insert(0);
+            if *count == 0 {
+                order.push(identifier);
+            }
+            *count += 1;
+        }
 
-struct QueriesMap {
-    advice_map: HashMap<(Column<Advice>, Rotation), usize>,
-    instance_map: HashMap<(Column<Instance>, Rotation), usize>,
-    fixed_map: HashMap<(Column<Fixed>, Rotation), usize>,
-    advice: Vec<(Column<Advice>, Rotation)>,
-    instance: Vec<(Column<Instance>, Rotation)>,
-    fixed: Vec<(Column<Fixed>, Rotation)>,
-}
+        let mut counts = HashMap::new();
+        let mut order = Vec::new();
+        visit(self, &mut counts, &mut order);
 
-impl QueriesMap {
-    fn add_advice(&mut self, col: Column<Advice>, rot: Rotation) -> usize {
-        *self.advice_map.entry((col, rot)).or_insert_with(|| {
-            self.advice.push((col, rot));
-            self.advice.len() - 1
-        })
+        let mut common: Vec<_> = order
+            .into_iter()
+            .map(|identifier| {
+                let count = counts[&identifier];
+                (identifier, count)
+            })
+            .filter(|(_, count)| *count > 1)
+            .collect();
+        common.sort_by(|(_, a), (_, b)| b.cmp(a));
+        common
     }
-    fn add_instance(&mut self, col: Column<Instance>, rot: Rotation) -> usize {
-        *self.instance_map.entry((col, rot)).or_insert_with(|| {
-            self.instance.push((col, rot));
-            self.instance.len() - 1
-        })
+
+    /// Returns the number of distinct `(column, rotation)` pairs queried by this
+    /// expression, each repeated query counting once.
+    pub fn distinct_columns(&self) -> usize {
+        self.queried_cells().len()
     }
-    fn add_fixed(&mut self, col: Column<Fixed>, rot: Rotation) -> usize {
-        *self.fixed_map.entry((col, rot)).or_insert_with(|| {
-            self.fixed.push((col, rot));
-            self.fixed.len() - 1
-        })
+
+    /// Returns the set of columns queried by this expression, at any rotation.
+    /// Selectors, challenges and constants do not contribute any column.
+    pub fn queried_columns(&self) -> BTreeSet<Column<Any>> {
+        self.queried_cells()
+            .into_iter()
+            .map(|(column, _)| column)
+            .collect()
     }
-}
 
-impl QueriesMap {
-    fn as_expression<F: Field>(&mut self, expr: &ExpressionMid<F>) -> Expression<F> {
-        match expr {
-            ExpressionMid::Constant(c) => Expression::Constant(*c),
-            ExpressionMid::Fixed(query) => {
-                let (col, rot) = (Column::new(query.column_index, Fixed), query.rotation);
-                let index = self.add_fixed(col, rot);
-                Expression::Fixed(FixedQuery {
-                    index: Some(index),
-                    column_index: query.column_index,
-                    rotation: query.rotation,
-                })
-            }
-            ExpressionMid::Advice(query) => {
-                let (col, rot) = (
-                    Column::new(query.column_index, Advice { phase: query.phase }),
+    /// Returns the set of `(column, rotation)` pairs queried by this expression.
+    /// Selectors, challenges and constants do not contribute any cell.
+    pub fn queried_cells(&self) -> BTreeSet<(Column<Any>, Rotation)> {
+        let union = |mut a: BTreeSet<(Column<Any>, Rotation)>, b| {
+            a.extend(b);
+            a
+        };
+        self.evaluate(
+            &|_| BTreeSet::new(),
+            &|_| BTreeSet::new(),
+            &|query| {
+                BTreeSet::from([(Column::new(query.column_index, Any::Fixed), query.rotation)])
+            },
+            &|query| {
+                let column_type = Any::Advice(Advice::new(query.phase.0));
+                BTreeSet::from([(Column::new(query.column_index, column_type), query.rotation)])
+            },
+            &|query| {
+                BTreeSet::from([(
+                    Column::new(query.column_index, Any::Instance),
                     query.rotation,
-                );
-                let index = self.add_advice(col, rot);
-                Expression::Advice(AdviceQuery {
-                    index: Some(index),
-                    column_index: query.column_index,
-                    rotation: query.rotation,
-                    phase: sealed::Phase(query.phase),
-                })
-            }
-            ExpressionMid::Instance(query) => {
-                let (col, rot) = (Column::new(query.column_index, Instance), query.rotation);
-                let index = self.add_instance(col, rot);
-                Expression::Instance(InstanceQuery {
-                    index: Some(index),
-                    column_index: query.column_index,
-                    rotation: query.rotation,
-                })
+                )])
+            },
+            &|_| BTreeSet::new(),
+            &|a| a,
+            &union,
+            &union,
+            &|a, _| a,
+        )
+    }
+
+    /// Returns the largest rotation at which this expression queries a fixed, advice
+    /// or instance column, or `Rotation(0)` if it queries none.
+    pub fn max_rotation(&self) -> Rotation {
+        Rotation(self.rotation_extent().map_or(0, |(_, max)| max))
+    }
+
+    /// Returns the smallest rotation at which this expression queries a fixed, advice
+    /// or instance column, or `Rotation(0)` if it queries none.
+    pub fn min_rotation(&self) -> Rotation {
+        Rotation(self.rotation_extent().map_or(0, |(min, _)| min))
+    }
+
+    /// Returns how many rows ahead of the current row this expression reads, i.e. the
+    /// largest positive rotation at which it queries a column, or 0 if it queries none.
+    pub fn max_positive_rotation(&self) -> i32 {
+        max(self.max_rotation().0, 0)
+    }
+
+    /// Returns how many rows behind the current row this expression reads, as the
+    /// smallest negative rotation at which it queries a column, or 0 if it queries none.
+    pub fn min_negative_rotation(&self) -> i32 {
+        min(self.min_rotation().0, 0)
+    }
+
+    /// Returns the smallest and largest rotations queried by this expression.
+    fn rotation_extent(&self) -> Option<(i32, i32)> {
+        let leaf = |rotation: Rotation| Some((rotation.0, rotation.0));
+        let union = |a: Option<(i32, i32)>, b: Option<(i32, i32)>| match (a, b) {
+            (Some((min_a, max_a)), Some((min_b, max_b))) => {
+                Some((min(min_a, min_b), max(max_a, max_b)))
             }
-            ExpressionMid::Challenge(c) => Expression::Challenge((*c).into()),
-            ExpressionMid::Negated(e) => Expression::Negated(Box::new(self.as_expression(e))),
-            ExpressionMid::Sum(lhs, rhs) => Expression::Sum(
-                Box::new(self.as_expression(lhs)),
-                Box::new(self.as_expression(rhs)),
-            ),
-            ExpressionMid::Product(lhs, rhs) => Expression::Product(
-                Box::new(self.as_expression(lhs)),
-                Box::new(self.as_expression(rhs)),
-            ),
-            ExpressionMid::Scaled(e, c) => Expression::Scaled(Box::new(self.as_expression(e)), *c),
+            (a, None) => a,
+            (None, b) => b,
+        };
+        self.evaluate(
+            &|_| None,
+            &|_| None,
+            &|query| leaf(query.rotation),
+            &|query| leaf(query.rotation),
+            &|query| leaf(query.rotation),
+            &|_| None,
+            &|a| a,
+            &union,
+            &union,
+            &|a, _| a,
+        )
+    }
+
+    /// Returns whether this expression queries `column` at any rotation.
+    fn queries_column(&self, column: Column<Any>) -> bool {
+        self.evaluate(
+            &|_| false,
+            &|_| false,
+            &|query| column == Column::new(query.column_index, Any::Fixed),
+            &|query| {
+                column == Column::new(query.column_index, Any::Advice(Advice::new(query.phase.0)))
+            },
+            &|query| column == Column::new(query.column_index, Any::Instance),
+            &|_| false,
+            &|a| a,
+            &|a, b| a || b,
+            &|a, b| a || b,
+            &|a, _| a,
+        )
+    }
+
+    /// Evaluates this expression at each of the `num_rows` rows. The column closures
+    /// receive the row at which the query lands, with rotations wrapping around modulo
+    /// `num_rows`. Panics if the expression contains a selector.
+    pub fn evaluate_rows(
+        &self,
+        num_rows: usize,
+        advice: &impl Fn(usize, AdviceQuery) -> F,
+        fixed: &impl Fn(usize, FixedQuery) -> F,
+        instance: &impl Fn(usize, InstanceQuery) -> F,
+        challenge: &impl Fn(Challenge) -> F,
+    ) -> Vec<F> {
+        (0..num_rows)
+            .map(|row| self.evaluate_row(row, num_rows, advice, fixed, instance, challenge))
+            .collect()
+    }
+
+    /// Evaluates this expression at `row` only, as in [`Expression::evaluate_rows`].
+    fn evaluate_row(
+        &self,
+        row: usize,
+        num_rows: usize,
+        advice: &impl Fn(usize, AdviceQuery) -> F,
+        fixed: &impl Fn(usize, FixedQuery) -> F,
+        instance: &impl Fn(usize, InstanceQuery) -> F,
+        challenge: &impl Fn(Challenge) -> F,
+    ) -> F {
+        let resolve = |rotation: Rotation| {
+            (row as i64 + rotation.0 as i64).rem_euclid(num_rows as i64) as usize
+        };
+        self.evaluate(
+            &|constant| constant,
+            &|_| panic!("selectors must be compressed into fixed columns to evaluate rows"),
+            &|query| fixed(resolve(query.rotation), query),
+            &|query| advice(resolve(query.rotation), query),
+            &|query| instance(resolve(query.rotation), query),
+            challenge,
+            &|a| -a,
+            &|a, b| a + b,
+            &|a, b| a * b,
+            &|a, f| a * f,
+        )
+    }
+
+    /// Returns a copy of this expression in which every occurrence of `challenge` is
+    /// replaced by the constant `value`. Other challenges are left unchanged.
+    pub fn substitute_challenge(&self, challenge: Challenge, value: F) -> Expression<F> {
+        self.substitute_challenges(&HashMap::from([(challenge, value)]))
+    }
+
+    /// Returns a copy of this expression in which every challenge that is a key of
+    /// `values` is replaced by the corresponding constant. Other challenges are left
+    /// unchanged.
+    pub fn substitute_challenges(&self, values: &HashMap<Challenge, F>) -> Expression<F> {
+        let substitute = |expr: &Expression<F>| Box::new(expr.substitute_challenges(values));
+        match self {
+            Expression::Challenge(challenge) => match values.get(challenge) {
+                Some(value) => Expression::Constant(*value),
+                None => self.clone(),
+            },
+            Expression::Negated(a) => Expression::Negated(substitute(a)),
+            Expression::Sum(a, b) => Expression::Sum(substitute(a), substitute(b)),
+            Expression::Product(a, b) => Expression::Product(substitute(a), substitute(b)),
+            Expression::Scaled(a, f) => Expression::Scaled(substitute(a), *f),
+            _ => self.clone(),
         }
     }
-}
 
-impl<F: Field> From<ConstraintSystem<F>> for ConstraintSystemV2Backend<F> {
-    fn from(cs: ConstraintSystem<F>) -> Self {
-        ConstraintSystemV2Backend {
-            num_fixed_columns: cs.num_fixed_columns,
-            num_advice_columns: cs.num_advice_columns,
-            num_instance_columns: cs.num_instance_columns,
-            num_challenges: cs.num_challenges,
-            unblinded_advice_columns: cs.unblinded_advice_columns,
-            advice_column_phase: cs.advice_column_phase.iter().map(|p| p.0).collect(),
-            challenge_phase: cs.challenge_phase.iter().map(|p| p.0).collect(),
-            gates: cs
-                .gates
+    /// Expands this expression into a canonical sum of monomials, by distributing
+    /// products over sums and pushing negations and scalings down into the coefficients
+    /// of the monomials. Each monomial is a product of leaves, sorted by identifier and
+    /// scaled by its coefficient unless it is one. Like monomials are combined, those
+    /// with a zero coefficient are dropped, and the monomials are sorted by degree and
+    /// then by the identifiers of their leaves, so two expressions that are equal as
+    /// polynomials expand to the same expression. Constants and challenges are treated
+    /// as leaves of degree zero, challenges included in the monomials.
+    ///
+    /// The result evaluates identically to this expression, and its degree is the same
+    /// unless some terms cancel out. In the worst case the number of monomials is
+    /// exponential in the size of the expression: a product of `n` sums of two terms
+    /// expands to `2^n` monomials. See [`Expression::try_expand`] for a bounded version.
+    pub fn expand(&self) -> Expression<F> {
+        self.try_expand(usize::MAX)
+            .expect("the number of monomials is unbounded")
+    }
+
+    /// Expands this expression like [`Expression::expand`], giving up and returning
+    /// `None` as soon as an intermediate result has more than `max_monomials`
+    /// monomials. The tree is walked with an explicit stack, so deep expressions do not
+    /// overflow the call stack.
+    pub fn try_expand(&self, max_monomials: usize) -> Option<Expression<F>> {
+        let monomials = self.monomials(max_monomials, &|challenge| {
+            Some(vec![(F::ONE, vec![Expression::Challenge(challenge)])])
+        })?;
+
+        let monomials = monomials.into_iter().map(|(coeff, leaves)| {
+            let product = leaves
                 .into_iter()
-                .flat_map(|mut g| {
-                    let constraint_names = std::mem::take(&mut g.constraint_names);
-                    let gate_name = g.name.clone();
-                    g.polys.into_iter().enumerate().map(move |(i, e)| {
-                        let name = match constraint_names[i].as_str() {
-                            "" => gate_name.clone(),
-                            constraint_name => format!("{gate_name}:{constraint_name}"),
-                        };
-                        GateV2Backend {
-                            name,
-                            poly: e.into(),
-                        }
-                    })
-                })
-                .collect(),
-            permutation: halo2_middleware::permutation::ArgumentV2 {
-                columns: cs
-                    .permutation
-                    .columns
-                    .into_iter()
-                    .map(|c| c.into())
-                    .collect(),
+                .reduce(|a, b| Expression::Product(Box::new(a), Box::new(b)));
+            match product {
+                None => Expression::Constant(coeff),
+                Some(product) if coeff == F::ONE => product,
+                Some(product) => Expression::Scaled(Box::new(product), coeff),
+            }
+        });
+        Some(
+            monomials
+                .reduce(|a, b| Expression::Sum(Box::new(a), Box::new(b)))
+                .unwrap_or(Expression::Constant(F::ZERO)),
+        )
+    }
+
+    /// Returns the monomials of the expansion of this expression, as in
+    /// [`Expression::try_expand`], mapping each challenge to monomials with `challenge`.
+    fn monomials(
+        &self,
+        max_monomials: usize,
+        challenge: &impl Fn(Challenge) -> Option<Monomials<F>>,
+    ) -> Option<Monomials<F>> {
+        let leaf = |expr: Expression<F>| Some(vec![(F::ONE, vec![expr])]);
+        let combine = |monomials| Self::combine_monomials(monomials, max_monomials);
+        self.evaluate_iter(
+            &|scalar| combine(vec![(scalar, vec![])]),
+            &|selector| leaf(Expression::Selector(selector)),
+            &|query| leaf(Expression::Fixed(query)),
+            &|query| leaf(Expression::Advice(query)),
+            &|query| leaf(Expression::Instance(query)),
+            challenge,
+            &|a| {
+                Some(
+                    a?.into_iter()
+                        .map(|(coeff, leaves)| (-coeff, leaves))
+                        .collect(),
+                )
             },
-            lookups: cs
-                .lookups
-                .into_iter()
-                .map(|l| halo2_middleware::lookup::ArgumentV2 {
-                    name: l.name,
-                    input_expressions: l.input_expressions.into_iter().map(|e| e.into()).collect(),
-                    table_expressions: l.table_expressions.into_iter().map(|e| e.into()).collect(),
-                })
-                .collect(),
-            shuffles: cs
-                .shuffles
-                .into_iter()
-                .map(|s| halo2_middleware::shuffle::ArgumentV2 {
-                    name: s.name,
-                    input_expressions: s.input_expressions.into_iter().map(|e| e.into()).collect(),
-                    shuffle_expressions: s
-                        .shuffle_expressions
-                        .into_iter()
-                        .map(|e| e.into())
+            &|a, b| {
+                let mut a = a?;
+                a.extend(b?);
+                combine(a)
+            },
+            &|a, b| {
+                let (a, b) = (a?, b?);
+                if a.len().saturating_mul(b.len()) > max_monomials {
+                    return None;
+                }
+                combine(
+                    a.iter()
+                        .flat_map(|(coeff_a, leaves_a)| {
+                            b.iter().map(move |(coeff_b, leaves_b)| {
+                                let leaves = leaves_a.iter().chain(leaves_b).cloned().collect();
+                                (*coeff_a * coeff_b, leaves)
+                            })
+                        })
                         .collect(),
-                })
-                .collect(),
-            general_column_annotations: cs.general_column_annotations,
-        }
+                )
+            },
+            &|a, f| {
+                combine(
+                    a?.into_iter()
+                        .map(|(coeff, leaves)| (coeff * f, leaves))
+                        .collect(),
+                )
+            },
+        )
     }
-}
-
-/// Collect queries used in gates while mapping those gates to equivalent ones with indexed
-/// query references in the expressions.
-fn cs2_collect_queries_gates<F: Field>(
-    cs2: &ConstraintSystemV2Backend<F>,
-    queries: &mut QueriesMap,
-) -> Vec<Gate<F>> {
-    cs2.gates
-        .iter()
-        .map(|gate| Gate {
-            name: gate.name.clone(),
-            constraint_names: Vec::new(),
-            polys: vec![queries.as_expression(gate.polynomial())],
-            queried_selectors: Vec::new(), // Unused?
-            queried_cells: Vec::new(),     // Unused?
-        })
-        .collect()
-}
 
-/// Collect queries used in lookups while mapping those lookups to equivalent ones with indexed
-/// query references in the expressions.
-fn cs2_collect_queries_lookups<F: Field>(
-    cs2: &ConstraintSystemV2Backend<F>,
-    queries: &mut QueriesMap,
-) -> Vec<lookup::Argument<F>> {
-    cs2.lookups
-        .iter()
-        .map(|lookup| lookup::Argument {
-            name: lookup.name.clone(),
-            input_expressions: lookup
-                .input_expressions
-                .iter()
-                .map(|e| queries.as_expression(e))
-                .collect(),
-            table_expressions: lookup
-                .table_expressions
-                .iter()
-                .map(|e| queries.as_expression(e))
-                .collect(),
+    /// Returns the coefficients of the expansion of this expression, as pairs of a
+    /// monomial and its nonzero coefficient. A monomial is the sorted multiset of the
+    /// cells multiplied, constant terms having an empty monomial, and the pairs are
+    /// sorted by degree and then by monomial. Panics if the expression contains a
+    /// selector or a challenge; see [`Expression::coefficients_with_challenges`].
+    #[allow(clippy::type_complexity)]
+    pub fn coefficients(&self) -> Vec<(Vec<(Column<Any>, Rotation)>, F)> {
+        self.coefficients_with_challenges(&|_| {
+            panic!("challenges must be given a value to compute coefficients")
         })
-        .collect()
-}
+    }
 
-/// Collect queries used in shuffles while mapping those lookups to equivalent ones with indexed
-/// query references in the expressions.
+    /// Returns the coefficients of the expansion of this expression like
+    /// [`Expression::coefficients`], folding each challenge into the coefficients as
+    /// the value given by `challenge`. Panics if the expression contains a selector.
+    #[allow(clippy::type_complexity)]
+    pub fn coefficients_with_challenges(
+        &self,
+        challenge: &impl Fn(Challenge) -> F,
+    ) -> Vec<(Vec<(Column<Any>, Rotation)>, F)> {
+        let monomials = self
+            .monomials(usize::MAX, &|c| Some(vec![(challenge(c), vec![])]))
+            .expect("the number of monomials is unbounded");
+        let mut coefficients: Vec<_> = monomials
+            .into_iter()
+            .filter(|(coeff, _)| *coeff != F::ZERO)
+            .map(|(coeff, leaves)| {
+                let mut cells: Vec<(Column<Any>, Rotation)> = leaves
+                    .iter()
+                    .map(|leaf| match leaf {
+                        Expression::Fixed(query) => {
+                            (Column::new(query.column_index, Any::Fixed), query.rotation)
+                        }
+                        Expression::Advice(query) => (
+                            Column::new(
+                                query.column_index,
+                                Any::Advice(Advice::new(query.phase.0)),
+                            ),
+                            query.rotation,
+                        ),
+                        Expression::Instance(query) => (
+                            Column::new(query.column_index, Any::Instance),
+                            query.rotation,
+                        ),
+                        _ => panic!("selectors have no coefficients; compress them first"),
+                    })
+                    .collect();
+                cells.sort();
+                (cells, coeff)
+            })
+            .collect();
+        coefficients.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+        coefficients
+    }
+
+    /// Returns `Some(c)` if this expression equals `c` times `other` once both are
+    /// expanded as in [`Expression::expand`], so that for example `2*(a-b)` is found
+    /// to be twice `a-b`. Returns `None` if `other` expands to zero or the expressions
+    /// are not scalar multiples of each other. Challenges are treated as opaque leaves.
+    pub fn scalar_multiple_of(&self, other: &Expression<F>) -> Option<F> {
+        let expand = |expr: &Expression<F>| {
+            expr.monomials(usize::MAX, &|challenge| {
+                Some(vec![(F::ONE, vec![Expression::Challenge(challenge)])])
+            })
+            .expect("the number of monomials is unbounded")
+        };
+        let (lhs, rhs) = (expand(self), expand(other));
+        if rhs.is_empty() {
+            return None;
+        }
+        if lhs.is_empty() {
+            return Some(F::ZERO);
+        }
+        if lhs.len() != rhs.len() {
+            return None;
+        }
+
+        let same_leaves = |a: &[Expression<F>], b: &[Expression<F>]| {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|(a, b)| a.identifier() == b.identifier())
+        };
+        let factor = lhs[0].0 * rhs[0].0.invert().unwrap();
+        lhs.iter()
+            .zip(rhs.iter())
+            .all(|((coeff_lhs, leaves_lhs), (coeff_rhs, leaves_rhs))| {
+                same_leaves(leaves_lhs, leaves_rhs) && *coeff_lhs == factor * coeff_rhs
+            })
+            .then_some(factor)
+    }
+
+    /// Sorts the leaves of every monomial, combines like monomials and sorts them in
+    /// the canonical order of [`Expression::expand`]. Returns `None` if more than
+    /// `max_monomials` monomials are left.
+    fn combine_monomials(monomials: Monomials<F>, max_monomials: usize) -> Option<Monomials<F>> {
+        let mut combined: BTreeMap<MonomialKey, (F, Vec<Expression<F>>)> = BTreeMap::new();
+        for (coeff, mut leaves) in monomials {
+            leaves.sort_by_cached_key(|leaf| leaf.identifier());
+            let key = (
+                leaves.len(),
+                leaves.iter().map(|leaf| leaf.identifier()).collect(),
+            );
+            combined
+                .entry(key)
+                .and_modify(|(sum, _)| *sum += coeff)
+                .or_insert((coeff, leaves));
+        }
+        let monomials: Vec<_> = combined
+            .into_values()
+            .filter(|(coeff, _)| *coeff != F::ZERO)
+            .collect();
+        (monomials.len() <= max_monomials).then_some(monomials)
+    }
+
+    /// Returns the depth of this expression tree, a leaf having depth one.
+    pub fn depth(&self) -> usize {
+        match self {
+            Expression::Negated(a) | Expression::Scaled(a, _) => 1 + a.depth(),
+            Expression::Sum(a, b) | Expression::Product(a, b) => 1 + max(a.depth(), b.depth()),
+            _ => 1,
+        }
+    }
+
+    /// Sums `terms` as a balanced tree of `Sum` nodes, by adding them pairwise until a
+    /// single expression is left, so that the result has logarithmic depth in the number
+    /// of terms. Returns `Constant(0)` if `terms` is empty.
+    pub fn balanced_sum(terms: impl IntoIterator<Item = Expression<F>>) -> Expression<F> {
+        Self::reduce_pairwise(terms.into_iter().collect(), &|a, b| a + b)
+            .unwrap_or(Expression::Constant(F::ZERO))
+    }
+
+    /// Multiplies `factors` as a balanced tree of `Product` nodes, by multiplying them
+    /// pairwise until a single expression is left. The degree of the result is the sum
+    /// of the degrees of the factors, while its depth is logarithmic in their number.
+    /// Returns `Constant(1)` if `factors` is empty.
+    pub fn balanced_product(factors: impl IntoIterator<Item = Expression<F>>) -> Expression<F> {
+        Self::reduce_pairwise(factors.into_iter().collect(), &|a, b| a * b)
+            .unwrap_or(Expression::Constant(F::ONE))
+    }
+
+    /// Builds the linear combination `sum(c_i * expr_i)` of `terms` as a balanced sum of
+    /// `Scaled` terms. Terms with a zero coefficient are dropped and terms with a
+    /// coefficient of one are added unscaled. Returns `Constant(0)` if no terms remain.
+    pub fn linear_combination(
+        terms: impl IntoIterator<Item = (F, Expression<F>)>,
+    ) -> Expression<F> {
+        Self::balanced_sum(terms.into_iter().filter_map(|(coeff, expr)| {
+            if coeff == F::ZERO {
+                None
+            } else if coeff == F::ONE {
+                Some(expr)
+            } else {
+                Some(expr * coeff)
+            }
+        }))
+    }
+
+    /// Combines `exprs` pairwise with `combine` until a single expression is left.
+    fn reduce_pairwise(
+        mut exprs: Vec<Expression<F>>,
+        combine: &impl Fn(Expression<F>, Expression<F>) -> Expression<F>,
+    ) -> Option<Expression<F>> {
+        while exprs.len() > 1 {
+            let mut next = Vec::with_capacity((exprs.len() + 1) / 2);
+            let mut exprs_iter = exprs.into_iter();
+            while let Some(a) = exprs_iter.next() {
+                next.push(match exprs_iter.next() {
+                    Some(b) => combine(a, b),
+                    None => a,
+                });
+            }
+            exprs = next;
+        }
+        exprs.pop()
+    }
+
+    /// Estimates the maximum magnitude of this expression, treating field elements as
+    /// integers, given a bound on each leaf. Bounds propagate through sums as the sum of
+    /// the bounds and through products as their product, saturating at `u64::MAX`. A
+    /// negation keeps the bound of its operand, and the scalar of a scaled expression is
+    /// bounded by calling `leaf_bound` on the corresponding constant.
+    pub fn value_bound(&self, leaf_bound: &impl Fn(&Expression<F>) -> u64) -> u64 {
+        match self {
+            Expression::Negated(a) => a.value_bound(leaf_bound),
+            Expression::Sum(a, b) => a
+                .value_bound(leaf_bound)
+                .saturating_add(b.value_bound(leaf_bound)),
+            Expression::Product(a, b) => a
+                .value_bound(leaf_bound)
+                .saturating_mul(b.value_bound(leaf_bound)),
+            Expression::Scaled(a, f) => a
+                .value_bound(leaf_bound)
+                .saturating_mul(leaf_bound(&Expression::Constant(*f))),
+            leaf => leaf_bound(leaf),
+        }
+    }
+
+    /// Returns the number of nodes of this expression, counting every leaf and every
+    /// operation once. The tree is walked with an explicit stack, so this does not
+    /// overflow the call stack on deep expressions.
+    pub fn num_nodes(&self) -> usize {
+        let mut count = 0;
+        let mut stack = vec![self];
+        while let Some(expr) = stack.pop() {
+            count += 1;
+            match expr {
+                Expression::Negated(a) | Expression::Scaled(a, _) => stack.push(a),
+                Expression::Sum(a, b) | Expression::Product(a, b) => {
+                    stack.push(a);
+                    stack.push(b);
+                }
+                _ => (),
+            }
+        }
+        count
+    }
+
+    /// Returns the number of multiplications performed when evaluating this expression
+    /// once: one per `Product` and one per `Scaled` node. Shorthand for
+    /// `self.op_counts().multiplications`.
+    pub fn count_multiplications(&self) -> usize {
+        self.op_counts().multiplications
+    }
+
+    /// Returns the number of additions performed when evaluating this expression once,
+    /// that is the number of `Sum` nodes. Shorthand for `self.op_counts().additions`.
+    pub fn count_additions(&self) -> usize {
+        self.op_counts().additions
+    }
+
+    /// Approximate the computational complexity of this expression.
+    pub fn complexity(&self) -> usize {
+        match self {
+            Expression::Constant(_) => 0,
+            Expression::Selector(_) => 1,
+            Expression::Fixed(_) => 1,
+            Expression::Advice(_) => 1,
+            Expression::Instance(_) => 1,
+            Expression::Challenge(_) => 0,
+            Expression::Negated(poly) => poly.complexity() + 5,
+            Expression::Sum(a, b) => a.complexity() + b.complexity() + 15,
+            Expression::Product(a, b) => a.complexity() + b.complexity() + 30,
+            Expression::Scaled(poly, _) => poly.complexity() + 30,
+        }
+    }
+
+    /// Square this expression. Shorthand for `self.pow(2)`.
+    pub fn square(self) -> Self {
+        self.pow(2)
+    }
+
+    /// Raises this expression to the power `exponent` using exponentiation by squaring,
+    /// so that the resulting product tree has depth logarithmic in `exponent`. Returns the
+    /// constant one when `exponent` is zero.
+    ///
+    /// A `Product` owns both of its operands, so each squaring clones the subtree being
+    /// squared: the returned tree holds `exponent` copies of this expression, and its size
+    /// grows linearly with `exponent`. Large exponents allocate correspondingly large
+    /// trees.
+    pub fn pow(self, exponent: u64) -> Expression<F> {
+        match exponent {
+            0 => Expression::Constant(F::ONE),
+            1 => self,
+            _ if exponent % 2 == 1 => self.clone().pow(exponent - 1) * self,
+            _ => {
+                let half = self.pow(exponent / 2);
+                half.clone() * half
+            }
+        }
+    }
+
+    /// Replaces every fixed query whose `(column_index, rotation)` appears in `values`
+    /// with the corresponding constant. The query indices of the returned expression are
+    /// reset, as the set of queries it uses may have changed.
+    pub fn substitute_fixed(&self, values: &HashMap<(usize, i32), F>) -> Expression<F> {
+        let mut expr = self.evaluate(
+            &|constant| Expression::Constant(constant),
+            &|selector| Expression::Selector(selector),
+            &|query| match values.get(&(query.column_index, query.rotation.0)) {
+                Some(value) => Expression::Constant(*value),
+                None => Expression::Fixed(query),
+            },
+            &|query| Expression::Advice(query),
+            &|query| Expression::Instance(query),
+            &|challenge| Expression::Challenge(challenge),
+            &|a| Expression::Negated(Box::new(a)),
+            &|a, b| Expression::Sum(Box::new(a), Box::new(b)),
+            &|a, b| Expression::Product(Box::new(a), Box::new(b)),
+            &|a, f| Expression::Scaled(Box::new(a), f),
+        );
+        expr.clear_query_indices();
+        expr
+    }
+
+    /// Calls `f` on every challenge leaf of this expression.
+    fn visit_challenges_mut(&mut self, f: &mut impl FnMut(&mut Challenge)) {
+        match self {
+            Expression::Challenge(challenge) => f(challenge),
+            Expression::Negated(a) | Expression::Scaled(a, _) => a.visit_challenges_mut(f),
+            Expression::Sum(a, b) | Expression::Product(a, b) => {
+                a.visit_challenges_mut(f);
+                b.visit_challenges_mut(f);
+            }
+            _ => (),
+        }
+    }
+
+    /// Calls `f` on every instance query leaf of this expression.
+    fn visit_instance_queries_mut(&mut self, f: &mut impl FnMut(&mut InstanceQuery)) {
+        match self {
+            Expression::Instance(query) => f(query),
+            Expression::Negated(a) | Expression::Scaled(a, _) => a.visit_instance_queries_mut(f),
+            Expression::Sum(a, b) | Expression::Product(a, b) => {
+                a.visit_instance_queries_mut(f);
+                b.visit_instance_queries_mut(f);
+            }
+            _ => (),
+        }
+    }
+
+    /// Calls `f` on every advice query leaf of this expression.
+    fn visit_advice_queries_mut(&mut self, f: &mut impl FnMut(&mut AdviceQuery)) {
+        match self {
+            Expression::Advice(query) => f(query),
+            Expression::Negated(a) | Expression::Scaled(a, _) => a.visit_advice_queries_mut(f),
+            Expression::Sum(a, b) | Expression::Product(a, b) => {
+                a.visit_advice_queries_mut(f);
+                b.visit_advice_queries_mut(f);
+            }
+            _ => (),
+        }
+    }
+
+    /// Clears the query indices of this expression, so that they are assigned again
+    /// the next time `query_cells` is called.
+    fn clear_query_indices(&mut self) {
+        match self {
+            Expression::Constant(_) | Expression::Selector(_) | Expression::Challenge(_) => (),
+            Expression::Fixed(query) => query.index = None,
+            Expression::Advice(query) => query.index = None,
+            Expression::Instance(query) => query.index = None,
+            Expression::Negated(a) | Expression::Scaled(a, _) => a.clear_query_indices(),
+            Expression::Sum(a, b) | Expression::Product(a, b) => {
+                a.clear_query_indices();
+                b.clear_query_indices();
+            }
+        }
+    }
+
+    /// Returns whether or not this expression contains a simple `Selector`.
+    fn contains_simple_selector(&self) -> bool {
+        self.evaluate(
+            &|_| false,
+            &|selector| selector.is_simple(),
+            &|_| false,
+            &|_| false,
+            &|_| false,
+            &|_| false,
+            &|a| a,
+            &|a, b| a || b,
+            &|a, b| a || b,
+            &|a, _| a,
+        )
+    }
+
+    /// Extracts a simple selector from this gate, if present
+    fn extract_simple_selector(&self) -> Option<Selector> {
+        let op = |a, b| match (a, b) {
+            (Some(a), None) | (None, Some(a)) => Some(a),
+            (Some(_), Some(_)) => panic!("two simple selectors cannot be in the same expression"),
+            _ => None,
+        };
+
+        self.evaluate(
+            &|_| None,
+            &|selector| {
+                if selector.is_simple() {
+                    Some(selector)
+                } else {
+                    None
+                }
+            },
+            &|_| None,
+            &|_| None,
+            &|_| None,
+            &|_| None,
+            &|a| a,
+            &op,
+            &op,
+            &|a, _| a,
+        )
+    }
+}
+
+/// Monomials of an expanded expression, as coefficients and the leaves multiplied.
+type Monomials<F> = Vec<(F, Vec<Expression<F>>)>;
+
+/// Key under which like monomials are combined: their number of leaves and the sorted
+/// identifiers of those leaves.
+type MonomialKey = (usize, Vec<String>);
+
+/// Structural identity of a subexpression in [`Expression::evaluate_cse`]: queries by
+/// their column and rotation, and operations in terms of the ids of their operands.
+#[derive(PartialEq, Eq)]
+enum CseKey<F> {
+    Constant(F),
+    Selector(Selector),
+    Fixed(usize, Rotation),
+    Advice(usize, Rotation, sealed::Phase),
+    Instance(usize, Rotation),
+    Challenge(Challenge),
+    Negated(usize),
+    Sum(usize, usize),
+    Product(usize, usize),
+    Scaled(usize, F),
+}
+
+// Field elements are not required to implement `Hash`, so scalars are hashed through
+// their `Debug` representation, streamed into the hasher without allocating.
+impl<F: Debug> Hash for CseKey<F> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        struct HashWriter<'a, H>(&'a mut H);
+
+        impl<H: Hasher> std::fmt::Write for HashWriter<'_, H> {
+            fn write_str(&mut self, s: &str) -> std::fmt::Result {
+                self.0.write(s.as_bytes());
+                Ok(())
+            }
+        }
+
+        fn hash_scalar<F: Debug, H: Hasher>(scalar: &F, state: &mut H) {
+            std::fmt::Write::write_fmt(&mut HashWriter(state), format_args!("{scalar:?}"))
+                .expect("hashing does not fail")
+        }
+
+        std::mem::discriminant(self).hash(state);
+        match self {
+            CseKey::Constant(scalar) => hash_scalar(scalar, state),
+            CseKey::Selector(selector) => selector.hash(state),
+            CseKey::Fixed(column, rotation) | CseKey::Instance(column, rotation) => {
+                (column, rotation).hash(state)
+            }
+            CseKey::Advice(column, rotation, phase) => (column, rotation, phase).hash(state),
+            CseKey::Challenge(challenge) => challenge.hash(state),
+            CseKey::Negated(a) => a.hash(state),
+            CseKey::Scaled(a, scalar) => {
+                a.hash(state);
+                hash_scalar(scalar, state)
+            }
+            CseKey::Sum(a, b) | CseKey::Product(a, b) => (a, b).hash(state),
+        }
+    }
+}
+
+/// Values of the subexpressions computed by [`Expression::evaluate_cse`], indexed by id.
+struct CseMemo<F, T> {
+    ids: HashMap<CseKey<F>, usize>,
+    values: Vec<T>,
+}
+
+impl<F: Debug + Eq, T> CseMemo<F, T> {
+    /// Returns the id of the value of the subexpression identified by `key`, computing it
+    /// from the values computed so far if it has not been computed yet.
+    fn intern(&mut self, key: CseKey<F>, value: impl FnOnce(&[T]) -> T) -> usize {
+        if let Some(id) = self.ids.get(&key) {
+            return *id;
+        }
+        let id = self.values.len();
+        let value = value(&self.values);
+        self.values.push(value);
+        self.ids.insert(key, id);
+        id
+    }
+}
+
+impl<F: std::fmt::Debug> std::fmt::Debug for Expression<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expression::Constant(scalar) => f.debug_tuple("Constant").field(scalar).finish(),
+            Expression::Selector(selector) => f.debug_tuple("Selector").field(selector).finish(),
+            // Skip enum variant and print query struct directly to maintain backwards compatibility.
+            Expression::Fixed(query) => {
+                let mut debug_struct = f.debug_struct("Fixed");
+                match query.index {
+                    None => debug_struct.field("query_index", &query.index),
+                    Some(idx) => debug_struct.field("query_index", &idx),
+                };
+                debug_struct
+                    .field("column_index", &query.column_index)
+                    .field("rotation", &query.rotation)
+                    .finish()
+            }
+            Expression::Advice(query) => {
+                let mut debug_struct = f.debug_struct("Advice");
+                match query.index {
+                    None => debug_struct.field("query_index", &query.index),
+                    Some(idx) => debug_struct.field("query_index", &idx),
+                };
+                debug_struct
+                    .field("column_index", &query.column_index)
+                    .field("rotation", &query.rotation);
+                // Only show advice's phase if it's not in first phase.
+                if query.phase != FirstPhase.to_sealed() {
+                    debug_struct.field("phase", &query.phase);
+                }
+                debug_struct.finish()
+            }
+            Expression::Instance(query) => {
+                let mut debug_struct = f.debug_struct("Instance");
+                match query.index {
+                    None => debug_struct.field("query_index", &query.index),
+                    Some(idx) => debug_struct.field("query_index", &idx),
+                };
+                debug_struct
+                    .field("column_index", &query.column_index)
+                    .field("rotation", &query.rotation)
+                    .finish()
+            }
+            Expression::Challenge(challenge) => {
+                f.debug_tuple("Challenge").field(challenge).finish()
+            }
+            Expression::Negated(poly) => f.debug_tuple("Negated").field(poly).finish(),
+            Expression::Sum(a, b) => f.debug_tuple("Sum").field(a).field(b).finish(),
+            Expression::Product(a, b) => f.debug_tuple("Product").field(a).field(b).finish(),
+            Expression::Scaled(poly, scalar) => {
+                f.debug_tuple("Scaled").field(poly).field(scalar).finish()
+            }
+        }
+    }
+}
+
+impl<F: Field> Expression<F> {
+    /// Binding strength of this expression in infix notation: sums bind loosest, then
+    /// products and scalings, then negations, and leaves bind tightest.
+    fn precedence(&self) -> u8 {
+        match self {
+            Expression::Sum(_, _) => 1,
+            Expression::Product(_, _) | Expression::Scaled(_, _) => 2,
+            Expression::Negated(_) => 3,
+            Expression::Constant(scalar) if matches!(small_scalar(scalar), Some(n) if n < 0) => 3,
+            _ => 4,
+        }
+    }
+
+    /// Writes this expression in infix notation, writing its leaves other than constants
+    /// with `leaf`.
+    fn fmt_infix(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        leaf: &dyn Fn(&Expression<F>, &mut std::fmt::Formatter<'_>) -> std::fmt::Result,
+    ) -> std::fmt::Result {
+        match self {
+            Expression::Constant(scalar) => fmt_scalar(scalar, f),
+            Expression::Negated(a) => {
+                write!(f, "-")?;
+                a.fmt_operand(f, 4, leaf)
+            }
+            Expression::Sum(a, b) => {
+                a.fmt_operand(f, 1, leaf)?;
+                match b.as_ref() {
+                    Expression::Negated(b) => {
+                        write!(f, " - ")?;
+                        b.fmt_operand(f, 2, leaf)
+                    }
+                    _ => {
+                        write!(f, " + ")?;
+                        b.fmt_operand(f, 1, leaf)
+                    }
+                }
+            }
+            Expression::Product(a, b) => {
+                a.fmt_operand(f, 2, leaf)?;
+                write!(f, " * ")?;
+                b.fmt_operand(f, 2, leaf)
+            }
+            Expression::Scaled(a, scalar) => {
+                fmt_scalar(scalar, f)?;
+                write!(f, "*")?;
+                a.fmt_operand(f, 2, leaf)
+            }
+            _ => leaf(self, f),
+        }
+    }
+
+    /// Writes this expression as in [`Expression::fmt_infix`], wrapped in parentheses if
+    /// it binds looser than `precedence`.
+    fn fmt_operand(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        precedence: u8,
+        leaf: &dyn Fn(&Expression<F>, &mut std::fmt::Formatter<'_>) -> std::fmt::Result,
+    ) -> std::fmt::Result {
+        if self.precedence() < precedence {
+            write!(f, "(")?;
+            self.fmt_infix(f, leaf)?;
+            write!(f, ")")
+        } else {
+            self.fmt_infix(f, leaf)
+        }
+    }
+}
+
+/// Displays an expression in infix notation, writing its leaves other than constants
+/// with `leaf`. Shared by the [`std::fmt::Display`] implementation of [`Expression`] and
+/// [`Expression::render`].
+struct Infix<'a, F, L> {
+    expr: &'a Expression<F>,
+    leaf: L,
+}
+
+impl<F: Field, L> std::fmt::Display for Infix<'_, F, L>
+where
+    L: Fn(&Expression<F>, &mut std::fmt::Formatter<'_>) -> std::fmt::Result,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.expr.fmt_infix(f, &self.leaf)
+    }
+}
+
+/// Writes a query, selector or challenge leaf as in the [`std::fmt::Display`]
+/// implementation of [`Expression`].
+fn fmt_leaf<F: Field>(expr: &Expression<F>, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match expr {
+        Expression::Selector(selector) => write!(f, "s{}", selector.0),
+        Expression::Fixed(query) => write!(f, "f{}[{}]", query.column_index, query.rotation.0),
+        Expression::Advice(query) => write!(f, "a{}[{}]", query.column_index, query.rotation.0),
+        Expression::Instance(query) => {
+            write!(f, "i{}[{}]", query.column_index, query.rotation.0)
+        }
+        Expression::Challenge(challenge) => write!(f, "challenge{}", challenge.index()),
+        _ => unreachable!("not a leaf"),
+    }
+}
+
+/// Returns `scalar` as a signed integer if its absolute value is small enough to be
+/// worth printing in decimal.
+fn small_scalar<F: Field>(scalar: &F) -> Option<i64> {
+    const SMALL: i64 = 256;
+    let mut small = F::ZERO;
+    for n in 0..SMALL {
+        if *scalar == small {
+            return Some(n);
+        }
+        if *scalar == -small {
+            return Some(-n);
+        }
+        small += F::ONE;
+    }
+    None
+}
+
+fn fmt_scalar<F: Field>(scalar: &F, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match small_scalar(scalar) {
+        Some(n) => write!(f, "{n}"),
+        None => write!(f, "{scalar:?}"),
+    }
+}
+
+/// Renders the expression in infix notation, e.g. `s0 * (a0[0] - a1[1]) + 3*f0[-1]`.
+/// Fixed, advice and instance queries are written as `f`, `a` and `i` followed by the
+/// column index and the rotation in brackets, selectors as `s` and challenges as
+/// `challenge` followed by their index. Small constants are written in decimal.
+impl<F: Field> std::fmt::Display for Expression<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_infix(f, &fmt_leaf::<F>)
+    }
+}
+
+impl<F: Field> Neg for Expression<F> {
+    type Output = Expression<F>;
+    fn neg(self) -> Self::Output {
+        Expression::Negated(Box::new(self))
+    }
+}
+
+impl<F: Field> Add for Expression<F> {
+    type Output = Expression<F>;
+    fn add(self, rhs: Expression<F>) -> Expression<F> {
+        if self.contains_simple_selector() || rhs.contains_simple_selector() {
+            panic!("attempted to use a simple selector in an addition");
+        }
+        Expression::Sum(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl<F: Field> Sub for Expression<F> {
+    type Output = Expression<F>;
+    fn sub(self, rhs: Expression<F>) -> Expression<F> {
+        if self.contains_simple_selector() || rhs.contains_simple_selector() {
+            panic!("attempted to use a simple selector in a subtraction");
+        }
+        Expression::Sum(Box::new(self), Box::new(-rhs))
+    }
+}
+
+impl<F: Field> Mul for Expression<F> {
+    type Output = Expression<F>;
+    fn mul(self, rhs: Expression<F>) -> Expression<F> {
+        if self.contains_simple_selector() && rhs.contains_simple_selector() {
+            panic!("attempted to multiply two expressions containing simple selectors");
+        }
+        Expression::Product(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl<F: Field> Mul<F> for Expression<F> {
+    type Output = Expression<F>;
+    fn mul(self, rhs: F) -> Expression<F> {
+        Expression::Scaled(Box::new(self), rhs)
+    }
+}
+
+impl<F: Field> Sum<Self> for Expression<F> {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.reduce(|acc, x| acc + x)
+            .unwrap_or(Expression::Constant(F::ZERO))
+    }
+}
+
+impl<F: Field> Product<Self> for Expression<F> {
+    fn product<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.reduce(|acc, x| acc * x)
+            .unwrap_or(Expression::Constant(F::ONE))
+    }
+}
+
+/// Represents an index into a vector where each entry corresponds to a distinct
+/// point that polynomials are queried at.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct PointIndex(pub usize);
+
+/// A "virtual cell" is a PLONK cell that has been queried at a particular relative offset
+/// within a custom gate.
+#[derive(Clone, Debug)]
+pub struct VirtualCell {
+    pub column: Column<Any>,
+    pub rotation: Rotation,
+}
+
+impl<Col: Into<Column<Any>>> From<(Col, Rotation)> for VirtualCell {
+    fn from((column, rotation): (Col, Rotation)) -> Self {
+        VirtualCell {
+            column: column.into(),
+            rotation,
+        }
+    }
+}
+
+/// An individual polynomial constraint.
+///
+/// These are returned by the closures passed to `ConstraintSystem::create_gate`.
+#[derive(Debug)]
+pub struct Constraint<F: Field> {
+    name: String,
+    poly: Expression<F>,
+}
+
+impl<F: Field> From<Expression<F>> for Constraint<F> {
+    fn from(poly: Expression<F>) -> Self {
+        Constraint {
+            name: "".to_string(),
+            poly,
+        }
+    }
+}
+
+impl<F: Field, S: AsRef<str>> From<(S, Expression<F>)> for Constraint<F> {
+    fn from((name, poly): (S, Expression<F>)) -> Self {
+        Constraint {
+            name: name.as_ref().to_string(),
+            poly,
+        }
+    }
+}
+
+impl<F: Field> From<Expression<F>> for Vec<Constraint<F>> {
+    fn from(poly: Expression<F>) -> Self {
+        vec![Constraint {
+            name: "".to_string(),
+            poly,
+        }]
+    }
+}
+
+/// A set of polynomial constraints with a common selector.
+///
+/// ```
+/// use halo2_common::{plonk::{Constraints, Expression}};
+/// use halo2_middleware::poly::Rotation;
+/// use halo2curves::pasta::Fp;
+/// # use halo2_common::plonk::ConstraintSystem;
+///
+/// # let mut meta = ConstraintSystem::<Fp>::default();
+/// let a = meta.advice_column();
+/// let b = meta.advice_column();
+/// let c = meta.advice_column();
+/// let s = meta.selector();
+///
+/// meta.create_gate("foo", |meta| {
+///     let next = meta.query_advice(a, Rotation::next());
+///     let a = meta.query_advice(a, Rotation::cur());
+///     let b = meta.query_advice(b, Rotation::cur());
+///     let c = meta.query_advice(c, Rotation::cur());
+///     let s_ternary = meta.query_selector(s);
+///
+///     let one_minus_a = Expression::Constant(Fp::one()) - a.clone();
+///
+///     Constraints::with_selector(
+///         s_ternary,
+///         std::array::IntoIter::new([
+///             ("a is boolean", a.clone() * one_minus_a.clone()),
+///             ("next == a ? b : c", next - (a * b + one_minus_a * c)),
+///         ]),
+///     )
+/// });
+/// ```
+///
+/// Note that the use of `std::array::IntoIter::new` is only necessary if you need to
+/// support Rust 1.51 or 1.52. If your minimum supported Rust version is 1.53 or greater,
+/// you can pass an array directly.
+#[derive(Debug)]
+pub struct Constraints<F: Field, C: Into<Constraint<F>>, Iter: IntoIterator<Item = C>> {
+    selector: Expression<F>,
+    constraints: Iter,
+}
+
+impl<F: Field, C: Into<Constraint<F>>, Iter: IntoIterator<Item = C>> Constraints<F, C, Iter> {
+    /// Constructs a set of constraints that are controlled by the given selector.
+    ///
+    /// Each constraint `c` in `iterator` will be converted into the constraint
+    /// `selector * c`.
+    pub fn with_selector(selector: Expression<F>, constraints: Iter) -> Self {
+        Constraints {
+            selector,
+            constraints,
+        }
+    }
+}
+
+fn apply_selector_to_constraint<F: Field, C: Into<Constraint<F>>>(
+    (selector, c): (Expression<F>, C),
+) -> Constraint<F> {
+    let constraint: Constraint<F> = c.into();
+    Constraint {
+        name: constraint.name,
+        poly: selector * constraint.poly,
+    }
+}
+
+type ApplySelectorToConstraint<F, C> = fn((Expression<F>, C)) -> Constraint<F>;
+type ConstraintsIterator<F, C, I> = std::iter::Map<
+    std::iter::Zip<std::iter::Repeat<Expression<F>>, I>,
+    ApplySelectorToConstraint<F, C>,
+>;
+
+impl<F: Field, C: Into<Constraint<F>>, Iter: IntoIterator<Item = C>> IntoIterator
+    for Constraints<F, C, Iter>
+{
+    type Item = Constraint<F>;
+    type IntoIter = ConstraintsIterator<F, C, Iter::IntoIter>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        std::iter::repeat(self.selector)
+            .zip(self.constraints)
+            .map(apply_selector_to_constraint)
+    }
+}
+
+/// Gate
+#[derive(Clone, Debug)]
+pub struct Gate<F: Field> {
+    name: String,
+    constraint_names: Vec<String>,
+    polys: Vec<Expression<F>>,
+    /// We track queried selectors separately from other cells, so that we can use them to
+    /// trigger debug checks on gates.
+    queried_selectors: Vec<Selector>,
+    queried_cells: Vec<VirtualCell>,
+    /// Arbitrary key/value annotations, such as the source location of the gate. They
+    /// are not part of the circuit.
+    metadata: HashMap<String, String>,
+    /// Whether this gate is intended to hold on every row, without a selector.
+    global: bool,
+}
+
+impl<F: Field> Gate<F> {
+    /// Returns the gate name.
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// Returns the name of the constraint at index `constraint_index`.
+    pub fn constraint_name(&self, constraint_index: usize) -> &str {
+        self.constraint_names[constraint_index].as_str()
+    }
+
+    /// Returns constraints of this gate
+    pub fn polynomials(&self) -> &[Expression<F>] {
+        &self.polys
+    }
+
+    pub fn queried_selectors(&self) -> &[Selector] {
+        &self.queried_selectors
+    }
+
+    pub fn queried_cells(&self) -> &[VirtualCell] {
+        &self.queried_cells
+    }
+
+    /// Returns whether this gate was created with [`ConstraintSystem::add_global_gate`]
+    /// and is intended to hold on every row.
+    pub fn is_global(&self) -> bool {
+        self.global
+    }
+
+    /// Returns the `(row, constraint_index)` of the first constraint of this gate that
+    /// does not evaluate to zero, scanning the `num_rows` rows in order and stopping at
+    /// the first violation. Queries are resolved as in [`Expression::evaluate_rows`],
+    /// with rotations wrapping around.
+    pub fn first_violation(
+        &self,
+        num_rows: usize,
+        advice: &impl Fn(usize, AdviceQuery) -> F,
+        fixed: &impl Fn(usize, FixedQuery) -> F,
+        instance: &impl Fn(usize, InstanceQuery) -> F,
+        challenge: &impl Fn(Challenge) -> F,
+    ) -> Option<(usize, usize)> {
+        (0..num_rows).find_map(|row| {
+            self.polys
+                .iter()
+                .position(|poly| {
+                    poly.evaluate_row(row, num_rows, advice, fixed, instance, challenge) != F::ZERO
+                })
+                .map(|constraint_index| (row, constraint_index))
+        })
+    }
+
+    /// Sets the metadata entry `key` of this gate to `value`, returning the previous
+    /// value if any.
+    pub fn set_metadata(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Option<String> {
+        self.metadata.insert(key.into(), value.into())
+    }
+
+    /// Returns the metadata entry `key` of this gate, if set.
+    pub fn get_metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(String::as_str)
+    }
+}
+
+struct QueriesMap {
+    advice_map: HashMap<(Column<Advice>, Rotation), usize>,
+    instance_map: HashMap<(Column<Instance>, Rotation), usize>,
+    fixed_map: HashMap<(Column<Fixed>, Rotation), usize>,
+    advice: Vec<(Column<Advice>, Rotation)>,
+    instance: Vec<(Column<Instance>, Rotation)>,
+    fixed: Vec<(Column<Fixed>, Rotation)>,
+}
+
+impl QueriesMap {
+    fn add_advice(&mut self, col: Column<Advice>, rot: Rotation) -> usize {
+        *self.advice_map.entry((col, rot)).or_insert_with(|| {
+            self.advice.push((col, rot));
+            self.advice.len() - 1
+        })
+    }
+    fn add_instance(&mut self, col: Column<Instance>, rot: Rotation) -> usize {
+        *self.instance_map.entry((col, rot)).or_insert_with(|| {
+            self.instance.push((col, rot));
+            self.instance.len() - 1
+        })
+    }
+    fn add_fixed(&mut self, col: Column<Fixed>, rot: Rotation) -> usize {
+        *self.fixed_map.entry((col, rot)).or_insert_with(|| {
+            self.fixed.push((col, rot));
+            self.fixed.len() - 1
+        })
+    }
+}
+
+impl QueriesMap {
+    fn as_expression<F: Field>(&mut self, expr: &ExpressionMid<F>) -> Expression<F> {
+        match expr {
+            ExpressionMid::Constant(c) => Expression::Constant(*c),
+            ExpressionMid::Fixed(query) => {
+                let (col, rot) = (Column::new(query.column_index, Fixed), query.rotation);
+                let index = self.add_fixed(col, rot);
+                Expression::Fixed(FixedQuery {
+                    index: Some(index),
+                    column_index: query.column_index,
+                    rotation: query.rotation,
+                })
+            }
+            ExpressionMid::Advice(query) => {
+                let (col, rot) = (
+                    Column::new(query.column_index, Advice { phase: query.phase }),
+                    query.rotation,
+                );
+                let index = self.add_advice(col, rot);
+                Expression::Advice(AdviceQuery {
+                    index: Some(index),
+                    column_index: query.column_index,
+                    rotation: query.rotation,
+                    phase: sealed::Phase(query.phase),
+                })
+            }
+            ExpressionMid::Instance(query) => {
+                let (col, rot) = (Column::new(query.column_index, Instance), query.rotation);
+                let index = self.add_instance(col, rot);
+                Expression::Instance(InstanceQuery {
+                    index: Some(index),
+                    column_index: query.column_index,
+                    rotation: query.rotation,
+                })
+            }
+            ExpressionMid::Challenge(c) => Expression::Challenge((*c).into()),
+            ExpressionMid::Negated(e) => Expression::Negated(Box::new(self.as_expression(e))),
+            ExpressionMid::Sum(lhs, rhs) => Expression::Sum(
+                Box::new(self.as_expression(lhs)),
+                Box::new(self.as_expression(rhs)),
+            ),
+            ExpressionMid::Product(lhs, rhs) => Expression::Product(
+                Box::new(self.as_expression(lhs)),
+                Box::new(self.as_expression(rhs)),
+            ),
+            ExpressionMid::Scaled(e, c) => Expression::Scaled(Box::new(self.as_expression(e)), *c),
+        }
+    }
+}
+
+impl<F: Field> From<ConstraintSystem<F>> for ConstraintSystemV2Backend<F> {
+    fn from(cs: ConstraintSystem<F>) -> Self {
+        ConstraintSystemV2Backend {
+            num_fixed_columns: cs.num_fixed_columns,
+            num_advice_columns: cs.num_advice_columns,
+            num_instance_columns: cs.num_instance_columns,
+            num_challenges: cs.num_challenges,
+            unblinded_advice_columns: cs.unblinded_advice_columns,
+            advice_column_phase: cs.advice_column_phase.iter().map(|p| p.0).collect(),
+            challenge_phase: cs.challenge_phase.iter().map(|p| p.0).collect(),
+            gates: cs
+                .gates
+                .into_iter()
+                .flat_map(|mut g| {
+                    let constraint_names = std::mem::take(&mut g.constraint_names);
+                    let gate_name = g.name.clone();
+                    g.polys.into_iter().enumerate().map(move |(i, e)| {
+                        let name = match constraint_names[i].as_str() {
+                            "" => gate_name.clone(),
+                            constraint_name => format!("{gate_name}:{constraint_name}"),
+                        };
+                        GateV2Backend {
+                            name,
+                            poly: e.into(),
+                        }
+                    })
+                })
+                .collect(),
+            permutation: halo2_middleware::permutation::ArgumentV2 {
+                columns: cs
+                    .permutation
+                    .columns
+                    .into_iter()
+                    .map(|c| c.into())
+                    .collect(),
+            },
+            lookups: cs
+                .lookups
+                .into_iter()
+                .map(|l| halo2_middleware::lookup::ArgumentV2 {
+                    name: l.name,
+                    input_expressions: l.input_expressions.into_iter().map(|e| e.into()).collect(),
+                    table_expressions: l.table_expressions.into_iter().map(|e| e.into()).collect(),
+                })
+                .collect(),
+            shuffles: cs
+                .shuffles
+                .into_iter()
+                .map(|s| halo2_middleware::shuffle::ArgumentV2 {
+                    name: s.name,
+                    input_expressions: s.input_expressions.into_iter().map(|e| e.into()).collect(),
+                    shuffle_expressions: s
+                        .shuffle_expressions
+                        .into_iter()
+                        .map(|e| e.into())
+                        .collect(),
+                })
+                .collect(),
+            general_column_annotations: cs.general_column_annotations,
+            minimum_degree: cs.minimum_degree,
+        }
+    }
+}
+
+/// Collect queries used in gates while mapping those gates to equivalent ones with indexed
+/// query references in the expressions.
+fn cs2_collect_queries_gates<F: Field>(
+    cs2: &ConstraintSystemV2Backend<F>,
+    queries: &mut QueriesMap,
+) -> Vec<Gate<F>> {
+    cs2.gates
+        .iter()
+        .map(|gate| Gate {
+            name: gate.name.clone(),
+            constraint_names: vec![String::new()],
+            polys: vec![queries.as_expression(gate.polynomial())],
+            queried_selectors: Vec::new(), // Unused?
+            queried_cells: Vec::new(),     // Unused?
+            metadata: HashMap::new(),
+            global: false,
+        })
+        .collect()
+}
+
+/// Collect queries used in lookups while mapping those lookups to equivalent ones with indexed
+/// query references in the expressions.
+fn cs2_collect_queries_lookups<F: Field>(
+    cs2: &ConstraintSystemV2Backend<F>,
+    queries: &mut QueriesMap,
+) -> Vec<lookup::Argument<F>> {
+    cs2.lookups
+        .iter()
+        .map(|lookup| lookup::Argument {
+            name: lookup.name.clone(),
+            input_expressions: lookup
+                .input_expressions
+                .iter()
+                .map(|e| queries.as_expression(e))
+                .collect(),
+            table_expressions: lookup
+                .table_expressions
+                .iter()
+                .map(|e| queries.as_expression(e))
+                .collect(),
+        })
+        .collect()
+}
+
+/// Collect queries used in shuffles while mapping those lookups to equivalent ones with indexed
+/// query references in the expressions.
 fn cs2_collect_queries_shuffles<F: Field>(
     cs2: &ConstraintSystemV2Backend<F>,
     queries: &mut QueriesMap,
@@ -1672,1136 +4046,5087 @@ fn cs2_collect_queries_shuffles<F: Field>(
             input_expressions: shuffle
                 .input_expressions
                 .iter()
-                .map(|e| queries.as_expression(e))
-                .collect(),
-            shuffle_expressions: shuffle
-                .shuffle_expressions
+                .map(|e| queries.as_expression(e))
+                .collect(),
+            shuffle_expressions: shuffle
+                .shuffle_expressions
+                .iter()
+                .map(|e| queries.as_expression(e))
+                .collect(),
+        })
+        .collect()
+}
+
+/// Collect all queries used in the expressions of gates, lookups and shuffles.  Map the
+/// expressions of gates, lookups and shuffles into equivalent ones with indexed query
+/// references.
+#[allow(clippy::type_complexity)]
+pub fn collect_queries<F: Field>(
+    cs2: &ConstraintSystemV2Backend<F>,
+) -> (
+    Queries,
+    Vec<Gate<F>>,
+    Vec<lookup::Argument<F>>,
+    Vec<shuffle::Argument<F>>,
+) {
+    let mut queries = QueriesMap {
+        advice_map: HashMap::new(),
+        instance_map: HashMap::new(),
+        fixed_map: HashMap::new(),
+        advice: Vec::new(),
+        instance: Vec::new(),
+        fixed: Vec::new(),
+    };
+
+    let gates = cs2_collect_queries_gates(cs2, &mut queries);
+    let lookups = cs2_collect_queries_lookups(cs2, &mut queries);
+    let shuffles = cs2_collect_queries_shuffles(cs2, &mut queries);
+
+    // Each column used in a copy constraint involves a query at rotation current.
+    for column in &cs2.permutation.columns {
+        match column.column_type {
+            Any::Instance => {
+                queries.add_instance(Column::new(column.index, Instance), Rotation::cur())
+            }
+            Any::Fixed => queries.add_fixed(Column::new(column.index, Fixed), Rotation::cur()),
+            Any::Advice(advice) => {
+                queries.add_advice(Column::new(column.index, advice), Rotation::cur())
+            }
+        };
+    }
+
+    let mut num_advice_queries = vec![0; cs2.num_advice_columns];
+    for (column, _) in queries.advice.iter() {
+        num_advice_queries[column.index()] += 1;
+    }
+
+    let queries = Queries {
+        advice: queries.advice,
+        instance: queries.instance,
+        fixed: queries.fixed,
+        num_advice_queries,
+    };
+    (queries, gates, lookups, shuffles)
+}
+
+/// This is a description of the circuit environment, such as the gate, column and
+/// permutation arrangements.
+#[derive(Debug, Clone)]
+pub struct ConstraintSystem<F: Field> {
+    pub num_fixed_columns: usize,
+    pub num_advice_columns: usize,
+    pub num_instance_columns: usize,
+    pub num_selectors: usize,
+    pub num_challenges: usize,
+
+    /// Contains the index of each advice column that is left unblinded.
+    pub unblinded_advice_columns: Vec<usize>,
+
+    /// Contains the phase for each advice column. Should have same length as num_advice_columns.
+    pub advice_column_phase: Vec<sealed::Phase>,
+    /// Contains the phase for each challenge. Should have same length as num_challenges.
+    pub challenge_phase: Vec<sealed::Phase>,
+
+    /// This is a cached vector that maps virtual selectors to the concrete
+    /// fixed column that they were compressed into. This is just used by dev
+    /// tooling right now.
+    pub selector_map: Vec<Column<Fixed>>,
+
+    pub gates: Vec<Gate<F>>,
+    pub advice_queries: Vec<(Column<Advice>, Rotation)>,
+    // Contains an integer for each advice column
+    // identifying how many distinct queries it has
+    // so far; should be same length as num_advice_columns.
+    pub num_advice_queries: Vec<usize>,
+    pub instance_queries: Vec<(Column<Instance>, Rotation)>,
+    pub fixed_queries: Vec<(Column<Fixed>, Rotation)>,
+
+    // Permutation argument for performing equality constraints
+    pub permutation: permutation::Argument,
+
+    // Vector of lookup arguments, where each corresponds to a sequence of
+    // input expressions and a sequence of table expressions involved in the lookup.
+    pub lookups: Vec<lookup::Argument<F>>,
+
+    // Vector of shuffle arguments, where each corresponds to a sequence of
+    // input expressions and a sequence of shuffle expressions involved in the shuffle.
+    pub shuffles: Vec<shuffle::Argument<F>>,
+
+    // List of indexes of Fixed columns which are associated to a circuit-general Column tied to their annotation.
+    pub general_column_annotations: HashMap<metadata::Column, String>,
+
+    // Vector of fixed columns, which can be used to store constant values
+    // that are copied into advice columns.
+    pub constants: Vec<Column<Fixed>>,
+
+    pub minimum_degree: Option<usize>,
+}
+
+/// The fields of a [`ConstraintSystem`], moved out by [`ConstraintSystem::into_parts`]
+/// and moved back in by [`ConstraintSystem::from_parts`].
+#[derive(Debug, Clone)]
+pub struct ConstraintSystemParts<F: Field> {
+    pub num_fixed_columns: usize,
+    pub num_advice_columns: usize,
+    pub num_instance_columns: usize,
+    pub num_selectors: usize,
+    pub num_challenges: usize,
+    pub unblinded_advice_columns: Vec<usize>,
+    pub advice_column_phase: Vec<sealed::Phase>,
+    pub challenge_phase: Vec<sealed::Phase>,
+    pub selector_map: Vec<Column<Fixed>>,
+    pub gates: Vec<Gate<F>>,
+    pub advice_queries: Vec<(Column<Advice>, Rotation)>,
+    pub num_advice_queries: Vec<usize>,
+    pub instance_queries: Vec<(Column<Instance>, Rotation)>,
+    pub fixed_queries: Vec<(Column<Fixed>, Rotation)>,
+    pub permutation: permutation::Argument,
+    pub lookups: Vec<lookup::Argument<F>>,
+    pub shuffles: Vec<shuffle::Argument<F>>,
+    pub general_column_annotations: HashMap<metadata::Column, String>,
+    pub constants: Vec<Column<Fixed>>,
+    pub minimum_degree: Option<usize>,
+}
+
+impl<F: Field> From<ConstraintSystemV2Backend<F>> for ConstraintSystem<F> {
+    fn from(cs2: ConstraintSystemV2Backend<F>) -> Self {
+        let (queries, gates, lookups, shuffles) = collect_queries(&cs2);
+        ConstraintSystem {
+            num_fixed_columns: cs2.num_fixed_columns,
+            num_advice_columns: cs2.num_advice_columns,
+            num_instance_columns: cs2.num_instance_columns,
+            num_selectors: 0,
+            num_challenges: cs2.num_challenges,
+            unblinded_advice_columns: cs2.unblinded_advice_columns,
+            advice_column_phase: cs2
+                .advice_column_phase
+                .into_iter()
+                .map(sealed::Phase)
+                .collect(),
+            challenge_phase: cs2.challenge_phase.into_iter().map(sealed::Phase).collect(),
+            selector_map: Vec::new(),
+            gates,
+            advice_queries: queries.advice,
+            num_advice_queries: queries.num_advice_queries,
+            instance_queries: queries.instance,
+            fixed_queries: queries.fixed,
+            permutation: cs2.permutation.into(),
+            lookups,
+            shuffles,
+            general_column_annotations: cs2.general_column_annotations,
+            constants: Vec::new(),
+            minimum_degree: cs2.minimum_degree,
+        }
+    }
+}
+
+/// Represents the minimal parameters that determine a `ConstraintSystem`.
+#[allow(dead_code)]
+pub struct PinnedConstraintSystem<'a, F: Field> {
+    num_fixed_columns: &'a usize,
+    num_advice_columns: &'a usize,
+    num_instance_columns: &'a usize,
+    num_selectors: &'a usize,
+    num_challenges: &'a usize,
+    advice_column_phase: &'a Vec<sealed::Phase>,
+    challenge_phase: &'a Vec<sealed::Phase>,
+    gates: PinnedGates<'a, F>,
+    advice_queries: &'a Vec<(Column<Advice>, Rotation)>,
+    instance_queries: &'a Vec<(Column<Instance>, Rotation)>,
+    fixed_queries: &'a Vec<(Column<Fixed>, Rotation)>,
+    permutation: &'a permutation::Argument,
+    lookups: &'a Vec<lookup::Argument<F>>,
+    shuffles: &'a Vec<shuffle::Argument<F>>,
+    constants: &'a Vec<Column<Fixed>>,
+    minimum_degree: &'a Option<usize>,
+}
+
+impl<'a, F: Field> std::fmt::Debug for PinnedConstraintSystem<'a, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug_struct = f.debug_struct("PinnedConstraintSystem");
+        debug_struct
+            .field("num_fixed_columns", self.num_fixed_columns)
+            .field("num_advice_columns", self.num_advice_columns)
+            .field("num_instance_columns", self.num_instance_columns)
+            .field("num_selectors", self.num_selectors);
+        // Only show multi-phase related fields if it's used.
+        if *self.num_challenges > 0 {
+            debug_struct
+                .field("num_challenges", self.num_challenges)
+                .field("advice_column_phase", self.advice_column_phase)
+                .field("challenge_phase", self.challenge_phase);
+        }
+        debug_struct
+            .field("gates", &self.gates)
+            .field("advice_queries", self.advice_queries)
+            .field("instance_queries", self.instance_queries)
+            .field("fixed_queries", self.fixed_queries)
+            .field("permutation", self.permutation)
+            .field("lookups", self.lookups);
+        if !self.shuffles.is_empty() {
+            debug_struct.field("shuffles", self.shuffles);
+        }
+        debug_struct
+            .field("constants", self.constants)
+            .field("minimum_degree", self.minimum_degree);
+        debug_struct.finish()
+    }
+}
+
+struct PinnedGates<'a, F: Field>(&'a Vec<Gate<F>>);
+
+impl<'a, F: Field> std::fmt::Debug for PinnedGates<'a, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.debug_list()
+            .entries(self.0.iter().flat_map(|gate| gate.polynomials().iter()))
+            .finish()
+    }
+}
+
+impl<F: Field> Default for ConstraintSystem<F> {
+    fn default() -> ConstraintSystem<F> {
+        ConstraintSystem {
+            num_fixed_columns: 0,
+            num_advice_columns: 0,
+            num_instance_columns: 0,
+            num_selectors: 0,
+            num_challenges: 0,
+            unblinded_advice_columns: Vec::new(),
+            advice_column_phase: Vec::new(),
+            challenge_phase: Vec::new(),
+            selector_map: vec![],
+            gates: vec![],
+            fixed_queries: Vec::new(),
+            advice_queries: Vec::new(),
+            num_advice_queries: Vec::new(),
+            instance_queries: Vec::new(),
+            permutation: permutation::Argument::default(),
+            lookups: Vec::new(),
+            shuffles: Vec::new(),
+            general_column_annotations: HashMap::new(),
+            constants: vec![],
+            minimum_degree: None,
+        }
+    }
+}
+
+impl<F: Field> ConstraintSystem<F> {
+    /// Obtain a pinned version of this constraint system; a structure with the
+    /// minimal parameters needed to determine the rest of the constraint
+    /// system.
+    pub fn pinned(&self) -> PinnedConstraintSystem<'_, F> {
+        PinnedConstraintSystem {
+            num_fixed_columns: &self.num_fixed_columns,
+            num_advice_columns: &self.num_advice_columns,
+            num_instance_columns: &self.num_instance_columns,
+            num_selectors: &self.num_selectors,
+            num_challenges: &self.num_challenges,
+            advice_column_phase: &self.advice_column_phase,
+            challenge_phase: &self.challenge_phase,
+            gates: PinnedGates(&self.gates),
+            fixed_queries: &self.fixed_queries,
+            advice_queries: &self.advice_queries,
+            instance_queries: &self.instance_queries,
+            permutation: &self.permutation,
+            lookups: &self.lookups,
+            shuffles: &self.shuffles,
+            constants: &self.constants,
+            minimum_degree: &self.minimum_degree,
+        }
+    }
+
+    /// Returns a 32-byte BLAKE2b digest of the pinned representation of this constraint
+    /// system, which identifies the circuit structure.
+    pub fn digest(&self) -> [u8; 32] {
+        let mut hasher = Blake2bParams::new()
+            .hash_length(32)
+            .personal(b"Halo2-Constr-Sys")
+            .to_state();
+        hasher.update(format!("{:?}", self.pinned()).as_bytes());
+
+        let mut digest = [0u8; 32];
+        digest.copy_from_slice(hasher.finalize().as_bytes());
+        digest
+    }
+
+    /// Returns a short human-readable identifier of this constraint system for logging,
+    /// such as `cs-3a4f-g12-l2-s1`: the first two bytes of [`ConstraintSystem::digest`]
+    /// in hex followed by the number of gates, lookups and shuffles.
+    pub fn short_id(&self) -> String {
+        let digest = self.digest();
+        format!(
+            "cs-{:02x}{:02x}-g{}-l{}-s{}",
+            digest[0],
+            digest[1],
+            self.gates.len(),
+            self.lookups.len(),
+            self.shuffles.len()
+        )
+    }
+
+    /// Enables this fixed column to be used for global constant assignments.
+    ///
+    /// # Side-effects
+    ///
+    /// The column will be equality-enabled.
+    pub fn enable_constant(&mut self, column: Column<Fixed>) {
+        if !self.constants.contains(&column) {
+            self.constants.push(column);
+            self.enable_equality(column);
+        }
+    }
+
+    /// Enable the ability to enforce equality over cells in this column
+    pub fn enable_equality<C: Into<Column<Any>>>(&mut self, column: C) {
+        let column = column.into();
+        self.query_any_index(column, Rotation::cur());
+        self.permutation.add_column(column);
+    }
+
+    /// Add a lookup argument for some input expressions and table columns.
+    ///
+    /// `table_map` returns a map between input expressions and the table columns
+    /// they need to match.
+    pub fn lookup<S: AsRef<str>>(
+        &mut self,
+        name: S,
+        table_map: impl FnOnce(&mut VirtualCells<'_, F>) -> Vec<(Expression<F>, TableColumn)>,
+    ) -> usize {
+        let mut cells = VirtualCells::new(self);
+        let table_map = table_map(&mut cells)
+            .into_iter()
+            .map(|(mut input, table)| {
+                if input.contains_simple_selector() {
+                    panic!("expression containing simple selector supplied to lookup argument");
+                }
+                let mut table = cells.query_fixed(table.inner(), Rotation::cur());
+                input.query_cells(&mut cells);
+                table.query_cells(&mut cells);
+                (input, table)
+            })
+            .collect();
+        let index = self.lookups.len();
+
+        self.lookups
+            .push(lookup::Argument::new(name.as_ref(), table_map));
+
+        index
+    }
+
+    /// Add a lookup argument for some input expressions and table expressions.
+    ///
+    /// `table_map` returns a map between input expressions and the table expressions
+    /// they need to match.
+    pub fn lookup_any<S: AsRef<str>>(
+        &mut self,
+        name: S,
+        table_map: impl FnOnce(&mut VirtualCells<'_, F>) -> Vec<(Expression<F>, Expression<F>)>,
+    ) -> usize {
+        let mut cells = VirtualCells::new(self);
+        let table_map = table_map(&mut cells)
+            .into_iter()
+            .map(|(mut input, mut table)| {
+                if input.contains_simple_selector() {
+                    panic!("expression containing simple selector supplied to lookup argument");
+                }
+                if table.contains_simple_selector() {
+                    panic!("expression containing simple selector supplied to lookup argument");
+                }
+                input.query_cells(&mut cells);
+                table.query_cells(&mut cells);
+                (input, table)
+            })
+            .collect();
+        let index = self.lookups.len();
+
+        self.lookups
+            .push(lookup::Argument::new(name.as_ref(), table_map));
+
+        index
+    }
+
+    /// Add a shuffle argument for some input expressions and table expressions.
+    pub fn shuffle<S: AsRef<str>>(
+        &mut self,
+        name: S,
+        shuffle_map: impl FnOnce(&mut VirtualCells<'_, F>) -> Vec<(Expression<F>, Expression<F>)>,
+    ) -> usize {
+        let mut cells = VirtualCells::new(self);
+        let shuffle_map = shuffle_map(&mut cells)
+            .into_iter()
+            .map(|(mut input, mut table)| {
+                input.query_cells(&mut cells);
+                table.query_cells(&mut cells);
+                (input, table)
+            })
+            .collect();
+        let index = self.shuffles.len();
+
+        self.shuffles
+            .push(shuffle::Argument::new(name.as_ref(), shuffle_map));
+
+        index
+    }
+
+    fn query_fixed_index(&mut self, column: Column<Fixed>, at: Rotation) -> usize {
+        // Return existing query, if it exists
+        for (index, fixed_query) in self.fixed_queries.iter().enumerate() {
+            if fixed_query == &(column, at) {
+                return index;
+            }
+        }
+
+        // Make a new query
+        let index = self.fixed_queries.len();
+        self.fixed_queries.push((column, at));
+
+        index
+    }
+
+    pub(crate) fn query_advice_index(&mut self, column: Column<Advice>, at: Rotation) -> usize {
+        // Return existing query, if it exists
+        for (index, advice_query) in self.advice_queries.iter().enumerate() {
+            if advice_query == &(column, at) {
+                return index;
+            }
+        }
+
+        // Make a new query
+        let index = self.advice_queries.len();
+        self.advice_queries.push((column, at));
+        self.num_advice_queries[column.index] += 1;
+
+        index
+    }
+
+    fn query_instance_index(&mut self, column: Column<Instance>, at: Rotation) -> usize {
+        // Return existing query, if it exists
+        for (index, instance_query) in self.instance_queries.iter().enumerate() {
+            if instance_query == &(column, at) {
+                return index;
+            }
+        }
+
+        // Make a new query
+        let index = self.instance_queries.len();
+        self.instance_queries.push((column, at));
+
+        index
+    }
+
+    fn query_any_index(&mut self, column: Column<Any>, at: Rotation) -> usize {
+        match column.column_type() {
+            Any::Advice(_) => {
+                self.query_advice_index(Column::<Advice>::try_from(column).unwrap(), at)
+            }
+            Any::Fixed => self.query_fixed_index(Column::<Fixed>::try_from(column).unwrap(), at),
+            Any::Instance => {
+                self.query_instance_index(Column::<Instance>::try_from(column).unwrap(), at)
+            }
+        }
+    }
+
+    pub(crate) fn get_advice_query_index(&self, column: Column<Advice>, at: Rotation) -> usize {
+        for (index, advice_query) in self.advice_queries.iter().enumerate() {
+            if advice_query == &(column, at) {
+                return index;
+            }
+        }
+
+        panic!("get_advice_query_index called for non-existent query");
+    }
+
+    pub(crate) fn get_fixed_query_index(&self, column: Column<Fixed>, at: Rotation) -> usize {
+        for (index, fixed_query) in self.fixed_queries.iter().enumerate() {
+            if fixed_query == &(column, at) {
+                return index;
+            }
+        }
+
+        panic!("get_fixed_query_index called for non-existent query");
+    }
+
+    pub(crate) fn get_instance_query_index(&self, column: Column<Instance>, at: Rotation) -> usize {
+        for (index, instance_query) in self.instance_queries.iter().enumerate() {
+            if instance_query == &(column, at) {
+                return index;
+            }
+        }
+
+        panic!("get_instance_query_index called for non-existent query");
+    }
+
+    pub fn get_any_query_index(&self, column: Column<Any>, at: Rotation) -> usize {
+        match column.column_type() {
+            Any::Advice(_) => {
+                self.get_advice_query_index(Column::<Advice>::try_from(column).unwrap(), at)
+            }
+            Any::Fixed => {
+                self.get_fixed_query_index(Column::<Fixed>::try_from(column).unwrap(), at)
+            }
+            Any::Instance => {
+                self.get_instance_query_index(Column::<Instance>::try_from(column).unwrap(), at)
+            }
+        }
+    }
+
+    /// Sets the minimum degree required by the circuit, which can be set to a
+    /// larger amount than actually needed. This can be used, for example, to
+    /// force the permutation argument to involve more columns in the same set.
+    pub fn set_minimum_degree(&mut self, degree: usize) {
+        self.minimum_degree = Some(degree);
+    }
+
+    /// Creates a new gate.
+    ///
+    /// # Panics
+    ///
+    /// A gate is required to contain polynomial constraints. This method will panic if
+    /// `constraints` returns an empty iterator.
+    pub fn create_gate<C: Into<Constraint<F>>, Iter: IntoIterator<Item = C>, S: AsRef<str>>(
+        &mut self,
+        name: S,
+        constraints: impl FnOnce(&mut VirtualCells<'_, F>) -> Iter,
+    ) {
+        let mut cells = VirtualCells::new(self);
+        let constraints = constraints(&mut cells);
+        let (constraint_names, polys): (_, Vec<_>) = constraints
+            .into_iter()
+            .map(|c| c.into())
+            .map(|mut c: Constraint<F>| {
+                c.poly.query_cells(&mut cells);
+                (c.name, c.poly)
+            })
+            .unzip();
+
+        let queried_selectors = cells.queried_selectors;
+        let queried_cells = cells.queried_cells;
+
+        assert!(
+            !polys.is_empty(),
+            "Gates must contain at least one constraint."
+        );
+
+        self.gates.push(Gate {
+            name: name.as_ref().to_string(),
+            constraint_names,
+            polys,
+            queried_selectors,
+            queried_cells,
+            metadata: HashMap::new(),
+            global: false,
+        });
+    }
+
+    /// Appends a gate made of the single constraint `poly`, registering the queries it
+    /// uses. Returns `self` so that calls can be chained.
+    pub fn add_gate_expr(&mut self, name: impl Into<String>, mut poly: Expression<F>) -> &mut Self {
+        let mut cells = VirtualCells::new(self);
+        poly.query_cells(&mut cells);
+
+        let queried_selectors = cells.queried_selectors;
+        let queried_cells = cells.queried_cells;
+
+        self.gates.push(Gate {
+            name: name.into(),
+            constraint_names: vec![String::new()],
+            polys: vec![poly],
+            queried_selectors,
+            queried_cells,
+            metadata: HashMap::new(),
+            global: false,
+        });
+        self
+    }
+
+    /// Appends a gate made of the single constraint `poly` like
+    /// [`ConstraintSystem::add_gate_expr`], marking it as global: the constraint is
+    /// intended to hold on every row, so [`ConstraintSystem::ungated_gates`] does not
+    /// report it. Returns `self` so that calls can be chained.
+    pub fn add_global_gate(&mut self, name: impl Into<String>, poly: Expression<F>) -> &mut Self {
+        self.add_gate_expr(name, poly);
+        self.gates.last_mut().unwrap().global = true;
+        self
+    }
+
+    /// Moves the fields of this constraint system out, without cloning them.
+    pub fn into_parts(self) -> ConstraintSystemParts<F> {
+        let ConstraintSystem {
+            num_fixed_columns,
+            num_advice_columns,
+            num_instance_columns,
+            num_selectors,
+            num_challenges,
+            unblinded_advice_columns,
+            advice_column_phase,
+            challenge_phase,
+            selector_map,
+            gates,
+            advice_queries,
+            num_advice_queries,
+            instance_queries,
+            fixed_queries,
+            permutation,
+            lookups,
+            shuffles,
+            general_column_annotations,
+            constants,
+            minimum_degree,
+        } = self;
+        ConstraintSystemParts {
+            num_fixed_columns,
+            num_advice_columns,
+            num_instance_columns,
+            num_selectors,
+            num_challenges,
+            unblinded_advice_columns,
+            advice_column_phase,
+            challenge_phase,
+            selector_map,
+            gates,
+            advice_queries,
+            num_advice_queries,
+            instance_queries,
+            fixed_queries,
+            permutation,
+            lookups,
+            shuffles,
+            general_column_annotations,
+            constants,
+            minimum_degree,
+        }
+    }
+
+    /// Builds a constraint system from its fields, as returned by
+    /// [`ConstraintSystem::into_parts`]. No consistency checks are performed.
+    pub fn from_parts(parts: ConstraintSystemParts<F>) -> Self {
+        let ConstraintSystemParts {
+            num_fixed_columns,
+            num_advice_columns,
+            num_instance_columns,
+            num_selectors,
+            num_challenges,
+            unblinded_advice_columns,
+            advice_column_phase,
+            challenge_phase,
+            selector_map,
+            gates,
+            advice_queries,
+            num_advice_queries,
+            instance_queries,
+            fixed_queries,
+            permutation,
+            lookups,
+            shuffles,
+            general_column_annotations,
+            constants,
+            minimum_degree,
+        } = parts;
+        ConstraintSystem {
+            num_fixed_columns,
+            num_advice_columns,
+            num_instance_columns,
+            num_selectors,
+            num_challenges,
+            unblinded_advice_columns,
+            advice_column_phase,
+            challenge_phase,
+            selector_map,
+            gates,
+            advice_queries,
+            num_advice_queries,
+            instance_queries,
+            fixed_queries,
+            permutation,
+            lookups,
+            shuffles,
+            general_column_annotations,
+            constants,
+            minimum_degree,
+        }
+    }
+
+    /// Converts a copy of this constraint system into a `ConstraintSystemV2Backend` and
+    /// back, for checking that a system survives the V2 pipeline. The conversion does not
+    /// preserve `num_selectors`, `selector_map` and `constants`, and gates with several
+    /// constraints come back as one gate per constraint.
+    pub fn roundtrip_v2(&self) -> ConstraintSystem<F> {
+        let cs2: ConstraintSystemV2Backend<F> = self.clone().into();
+        cs2.into()
+    }
+
+    /// Returns a copy of this constraint system that only keeps the gates at
+    /// `gate_indices`. Columns, phases, permutation, lookups and shuffles are kept
+    /// unchanged, while the queries are collected again from the remaining expressions.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an index in `gate_indices` is not the index of a gate.
+    pub fn subset_with_gates(&self, gate_indices: &[usize]) -> ConstraintSystem<F> {
+        let mut cs = ConstraintSystem {
+            gates: gate_indices
+                .iter()
+                .map(|&index| self.gates[index].clone())
+                .collect(),
+            ..self.clone()
+        };
+        cs.reindex_queries();
+        cs
+    }
+
+    /// Sorts the gates by a canonical key, made of the identifiers of their polynomials
+    /// followed by their name, so that the gate order does not depend on the order in
+    /// which gates were registered. The queries are collected again afterwards.
+    pub fn sort_gates(&mut self) {
+        self.gates.sort_by_cached_key(|gate| {
+            let identifiers: String = gate.polys.iter().map(|poly| poly.identifier()).collect();
+            (identifiers, gate.name.clone())
+        });
+        self.reindex_queries();
+    }
+
+    /// Applies `f` to every gate polynomial and collects the queries again from the
+    /// resulting expressions. Returns the maximum degree and the total number of nodes
+    /// of the gate polynomials before and after the transformation.
+    pub fn apply_to_gates(
+        &mut self,
+        f: impl Fn(&Expression<F>) -> Expression<F>,
+    ) -> TransformReport {
+        let measure = |gates: &[Gate<F>]| {
+            let polys = || gates.iter().flat_map(|gate| gate.polys.iter());
+            (
+                polys().map(|poly| poly.degree()).max().unwrap_or(0),
+                polys().map(|poly| poly.num_nodes()).sum::<usize>(),
+            )
+        };
+
+        let (degree_before, nodes_before) = measure(&self.gates);
+        for poly in self.gates.iter_mut().flat_map(|gate| gate.polys.iter_mut()) {
+            *poly = f(poly);
+        }
+        self.reindex_queries();
+        let (degree_after, nodes_after) = measure(&self.gates);
+
+        TransformReport {
+            degree_before,
+            degree_after,
+            nodes_before,
+            nodes_after,
+        }
+    }
+
+    /// Removes the challenges that are not referenced by any gate, lookup or shuffle
+    /// expression, renumbering the remaining ones consecutively (in their original
+    /// order) and rewriting every challenge leaf accordingly. Returns the number of
+    /// challenges removed.
+    pub fn prune_unused_challenges(&mut self) -> usize {
+        let mut used = vec![false; self.num_challenges];
+        self.visit_challenges_mut(&mut |challenge| used[challenge.index] = true);
+
+        let mut renumbering = vec![None; self.num_challenges];
+        let mut challenge_phase = Vec::new();
+        for (index, phase) in self.challenge_phase.iter().enumerate() {
+            if used[index] {
+                renumbering[index] = Some(challenge_phase.len());
+                challenge_phase.push(*phase);
+            }
+        }
+        self.visit_challenges_mut(&mut |challenge| {
+            challenge.index = renumbering[challenge.index].unwrap();
+        });
+
+        let removed = self.num_challenges - challenge_phase.len();
+        self.num_challenges = challenge_phase.len();
+        self.challenge_phase = challenge_phase;
+        removed
+    }
+
+    /// Calls `f` on every challenge leaf of the gate, lookup and shuffle expressions.
+    fn visit_challenges_mut(&mut self, f: &mut impl FnMut(&mut Challenge)) {
+        for expr in self.expressions_mut() {
+            expr.visit_challenges_mut(f);
+        }
+    }
+
+    /// Returns the gate, lookup and shuffle expressions of this constraint system.
+    fn expressions_mut(&mut self) -> impl Iterator<Item = &mut Expression<F>> {
+        self.gates
+            .iter_mut()
+            .flat_map(|gate| gate.polys.iter_mut())
+            .chain(self.lookups.iter_mut().flat_map(|lookup| {
+                lookup
+                    .input_expressions
+                    .iter_mut()
+                    .chain(lookup.table_expressions.iter_mut())
+            }))
+            .chain(self.shuffles.iter_mut().flat_map(|shuffle| {
+                shuffle
+                    .input_expressions
+                    .iter_mut()
+                    .chain(shuffle.shuffle_expressions.iter_mut())
+            }))
+    }
+
+    /// Moves every advice column and challenge `delta` phases later, rewriting the
+    /// phases of the advice queries and challenges of every expression, of the cells
+    /// queried by the gates, of the permutation columns and of the column annotations.
+    /// Returns an error, leaving the constraint system untouched, if a phase would go
+    /// past the third phase.
+    pub fn shift_phases(&mut self, delta: u8) -> Result<(), String> {
+        let max_phase = ThirdPhase.to_sealed().0;
+        let last_phase = self
+            .advice_column_phase
+            .iter()
+            .chain(self.challenge_phase.iter())
+            .map(|phase| phase.0)
+            .max()
+            .unwrap_or_default();
+        if last_phase
+            .checked_add(delta)
+            .map_or(true, |phase| phase > max_phase)
+        {
+            return Err(format!(
+                "shifting phase {last_phase} by {delta} goes past the last phase {max_phase}"
+            ));
+        }
+
+        let shift = |column: &mut Column<Any>| {
+            if let Any::Advice(advice) = &mut column.column_type {
+                advice.phase += delta;
+            }
+        };
+        for phase in self
+            .advice_column_phase
+            .iter_mut()
+            .chain(self.challenge_phase.iter_mut())
+        {
+            phase.0 += delta;
+        }
+        for (column, _) in self.advice_queries.iter_mut() {
+            column.column_type.phase += delta;
+        }
+        for expr in self.expressions_mut() {
+            expr.visit_advice_queries_mut(&mut |query| query.phase.0 += delta);
+            expr.visit_challenges_mut(&mut |challenge| challenge.phase += delta);
+        }
+        for gate in self.gates.iter_mut() {
+            for cell in gate.queried_cells.iter_mut() {
+                shift(&mut cell.column);
+            }
+        }
+        for column in self.permutation.columns.iter_mut() {
+            shift(column);
+        }
+        self.general_column_annotations = std::mem::take(&mut self.general_column_annotations)
+            .into_iter()
+            .map(|(mut column, annotation)| {
+                if let Any::Advice(advice) = &mut column.column_type {
+                    advice.phase += delta;
+                }
+                (column, annotation)
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Renumbers the instance columns so that column `i` becomes column `perm[i]`,
+    /// rewriting the instance queries, the instance leaves of every expression, the
+    /// cells queried by the gates, the columns of the permutation argument and the
+    /// column annotations. Returns
+    /// an error, leaving the constraint system untouched, if `perm` is not a
+    /// permutation of the instance column indices.
+    pub fn permute_instance_columns(&mut self, perm: &[usize]) -> Result<(), Error> {
+        let mut seen = vec![false; self.num_instance_columns];
+        if perm.len() != self.num_instance_columns
+            || !perm
+                .iter()
+                .all(|&index| index < seen.len() && !std::mem::replace(&mut seen[index], true))
+        {
+            return Err(Error::Other(format!(
+                "{perm:?} is not a permutation of {} instance columns",
+                self.num_instance_columns
+            )));
+        }
+
+        let permute = |column: &mut Column<Any>| {
+            if column.column_type == Any::Instance {
+                column.index = perm[column.index];
+            }
+        };
+        for (column, _) in self.instance_queries.iter_mut() {
+            column.index = perm[column.index];
+        }
+        for expr in self.expressions_mut() {
+            expr.visit_instance_queries_mut(&mut |query| {
+                query.column_index = perm[query.column_index]
+            });
+        }
+        for gate in self.gates.iter_mut() {
+            for cell in gate.queried_cells.iter_mut() {
+                permute(&mut cell.column);
+            }
+        }
+        for column in self.permutation.columns.iter_mut() {
+            permute(column);
+        }
+        self.general_column_annotations = std::mem::take(&mut self.general_column_annotations)
+            .into_iter()
+            .map(|(mut column, annotation)| {
+                if column.column_type == Any::Instance {
+                    column.index = perm[column.index];
+                }
+                (column, annotation)
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Collects the queries again from the expressions of the gates, lookups, shuffles
+    /// and the permutation argument, reassigning the query indices of every expression
+    /// and the queried cells and selectors of every gate.
+    fn reindex_queries(&mut self) {
+        self.advice_queries.clear();
+        self.num_advice_queries = vec![0; self.num_advice_columns];
+        self.instance_queries.clear();
+        self.fixed_queries.clear();
+
+        let mut gates = std::mem::take(&mut self.gates);
+        let mut lookups = std::mem::take(&mut self.lookups);
+        let mut shuffles = std::mem::take(&mut self.shuffles);
+
+        // Each gate records the cells and selectors it queries, as in `create_gate`.
+        for gate in gates.iter_mut() {
+            let mut cells = VirtualCells::new(self);
+            for poly in gate.polys.iter_mut() {
+                poly.clear_query_indices();
+                poly.query_cells(&mut cells);
+            }
+            gate.queried_selectors = cells.queried_selectors;
+            gate.queried_cells = cells.queried_cells;
+        }
+
+        let mut cells = VirtualCells::new(self);
+        for expr in lookups
+            .iter_mut()
+            .flat_map(|lookup| {
+                lookup
+                    .input_expressions
+                    .iter_mut()
+                    .chain(lookup.table_expressions.iter_mut())
+            })
+            .chain(shuffles.iter_mut().flat_map(|shuffle| {
+                shuffle
+                    .input_expressions
+                    .iter_mut()
+                    .chain(shuffle.shuffle_expressions.iter_mut())
+            }))
+        {
+            expr.clear_query_indices();
+            expr.query_cells(&mut cells);
+        }
+
+        // Each column used in a copy constraint involves a query at rotation current.
+        for column in self.permutation.columns.clone() {
+            self.query_any_index(column, Rotation::cur());
+        }
+
+        self.gates = gates;
+        self.lookups = lookups;
+        self.shuffles = shuffles;
+    }
+
+    /// This will compress selectors together depending on their provided
+    /// assignments. This `ConstraintSystem` will then be modified to add new
+    /// fixed columns (representing the actual selectors) and will return the
+    /// polynomials for those columns. Finally, an internal map is updated to
+    /// find which fixed column corresponds with a given `Selector`.
+    ///
+    /// Do not call this twice. Yes, this should be a builder pattern instead.
+    pub fn compress_selectors(mut self, selectors: Vec<Vec<bool>>) -> (Self, Vec<Vec<F>>) {
+        // The number of provided selector assignments must be the number we
+        // counted for this constraint system.
+        assert_eq!(selectors.len(), self.num_selectors);
+
+        // Compute the maximal degree of every selector. We only consider the
+        // expressions in gates, as lookup arguments cannot support simple
+        // selectors. Selectors that are complex or do not appear in any gates
+        // will have degree zero.
+        let mut degrees = vec![0; selectors.len()];
+        for expr in self.gates.iter().flat_map(|gate| gate.polys.iter()) {
+            if let Some(selector) = expr.extract_simple_selector() {
+                degrees[selector.0] = max(degrees[selector.0], expr.degree());
+            }
+        }
+
+        // We will not increase the degree of the constraint system, so we limit
+        // ourselves to the largest existing degree constraint.
+        let max_degree = self.degree();
+
+        let mut new_columns = vec![];
+        let (polys, selector_assignment) = compress_selectors::process(
+            selectors
+                .into_iter()
+                .zip(degrees)
+                .enumerate()
+                .map(
+                    |(i, (activations, max_degree))| compress_selectors::SelectorDescription {
+                        selector: i,
+                        activations,
+                        max_degree,
+                    },
+                )
+                .collect(),
+            max_degree,
+            || {
+                let column = self.fixed_column();
+                new_columns.push(column);
+                Expression::Fixed(FixedQuery {
+                    index: Some(self.query_fixed_index(column, Rotation::cur())),
+                    column_index: column.index,
+                    rotation: Rotation::cur(),
+                })
+            },
+        );
+
+        let mut selector_map = vec![None; selector_assignment.len()];
+        let mut selector_replacements = vec![None; selector_assignment.len()];
+        for assignment in selector_assignment {
+            selector_replacements[assignment.selector] = Some(assignment.expression);
+            selector_map[assignment.selector] = Some(new_columns[assignment.combination_index]);
+        }
+
+        self.selector_map = selector_map
+            .into_iter()
+            .map(|a| a.unwrap())
+            .collect::<Vec<_>>();
+        let selector_replacements = selector_replacements
+            .into_iter()
+            .map(|a| a.unwrap())
+            .collect::<Vec<_>>();
+        self.replace_selectors_with_fixed(&selector_replacements);
+
+        (self, polys)
+    }
+
+    /// Does not combine selectors and directly replaces them everywhere with fixed columns.
+    pub fn directly_convert_selectors_to_fixed(
+        mut self,
+        selectors: Vec<Vec<bool>>,
+    ) -> (Self, Vec<Vec<F>>) {
+        // The number of provided selector assignments must be the number we
+        // counted for this constraint system.
+        assert_eq!(selectors.len(), self.num_selectors);
+
+        let (polys, selector_replacements): (Vec<_>, Vec<_>) = selectors
+            .into_iter()
+            .map(|selector| {
+                let poly = selector
+                    .iter()
+                    .map(|b| if *b { F::ONE } else { F::ZERO })
+                    .collect::<Vec<_>>();
+                let column = self.fixed_column();
+                let rotation = Rotation::cur();
+                let expr = Expression::Fixed(FixedQuery {
+                    index: Some(self.query_fixed_index(column, rotation)),
+                    column_index: column.index,
+                    rotation,
+                });
+                (poly, expr)
+            })
+            .unzip();
+
+        self.replace_selectors_with_fixed(&selector_replacements);
+        self.num_selectors = 0;
+
+        (self, polys)
+    }
+
+    fn replace_selectors_with_fixed(&mut self, selector_replacements: &[Expression<F>]) {
+        fn replace_selectors<F: Field>(
+            expr: &mut Expression<F>,
+            selector_replacements: &[Expression<F>],
+            must_be_nonsimple: bool,
+        ) {
+            *expr = expr.evaluate(
+                &|constant| Expression::Constant(constant),
+                &|selector| {
+                    if must_be_nonsimple {
+                        // Simple selectors are prohibited from appearing in
+                        // expressions in the lookup argument by
+                        // `ConstraintSystem`.
+                        assert!(!selector.is_simple());
+                    }
+
+                    selector_replacements[selector.0].clone()
+                },
+                &|query| Expression::Fixed(query),
+                &|query| Expression::Advice(query),
+                &|query| Expression::Instance(query),
+                &|challenge| Expression::Challenge(challenge),
+                &|a| -a,
+                &|a, b| a + b,
+                &|a, b| a * b,
+                &|a, f| a * f,
+            );
+        }
+
+        // Substitute selectors for the real fixed columns in all gates
+        for expr in self.gates.iter_mut().flat_map(|gate| gate.polys.iter_mut()) {
+            replace_selectors(expr, selector_replacements, false);
+        }
+
+        // Substitute non-simple selectors for the real fixed columns in all
+        // lookup expressions
+        for expr in self.lookups.iter_mut().flat_map(|lookup| {
+            lookup
+                .input_expressions
+                .iter_mut()
+                .chain(lookup.table_expressions.iter_mut())
+        }) {
+            replace_selectors(expr, selector_replacements, true);
+        }
+
+        for expr in self.shuffles.iter_mut().flat_map(|shuffle| {
+            shuffle
+                .input_expressions
+                .iter_mut()
+                .chain(shuffle.shuffle_expressions.iter_mut())
+        }) {
+            replace_selectors(expr, selector_replacements, true);
+        }
+    }
+
+    /// Allocate a new (simple) selector. Simple selectors cannot be added to
+    /// expressions nor multiplied by other expressions containing simple
+    /// selectors. Also, simple selectors may not appear in lookup argument
+    /// inputs.
+    pub fn selector(&mut self) -> Selector {
+        let index = self.num_selectors;
+        self.num_selectors += 1;
+        Selector(index, true)
+    }
+
+    /// Allocate a new complex selector that can appear anywhere
+    /// within expressions.
+    pub fn complex_selector(&mut self) -> Selector {
+        let index = self.num_selectors;
+        self.num_selectors += 1;
+        Selector(index, false)
+    }
+
+    /// Allocates a new fixed column that can be used in a lookup table.
+    pub fn lookup_table_column(&mut self) -> TableColumn {
+        TableColumn {
+            inner: self.fixed_column(),
+        }
+    }
+
+    /// Annotate a Lookup column.
+    pub fn annotate_lookup_column<A, AR>(&mut self, column: TableColumn, annotation: A)
+    where
+        A: Fn() -> AR,
+        AR: Into<String>,
+    {
+        // We don't care if the table has already an annotation. If it's the case we keep the new one.
+        self.general_column_annotations.insert(
+            metadata::Column::from((Any::Fixed, column.inner().index)),
+            annotation().into(),
+        );
+    }
+
+    /// Annotate an Instance column.
+    pub fn annotate_lookup_any_column<A, AR, T>(&mut self, column: T, annotation: A)
+    where
+        A: Fn() -> AR,
+        AR: Into<String>,
+        T: Into<Column<Any>>,
+    {
+        let col_any = column.into();
+        // We don't care if the table has already an annotation. If it's the case we keep the new one.
+        self.general_column_annotations.insert(
+            metadata::Column::from((col_any.column_type, col_any.index)),
+            annotation().into(),
+        );
+    }
+
+    /// Allocate a new fixed column
+    pub fn fixed_column(&mut self) -> Column<Fixed> {
+        let tmp = Column {
+            index: self.num_fixed_columns,
+            column_type: Fixed,
+        };
+        self.num_fixed_columns += 1;
+        tmp
+    }
+
+    /// Allocate a new unblinded advice column at `FirstPhase`
+    pub fn unblinded_advice_column(&mut self) -> Column<Advice> {
+        self.unblinded_advice_column_in(FirstPhase)
+    }
+
+    /// Allocate a new advice column at `FirstPhase`
+    pub fn advice_column(&mut self) -> Column<Advice> {
+        self.advice_column_in(FirstPhase)
+    }
+
+    /// Allocate a new unblinded advice column in given phase. This allows for the generation of deterministic commitments to advice columns
+    /// which can be used to split large circuits into smaller ones, whose proofs can then be "joined" together by their common witness commitments.
+    pub fn unblinded_advice_column_in<P: Phase>(&mut self, phase: P) -> Column<Advice> {
+        let phase = phase.to_sealed();
+        if let Some(previous_phase) = phase.prev() {
+            self.assert_phase_exists(
+                previous_phase,
+                format!("Column<Advice> in later phase {phase:?}").as_str(),
+            );
+        }
+
+        let tmp = Column {
+            index: self.num_advice_columns,
+            column_type: Advice { phase: phase.0 },
+        };
+        self.unblinded_advice_columns.push(tmp.index);
+        self.num_advice_columns += 1;
+        self.num_advice_queries.push(0);
+        self.advice_column_phase.push(phase);
+        tmp
+    }
+
+    /// Allocate a new advice column in given phase
+    ///
+    /// # Panics
+    ///
+    /// It panics if previous phase before the given one doesn't have advice column allocated.
+    pub fn advice_column_in<P: Phase>(&mut self, phase: P) -> Column<Advice> {
+        let phase = phase.to_sealed();
+        if let Some(previous_phase) = phase.prev() {
+            self.assert_phase_exists(
+                previous_phase,
+                format!("Column<Advice> in later phase {phase:?}").as_str(),
+            );
+        }
+
+        let tmp = Column {
+            index: self.num_advice_columns,
+            column_type: Advice { phase: phase.0 },
+        };
+        self.num_advice_columns += 1;
+        self.num_advice_queries.push(0);
+        self.advice_column_phase.push(phase);
+        tmp
+    }
+
+    /// Allocate a new instance column
+    pub fn instance_column(&mut self) -> Column<Instance> {
+        let tmp = Column {
+            index: self.num_instance_columns,
+            column_type: Instance,
+        };
+        self.num_instance_columns += 1;
+        tmp
+    }
+
+    /// Requests a challenge that is usable after the given phase.
+    ///
+    /// # Panics
+    ///
+    /// It panics if the given phase doesn't have advice column allocated.
+    pub fn challenge_usable_after<P: Phase>(&mut self, phase: P) -> Challenge {
+        let phase = phase.to_sealed();
+        self.assert_phase_exists(
+            phase,
+            format!("Challenge usable after phase {phase:?}").as_str(),
+        );
+
+        let tmp = Challenge {
+            index: self.num_challenges,
+            phase: phase.0,
+        };
+        self.num_challenges += 1;
+        self.challenge_phase.push(phase);
+        tmp
+    }
+
+    /// Returns the challenge at `index` together with its phase, or `None` if no such
+    /// challenge has been requested.
+    pub fn challenge(&self, index: usize) -> Option<Challenge> {
+        self.challenge_phase.get(index).map(|phase| Challenge {
+            index,
+            phase: phase.0,
+        })
+    }
+
+    /// Helper funciotn to assert phase exists, to make sure phase-aware resources
+    /// are allocated in order, and to avoid any phase to be skipped accidentally
+    /// to cause unexpected issue in the future.
+    fn assert_phase_exists(&self, phase: sealed::Phase, resource: &str) {
+        self.advice_column_phase
+            .iter()
+            .find(|advice_column_phase| **advice_column_phase == phase)
+            .unwrap_or_else(|| {
+                panic!(
+                    "No Column<Advice> is used in phase {phase:?} while allocating a new {resource:?}"
+                )
+            });
+    }
+
+    /// Returns the list of phases
+    pub fn phases(&self) -> impl Iterator<Item = sealed::Phase> {
+        let max_phase = self
+            .advice_column_phase
+            .iter()
+            .max()
+            .map(|phase| phase.0)
+            .unwrap_or_default();
+        (0..=max_phase).map(sealed::Phase)
+    }
+
+    /// Compute the degree of the constraint system (the maximum degree of all
+    /// constraints).
+    pub fn degree(&self) -> usize {
+        // The permutation argument will serve alongside the gates, so must be
+        // accounted for.
+        let mut degree = self.permutation.required_degree();
+
+        // The lookup argument also serves alongside the gates and must be accounted
+        // for.
+        degree = std::cmp::max(
+            degree,
+            self.lookups
+                .iter()
+                .map(|l| l.required_degree())
+                .max()
+                .unwrap_or(1),
+        );
+
+        // The lookup argument also serves alongside the gates and must be accounted
+        // for.
+        degree = std::cmp::max(
+            degree,
+            self.shuffles
+                .iter()
+                .map(|l| l.required_degree())
+                .max()
+                .unwrap_or(1),
+        );
+
+        // Account for each gate to ensure our quotient polynomial is the
+        // correct degree and that our extended domain is the right size.
+        degree = std::cmp::max(
+            degree,
+            self.gates
+                .iter()
+                .flat_map(|gate| gate.polynomials().iter().map(|poly| poly.degree()))
+                .max()
+                .unwrap_or(0),
+        );
+
+        std::cmp::max(degree, self.minimum_degree.unwrap_or(1))
+    }
+
+    /// Returns the log2 ratio between the size of the extended evaluation domain and the
+    /// size of the base domain. The extended domain must be large enough to describe
+    /// the quotient polynomial, whose degree is `(self.degree() - 1) * n - 1`.
+    pub fn extended_k_shift(&self) -> u32 {
+        let quotient_poly_degree = (self.degree() - 1) as u64;
+        let mut shift = 0;
+        while (1u64 << shift) < quotient_poly_degree {
+            shift += 1;
+        }
+        shift
+    }
+
+    /// Returns the log2 size of the extended evaluation domain for a circuit with `2^k`
+    /// rows.
+    pub fn extended_domain_k(&self, k: u32) -> u32 {
+        k + self.extended_k_shift()
+    }
+
+    /// Returns the largest number of distinct queries made to any single advice
+    /// column, or 1 if there are no advice columns. This is the starting point
+    /// of [`ConstraintSystem::blinding_factors`].
+    pub fn max_advice_query_count(&self) -> usize {
+        *self.num_advice_queries.iter().max().unwrap_or(&1)
+    }
+
+    /// Compute the number of blinding factors necessary to perfectly blind
+    /// each of the prover's witness polynomials.
+    pub fn blinding_factors(&self) -> usize {
+        // All of the prover's advice columns are evaluated at no more than
+        let factors = self.max_advice_query_count();
+        // distinct points during gate checks.
+
+        // - The permutation argument witness polynomials are evaluated at most 3 times.
+        // - Each lookup argument has independent witness polynomials, and they are
+        //   evaluated at most 2 times.
+        let factors = std::cmp::max(3, factors);
+
+        // Each polynomial is evaluated at most an additional time during
+        // multiopen (at x_3 to produce q_evals):
+        let factors = factors + 1;
+
+        // h(x) is derived by the other evaluations so it does not reveal
+        // anything; in fact it does not even appear in the proof.
+
+        // h(x_3) is also not revealed; the verifier only learns a single
+        // evaluation of a polynomial in x_1 which has h(x_3) and another random
+        // polynomial evaluated at x_3 as coefficients -- this random polynomial
+        // is "random_poly" in the vanishing argument.
+
+        // Add an additional blinding factor as a slight defense against
+        // off-by-one errors.
+        factors + 1
+    }
+
+    /// Returns the name of every constraint across all gates, in order. Names are
+    /// formatted as `gate_name:constraint_name`, or just `gate_name` for unnamed
+    /// constraints.
+    pub fn constraint_names(&self) -> Vec<String> {
+        self.gates
+            .iter()
+            .flat_map(|gate| {
+                (0..gate.polys.len()).map(move |i| {
+                    match gate.constraint_names.get(i).map(String::as_str) {
+                        None | Some("") => gate.name.clone(),
+                        Some(constraint_name) => format!("{}:{constraint_name}", gate.name),
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the `(gate_index, constraint_index, degree)` triples of the constraints
+    /// whose degree exceeds `bound`.
+    pub fn gates_exceeding(&self, bound: usize) -> Vec<(usize, usize, usize)> {
+        self.gates
+            .iter()
+            .enumerate()
+            .flat_map(|(gate_index, gate)| {
+                gate.polys
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, poly)| (gate_index, i, poly.degree()))
+            })
+            .filter(|(_, _, degree)| *degree > bound)
+            .collect()
+    }
+
+    /// Returns the indices of the gates that query no selector and are not activated by
+    /// a fixed column as found by [`ConstraintSystem::gate_selectors`], and so are
+    /// enforced on every row, excluding the gates created with
+    /// [`ConstraintSystem::add_global_gate`]. Such a gate usually lacks its selector by
+    /// mistake.
+    pub fn ungated_gates(&self) -> Vec<usize> {
+        self.gates
+            .iter()
+            .zip(self.gate_selectors())
+            .enumerate()
+            .filter(|(_, (gate, selector))| {
+                !gate.global && gate.queried_selectors.is_empty() && selector.is_none()
+            })
+            .map(|(gate_index, _)| gate_index)
+            .collect()
+    }
+
+    /// Returns the `(gate_index, complexity)` pairs of every gate, where the complexity
+    /// is the sum of [`Expression::complexity`] over the gate's polynomials, sorted from
+    /// most to least expensive. Gates of equal complexity stay in gate order.
+    pub fn gates_by_cost(&self) -> Vec<(usize, usize)> {
+        let mut costs: Vec<_> = self
+            .gates
+            .iter()
+            .enumerate()
+            .map(|(gate_index, gate)| {
+                (
+                    gate_index,
+                    gate.polys
+                        .iter()
+                        .map(|poly| poly.complexity())
+                        .sum::<usize>(),
+                )
+            })
+            .collect();
+        costs.sort_by(|(_, a), (_, b)| b.cmp(a));
+        costs
+    }
+
+    /// Returns the length of the longest name returned by
+    /// [`ConstraintSystem::constraint_names`], or zero if there are no constraints.
+    pub fn max_constraint_name_len(&self) -> usize {
+        self.constraint_names()
+            .iter()
+            .map(|name| name.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns the `(gate_index, constraint_index)` pairs of the constraints that were
+    /// given no name of their own.
+    pub fn unnamed_constraints(&self) -> Vec<(usize, usize)> {
+        self.gates
+            .iter()
+            .enumerate()
+            .flat_map(|(gate_index, gate)| {
+                (0..gate.polys.len())
+                    .filter(move |i| {
+                        gate.constraint_names
+                            .get(*i)
+                            .map_or(true, |name| name.is_empty())
+                    })
+                    .map(move |i| (gate_index, i))
+            })
+            .collect()
+    }
+
+    /// Returns, for every gate, the fixed column that activates it. This is the column
+    /// queried at the current row as the left factor `s` of every polynomial `s * rest` of
+    /// the gate. Gates without such a common factor map to `None`.
+    pub fn gate_selectors(&self) -> Vec<Option<Column<Fixed>>> {
+        fn selector<F: Field>(poly: &Expression<F>) -> Option<Column<Fixed>> {
+            match poly {
+                Expression::Product(a, _) => match a.as_ref() {
+                    Expression::Fixed(query) if query.rotation == Rotation::cur() => {
+                        Some(Column::new(query.column_index, Fixed))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+
+        self.gates
+            .iter()
+            .map(|gate| -> Option<Column<Fixed>> {
+                let (first, rest) = gate.polys.split_first()?;
+                let column = selector(first)?;
+                rest.iter()
+                    .all(|poly| selector(poly) == Some(column))
+                    .then_some(column)
+            })
+            .collect()
+    }
+
+    /// Returns the indices of the fixed columns that activate some gate, as found by
+    /// [`ConstraintSystem::gate_selectors`]. Fixed columns only used as lookup tables or
+    /// to hold constants are not included.
+    pub fn selector_fixed_columns(&self) -> BTreeSet<usize> {
+        self.gate_selectors()
+            .into_iter()
+            .flatten()
+            .map(|column| column.index())
+            .collect()
+    }
+
+    /// Returns the `(gate_index, challenge_index, advice_column_index)` triples for which a
+    /// gate multiplies a challenge by a query to an advice column of a later phase than
+    /// the challenge.
+    pub fn challenge_phase_violations(&self) -> Vec<(usize, usize, usize)> {
+        fn leaves<F: Field>(
+            expr: &Expression<F>,
+            challenges: &mut Vec<Challenge>,
+            advice: &mut Vec<AdviceQuery>,
+        ) {
+            match expr {
+                Expression::Challenge(challenge) => challenges.push(*challenge),
+                Expression::Advice(query) => advice.push(*query),
+                Expression::Negated(a) | Expression::Scaled(a, _) => leaves(a, challenges, advice),
+                Expression::Sum(a, b) | Expression::Product(a, b) => {
+                    leaves(a, challenges, advice);
+                    leaves(b, challenges, advice);
+                }
+                _ => (),
+            }
+        }
+
+        fn visit<F: Field>(expr: &Expression<F>, violations: &mut BTreeSet<(usize, usize)>) {
+            match expr {
+                Expression::Product(a, b) => {
+                    for (lhs, rhs) in [(a, b), (b, a)] {
+                        let (mut challenges, mut advice) = (vec![], vec![]);
+                        leaves(lhs, &mut challenges, &mut vec![]);
+                        leaves(rhs, &mut vec![], &mut advice);
+                        for challenge in challenges.iter() {
+                            for query in advice.iter().filter(|q| q.phase() > challenge.phase()) {
+                                violations.insert((challenge.index(), query.column_index()));
+                            }
+                        }
+                    }
+                    visit(a, violations);
+                    visit(b, violations);
+                }
+                Expression::Negated(a) | Expression::Scaled(a, _) => visit(a, violations),
+                Expression::Sum(a, b) => {
+                    visit(a, violations);
+                    visit(b, violations);
+                }
+                _ => (),
+            }
+        }
+
+        self.gates
+            .iter()
+            .enumerate()
+            .flat_map(|(gate_index, gate)| {
+                let mut violations = BTreeSet::new();
+                for poly in gate.polys.iter() {
+                    visit(poly, &mut violations);
+                }
+                violations
+                    .into_iter()
+                    .map(move |(challenge, column)| (gate_index, challenge, column))
+            })
+            .collect()
+    }
+
+    /// Runs every available consistency check on this constraint system and collects a
+    /// message for each failure, rather than stopping at the first one. The checks cover
+    /// queried and permuted columns being allocated, and lookup and shuffle arguments
+    /// having matching input and table lengths. Challenges multiplying advice columns of
+    /// later phases, as reported by [`ConstraintSystem::challenge_phase_violations`], are
+    /// not errors: multi-phase circuits do this by design.
+    pub fn assert_well_formed(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        let num_columns = |column_type: &Any| match column_type {
+            Any::Advice(_) => self.num_advice_columns,
+            Any::Fixed => self.num_fixed_columns,
+            Any::Instance => self.num_instance_columns,
+        };
+        let queried = self
+            .advice_queries
+            .iter()
+            .map(|(column, at)| (Column::<Any>::from(*column), *at))
+            .chain(self.fixed_queries.iter().map(|(c, at)| ((*c).into(), *at)))
+            .chain(
+                self.instance_queries
+                    .iter()
+                    .map(|(c, at)| ((*c).into(), *at)),
+            );
+        for (column, at) in queried {
+            if column.index() >= num_columns(column.column_type()) {
+                errors.push(format!(
+                    "query of unallocated column {:?} at rotation {}",
+                    column, at.0
+                ));
+            }
+        }
+        for column in self.permutation.get_columns() {
+            if column.index() >= num_columns(column.column_type()) {
+                errors.push(format!("permutation over unallocated column {:?}", column));
+            }
+        }
+
+        for lookup in self.lookups.iter() {
+            if lookup.input_expressions.len() != lookup.table_expressions.len() {
+                errors.push(format!(
+                    "lookup \"{}\" has {} input expressions but {} table expressions",
+                    lookup.name,
+                    lookup.input_expressions.len(),
+                    lookup.table_expressions.len()
+                ));
+            }
+        }
+        for shuffle in self.shuffles.iter() {
+            if shuffle.input_expressions.len() != shuffle.shuffle_expressions.len() {
+                errors.push(format!(
+                    "shuffle \"{}\" has {} input expressions but {} shuffle expressions",
+                    shuffle.name,
+                    shuffle.input_expressions.len(),
+                    shuffle.shuffle_expressions.len()
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Returns the indices of the lookup arguments whose input or table expressions
+    /// query `column`.
+    pub fn lookups_referencing_column(&self, column: Column<Any>) -> Vec<usize> {
+        self.lookups
+            .iter()
+            .enumerate()
+            .filter(|(_, lookup)| {
+                lookup
+                    .input_expressions
+                    .iter()
+                    .chain(lookup.table_expressions.iter())
+                    .any(|expr| expr.queries_column(column))
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Returns whether `column` is queried by the input expressions of any lookup
+    /// argument, by the table expressions, or both.
+    pub fn lookup_role(&self, column: Column<Any>) -> LookupRole {
+        let queried_by =
+            |exprs: &[Expression<F>]| exprs.iter().any(|expr| expr.queries_column(column));
+        self.lookups
+            .iter()
+            .fold(LookupRole::NONE, |mut role, lookup| {
+                if queried_by(&lookup.input_expressions) {
+                    role = role | LookupRole::INPUT;
+                }
+                if queried_by(&lookup.table_expressions) {
+                    role = role | LookupRole::TABLE;
+                }
+                role
+            })
+    }
+
+    /// Returns the indices of the shuffle arguments whose input or shuffle expressions
+    /// query `column`.
+    pub fn shuffles_referencing_column(&self, column: Column<Any>) -> Vec<usize> {
+        self.shuffles
+            .iter()
+            .enumerate()
+            .filter(|(_, shuffle)| {
+                shuffle
+                    .input_expressions
+                    .iter()
+                    .chain(shuffle.shuffle_expressions.iter())
+                    .any(|expr| expr.queries_column(column))
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Returns whether this constraint system and `other` describe the same circuit
+    /// structure, i.e. everything that affects the verifying key: column and challenge
+    /// counts, phases, unblinded advice columns, the permuted columns in order, the
+    /// minimum degree, the sets of advice, instance and fixed queries, and the
+    /// identifiers of the gate, lookup and shuffle expressions. Names, annotations and the
+    /// order of the queries are ignored.
+    pub fn is_compatible_with(&self, other: &Self) -> bool {
+        fn identifiers<F: Field>(exprs: &[Expression<F>]) -> Vec<String> {
+            exprs.iter().map(|expr| expr.identifier()).collect()
+        }
+        // Queries are compared as sets: the order in which they were registered does not
+        // survive a round-trip through the backend constraint system.
+        fn sorted<T: Clone + Ord>(queries: &[T]) -> Vec<T> {
+            let mut queries = queries.to_vec();
+            queries.sort();
+            queries
+        }
+        fn structure<F: Field>(cs: &ConstraintSystem<F>) -> impl PartialEq {
+            let mut unblinded = cs.unblinded_advice_columns.clone();
+            unblinded.sort();
+            (
+                [
+                    cs.num_fixed_columns,
+                    cs.num_advice_columns,
+                    cs.num_instance_columns,
+                    cs.num_selectors,
+                    cs.num_challenges,
+                ],
+                (cs.advice_column_phase.clone(), cs.challenge_phase.clone()),
+                (unblinded, cs.permutation.columns.clone(), cs.minimum_degree),
+                (
+                    sorted(&cs.advice_queries),
+                    sorted(&cs.instance_queries),
+                    sorted(&cs.fixed_queries),
+                ),
+                cs.gates
+                    .iter()
+                    .map(|gate| identifiers(&gate.polys))
+                    .collect::<Vec<_>>(),
+                cs.lookups
+                    .iter()
+                    .map(|lookup| {
+                        (
+                            identifiers(&lookup.input_expressions),
+                            identifiers(&lookup.table_expressions),
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+                cs.shuffles
+                    .iter()
+                    .map(|shuffle| {
+                        (
+                            identifiers(&shuffle.input_expressions),
+                            identifiers(&shuffle.shuffle_expressions),
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        }
+
+        structure(self) == structure(other)
+    }
+
+    /// Groups the indices of lookup arguments whose table expressions have the same
+    /// identifiers. Groups are ordered by their first lookup.
+    pub fn lookups_with_shared_tables(&self) -> Vec<Vec<usize>> {
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        let mut group_by_table: HashMap<Vec<String>, usize> = HashMap::new();
+        for (index, lookup) in self.lookups.iter().enumerate() {
+            let table = lookup
+                .table_expressions
+                .iter()
+                .map(|expr| expr.identifier())
+                .collect();
+            let group = *group_by_table.entry(table).or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+            groups[group].push(index);
+        }
+        groups
+    }
+
+    /// Returns the total number of field multiplications needed to evaluate every gate
+    /// polynomial once, i.e. the per-row multiplication cost of the custom gates.
+    pub fn total_multiplications(&self) -> usize {
+        self.gates
+            .iter()
+            .flat_map(|gate| gate.polynomials())
+            .map(|poly| poly.op_counts().multiplications)
+            .sum()
+    }
+
+    /// Returns the range of indices in `advice_queries` occupied by the queries to the
+    /// advice column at `column_index`. Returns `None` if the column is not queried, or
+    /// if its queries are interleaved with those of other columns.
+    pub fn advice_query_index_range(&self, column_index: usize) -> Option<Range<usize>> {
+        let mut indices = self
+            .advice_queries
+            .iter()
+            .enumerate()
+            .filter(|(_, (column, _))| column.index() == column_index)
+            .map(|(index, _)| index);
+        let start = indices.next()?;
+        let mut end = start + 1;
+        for index in indices {
+            if index != end {
+                return None;
+            }
+            end += 1;
+        }
+        Some(start..end)
+    }
+
+    /// Returns the maximum number of distinct rotations at which any single column
+    /// (advice, fixed or instance) is queried.
+    pub fn max_queries_per_column(&self) -> usize {
+        let columns = self
+            .advice_queries
+            .iter()
+            .map(|(column, _)| Column::<Any>::from(*column))
+            .chain(
+                self.fixed_queries
+                    .iter()
+                    .map(|(column, _)| (*column).into()),
+            )
+            .chain(
+                self.instance_queries
+                    .iter()
+                    .map(|(column, _)| (*column).into()),
+            );
+
+        // Query vectors never contain duplicates, so every entry is a distinct rotation.
+        let mut num_queries: HashMap<Column<Any>, usize> = HashMap::new();
+        for column in columns {
+            *num_queries.entry(column).or_default() += 1;
+        }
+        num_queries.into_values().max().unwrap_or(0)
+    }
+
+    /// Returns the minimum necessary rows that need to exist in order to
+    /// account for e.g. blinding factors.
+    pub fn minimum_rows(&self) -> usize {
+        self.blinding_factors() // m blinding factors
+            + 1 // for l_{-(m + 1)} (l_last)
+            + 1 // for l_0 (just for extra breathing room for the permutation
+                // argument, to essentially force a separation in the
+                // permutation polynomial between the roles of l_last, l_0
+                // and the interstitial values.)
+            + 1 // for at least one row
+    }
+
+    /// Returns the smallest `k` such that a circuit with `2^k` rows fits `used_rows`
+    /// assigned rows, i.e. `2^k >= used_rows + minimum_rows()`, and is at least as large
+    /// as the degree of the constraint system, i.e. `2^k >= degree()`, so that the
+    /// quotient polynomial can be evaluated.
+    pub fn min_k(&self, used_rows: usize) -> u32 {
+        let rows = max(used_rows + self.minimum_rows(), self.degree()) as u64;
+        let mut k = 0;
+        while (1u64 << k) < rows {
+            k += 1;
+        }
+        k
+    }
+
+    /// Returns the number of lookup constraints, i.e. the total number of input
+    /// expressions over all lookup arguments.
+    pub fn num_lookup_constraints(&self) -> usize {
+        self.lookups
+            .iter()
+            .map(|lookup| lookup.input_expressions.len())
+            .sum()
+    }
+
+    /// Returns the number of shuffle constraints, i.e. the total number of input
+    /// expressions over all shuffle arguments.
+    pub fn num_shuffle_constraints(&self) -> usize {
+        self.shuffles
+            .iter()
+            .map(|shuffle| shuffle.input_expressions.len())
+            .sum()
+    }
+
+    /// Returns the number of permutation product polynomials `z` needed by the
+    /// permutation argument at the degree of this constraint system.
+    pub fn num_permutation_z_polys(&self) -> usize {
+        self.permutation.chunk_count(self.degree())
+    }
+
+    /// Returns number of fixed columns
+    pub fn num_fixed_columns(&self) -> usize {
+        self.num_fixed_columns
+    }
+
+    /// Returns number of advice columns
+    pub fn num_advice_columns(&self) -> usize {
+        self.num_advice_columns
+    }
+
+    /// Returns number of instance columns
+    pub fn num_instance_columns(&self) -> usize {
+        self.num_instance_columns
+    }
+
+    /// Returns number of selectors
+    pub fn num_selectors(&self) -> usize {
+        self.num_selectors
+    }
+
+    /// Returns number of challenges
+    pub fn num_challenges(&self) -> usize {
+        self.num_challenges
+    }
+
+    /// Returns phase of advice columns
+    pub fn advice_column_phase(&self) -> Vec<u8> {
+        self.advice_column_phase
+            .iter()
+            .map(|phase| phase.0)
+            .collect()
+    }
+
+    /// Returns the number of advice columns in each phase, indexed by phase
+    pub fn num_advice_columns_per_phase(&self) -> Vec<usize> {
+        self.phases()
+            .map(|phase| {
+                self.advice_column_phase
+                    .iter()
+                    .filter(|column_phase| **column_phase == phase)
+                    .count()
+            })
+            .collect()
+    }
+
+    /// Returns the order in which the prover interacts with the transcript: for each
+    /// phase, the advice columns committed in that phase followed by the challenges
+    /// squeezed after it.
+    pub fn commit_schedule(&self) -> Vec<PhaseStep> {
+        let indices_in = |phases: &[sealed::Phase], phase: sealed::Phase| -> Vec<usize> {
+            phases
                 .iter()
-                .map(|e| queries.as_expression(e))
-                .collect(),
+                .enumerate()
+                .filter(|(_, p)| **p == phase)
+                .map(|(index, _)| index)
+                .collect()
+        };
+        self.phases()
+            .map(|phase| PhaseStep {
+                phase: phase.0,
+                advice_columns: indices_in(&self.advice_column_phase, phase),
+                challenges: indices_in(&self.challenge_phase, phase),
+            })
+            .collect()
+    }
+
+    /// Returns phase of challenges
+    pub fn challenge_phase(&self) -> Vec<u8> {
+        self.challenge_phase.iter().map(|phase| phase.0).collect()
+    }
+
+    /// Returns gates
+    pub fn gates(&self) -> &Vec<Gate<F>> {
+        &self.gates
+    }
+
+    /// Returns general column annotations
+    pub fn general_column_annotations(&self) -> &HashMap<metadata::Column, String> {
+        &self.general_column_annotations
+    }
+
+    /// Returns advice queries
+    pub fn advice_queries(&self) -> &Vec<(Column<Advice>, Rotation)> {
+        &self.advice_queries
+    }
+
+    /// Returns instance queries
+    pub fn instance_queries(&self) -> &Vec<(Column<Instance>, Rotation)> {
+        &self.instance_queries
+    }
+
+    /// Returns fixed queries
+    pub fn fixed_queries(&self) -> &Vec<(Column<Fixed>, Rotation)> {
+        &self.fixed_queries
+    }
+
+    /// Returns the set of distinct rotations at which columns of the given type are
+    /// queried. The phase is ignored when `Any::Advice` is passed.
+    pub fn rotation_set(&self, column_type: Any) -> BTreeSet<i32> {
+        match column_type {
+            Any::Advice(_) => self.advice_queries.iter().map(|(_, at)| at.0).collect(),
+            Any::Fixed => self.fixed_queries.iter().map(|(_, at)| at.0).collect(),
+            Any::Instance => self.instance_queries.iter().map(|(_, at)| at.0).collect(),
+        }
+    }
+
+    /// Returns the set of distinct rotations at which any advice, fixed or instance
+    /// column is queried, the union of [`ConstraintSystem::rotation_set`] over all
+    /// column types.
+    pub fn all_query_rotations(&self) -> BTreeSet<i32> {
+        [Any::advice(), Any::Fixed, Any::Instance]
+            .into_iter()
+            .flat_map(|column_type| self.rotation_set(column_type))
+            .collect()
+    }
+
+    /// Returns the number of instance values the prover must supply for the instance
+    /// column at `column_index`, given `usable_rows` rows of the circuit. Queries at
+    /// negative or positive rotations extend the span `[min_rot, usable_rows + max_rot)`,
+    /// where the current row is always included.
+    pub fn instance_len(&self, column_index: usize, usable_rows: usize) -> usize {
+        let (min_rot, max_rot) = self
+            .instance_queries
+            .iter()
+            .filter(|(column, _)| column.index() == column_index)
+            .fold((0, 0), |(min_rot, max_rot), (_, at)| {
+                (min(min_rot, at.0), max(max_rot, at.0))
+            });
+        usable_rows + (max_rot - min_rot) as usize
+    }
+
+    /// Returns permutation argument
+    pub fn permutation(&self) -> &permutation::Argument {
+        &self.permutation
+    }
+
+    /// Returns lookup arguments
+    pub fn lookups(&self) -> &Vec<lookup::Argument<F>> {
+        &self.lookups
+    }
+
+    /// Returns shuffle arguments
+    pub fn shuffles(&self) -> &Vec<shuffle::Argument<F>> {
+        &self.shuffles
+    }
+
+    /// Returns constants
+    pub fn constants(&self) -> &Vec<Column<Fixed>> {
+        &self.constants
+    }
+}
+
+impl<F: SerdePrimeField> ConstraintSystem<F> {
+    /// Checks that every `Constant` and `Scaled` field element of the gate, lookup and
+    /// shuffle expressions is in canonical form, i.e. that its internal representation
+    /// is smaller than the modulus. Field elements read with
+    /// [`SerdeFormat::RawBytesUnchecked`] may not be. Returns a description of the
+    /// location of the first non-canonical element found.
+    pub fn validate_constants(&self) -> Result<(), String> {
+        let is_canonical = |scalar: &F| {
+            let mut bytes = Vec::new();
+            scalar.write_raw(&mut bytes).is_ok() && F::read_raw(&mut bytes.as_slice()).is_ok()
+        };
+        let check = |exprs: &[Expression<F>], location: &dyn Fn(usize) -> String| {
+            for (index, expr) in exprs.iter().enumerate() {
+                let scalars = expr.evaluate(
+                    &|scalar| vec![scalar],
+                    &|_| vec![],
+                    &|_| vec![],
+                    &|_| vec![],
+                    &|_| vec![],
+                    &|_| vec![],
+                    &|a| a,
+                    &|mut a, b| {
+                        a.extend(b);
+                        a
+                    },
+                    &|mut a, b| {
+                        a.extend(b);
+                        a
+                    },
+                    &|mut a, scalar| {
+                        a.push(scalar);
+                        a
+                    },
+                );
+                if let Some(scalar) = scalars.iter().find(|scalar| !is_canonical(scalar)) {
+                    return Err(format!(
+                        "non-canonical field element {scalar:?} in {}",
+                        location(index)
+                    ));
+                }
+            }
+            Ok(())
+        };
+
+        for gate in self.gates.iter() {
+            check(&gate.polys, &|index| {
+                let constraint_name = gate
+                    .constraint_names
+                    .get(index)
+                    .map_or(gate.name(), String::as_str);
+                format!(
+                    "gate \"{}\" constraint {index} (\"{constraint_name}\")",
+                    gate.name(),
+                )
+            })?;
+        }
+        for lookup in self.lookups.iter() {
+            check(&lookup.input_expressions, &|index| {
+                format!("lookup \"{}\" input expression {index}", lookup.name)
+            })?;
+            check(&lookup.table_expressions, &|index| {
+                format!("lookup \"{}\" table expression {index}", lookup.name)
+            })?;
+        }
+        for shuffle in self.shuffles.iter() {
+            check(&shuffle.input_expressions, &|index| {
+                format!("shuffle \"{}\" input expression {index}", shuffle.name)
+            })?;
+            check(&shuffle.shuffle_expressions, &|index| {
+                format!("shuffle \"{}\" shuffle expression {index}", shuffle.name)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds a [`ConstraintSystem`] out of gates given directly as expressions. The queries
+/// of every gate are registered as the gate is added, so all the query indices of the
+/// built constraint system are consistent.
+#[derive(Debug, Clone, Default)]
+pub struct ConstraintSystemBuilder<F: Field> {
+    cs: ConstraintSystem<F>,
+}
+
+impl<F: Field> ConstraintSystemBuilder<F> {
+    /// Starts building on top of `cs`, which is expected to have its columns allocated.
+    pub fn new(cs: ConstraintSystem<F>) -> Self {
+        ConstraintSystemBuilder { cs }
+    }
+
+    /// Adds a gate with the constraints `polys`, registering the queries they use.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `polys` is empty.
+    pub fn add_gate<S: AsRef<str>>(
+        &mut self,
+        name: S,
+        polys: impl IntoIterator<Item = Expression<F>>,
+    ) -> &mut Self {
+        self.cs.create_gate(name, |_| polys);
+        self
+    }
+
+    /// Returns the built constraint system.
+    pub fn build(self) -> ConstraintSystem<F> {
+        self.cs
+    }
+}
+
+/// Degree and size of the gate polynomials of a [`ConstraintSystem`] before and after a
+/// transformation, as returned by [`ConstraintSystem::apply_to_gates`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransformReport {
+    /// Maximum degree of the gate polynomials before the transformation
+    pub degree_before: usize,
+    /// Maximum degree of the gate polynomials after the transformation
+    pub degree_after: usize,
+    /// Total number of nodes of the gate polynomials before the transformation
+    pub nodes_before: usize,
+    /// Total number of nodes of the gate polynomials after the transformation
+    pub nodes_after: usize,
+}
+
+/// One phase of the prover's transcript interaction, as returned by
+/// [`ConstraintSystem::commit_schedule`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PhaseStep {
+    /// The phase
+    pub phase: u8,
+    /// Indices of the advice columns committed in this phase
+    pub advice_columns: Vec<usize>,
+    /// Indices of the challenges squeezed after this phase
+    pub challenges: Vec<usize>,
+}
+
+/// Exposes the "virtual cells" that can be queried while creating a custom gate or lookup
+/// table.
+#[derive(Debug)]
+pub struct VirtualCells<'a, F: Field> {
+    meta: &'a mut ConstraintSystem<F>,
+    queried_selectors: Vec<Selector>,
+    queried_cells: Vec<VirtualCell>,
+}
+
+impl<'a, F: Field> VirtualCells<'a, F> {
+    fn new(meta: &'a mut ConstraintSystem<F>) -> Self {
+        VirtualCells {
+            meta,
+            queried_selectors: vec![],
+            queried_cells: vec![],
+        }
+    }
+
+    /// Query a selector at the current position.
+    pub fn query_selector(&mut self, selector: Selector) -> Expression<F> {
+        self.queried_selectors.push(selector);
+        Expression::Selector(selector)
+    }
+
+    /// Query a fixed column at a relative position
+    pub fn query_fixed(&mut self, column: Column<Fixed>, at: Rotation) -> Expression<F> {
+        self.queried_cells.push((column, at).into());
+        Expression::Fixed(FixedQuery {
+            index: Some(self.meta.query_fixed_index(column, at)),
+            column_index: column.index,
+            rotation: at,
         })
-        .collect()
+    }
+
+    /// Query an advice column at a relative position
+    pub fn query_advice(&mut self, column: Column<Advice>, at: Rotation) -> Expression<F> {
+        self.queried_cells.push((column, at).into());
+        Expression::Advice(AdviceQuery {
+            index: Some(self.meta.query_advice_index(column, at)),
+            column_index: column.index,
+            rotation: at,
+            phase: sealed::Phase(column.column_type().phase),
+        })
+    }
+
+    /// Query an instance column at a relative position
+    pub fn query_instance(&mut self, column: Column<Instance>, at: Rotation) -> Expression<F> {
+        self.queried_cells.push((column, at).into());
+        Expression::Instance(InstanceQuery {
+            index: Some(self.meta.query_instance_index(column, at)),
+            column_index: column.index,
+            rotation: at,
+        })
+    }
+
+    /// Query an Any column at a relative position
+    pub fn query_any<C: Into<Column<Any>>>(&mut self, column: C, at: Rotation) -> Expression<F> {
+        let column = column.into();
+        match column.column_type() {
+            Any::Advice(_) => self.query_advice(Column::<Advice>::try_from(column).unwrap(), at),
+            Any::Fixed => self.query_fixed(Column::<Fixed>::try_from(column).unwrap(), at),
+            Any::Instance => self.query_instance(Column::<Instance>::try_from(column).unwrap(), at),
+        }
+    }
+
+    /// Query a challenge
+    pub fn query_challenge(&mut self, challenge: Challenge) -> Expression<F> {
+        Expression::Challenge(challenge)
+    }
 }
 
-/// Collect all queries used in the expressions of gates, lookups and shuffles.  Map the
-/// expressions of gates, lookups and shuffles into equivalent ones with indexed query
-/// references.
-#[allow(clippy::type_complexity)]
-pub fn collect_queries<F: Field>(
-    cs2: &ConstraintSystemV2Backend<F>,
-) -> (
-    Queries,
-    Vec<Gate<F>>,
-    Vec<lookup::Argument<F>>,
-    Vec<shuffle::Argument<F>>,
-) {
-    let mut queries = QueriesMap {
-        advice_map: HashMap::new(),
-        instance_map: HashMap::new(),
-        fixed_map: HashMap::new(),
-        advice: Vec::new(),
-        instance: Vec::new(),
-        fixed: Vec::new(),
+#[cfg(test)]
+mod tests {
+    use super::{
+        ArithGate, ArithOp, Challenge, Column, ConstraintSystem, ConstraintSystemBuilder,
+        DegreeStep, Expression, ExpressionKind, FirstPhase, LookupRole, OpCounts, PhaseStep,
+        RewriteRule, SecondPhase, ThirdPhase,
     };
+    use halo2_middleware::circuit::{Advice, Any, ConstraintSystemV2Backend, Fixed, Instance};
+    use halo2_middleware::ff::Field;
+    use halo2_middleware::metadata;
+    use halo2_middleware::poly::Rotation;
+    use halo2curves::bn256::Fr;
+    use proptest::prelude::*;
+    use std::collections::{BTreeSet, HashMap, HashSet};
 
-    let gates = cs2_collect_queries_gates(cs2, &mut queries);
-    let lookups = cs2_collect_queries_lookups(cs2, &mut queries);
-    let shuffles = cs2_collect_queries_shuffles(cs2, &mut queries);
+    #[test]
+    fn iter_sum() {
+        let exprs: Vec<Expression<Fr>> = vec![
+            Expression::Constant(1.into()),
+            Expression::Constant(2.into()),
+            Expression::Constant(3.into()),
+        ];
+        let happened: Expression<Fr> = exprs.into_iter().sum();
+        let expected: Expression<Fr> = Expression::Sum(
+            Box::new(Expression::Sum(
+                Box::new(Expression::Constant(1.into())),
+                Box::new(Expression::Constant(2.into())),
+            )),
+            Box::new(Expression::Constant(3.into())),
+        );
 
-    // Each column used in a copy constraint involves a query at rotation current.
-    for column in &cs2.permutation.columns {
-        match column.column_type {
-            Any::Instance => {
-                queries.add_instance(Column::new(column.index, Instance), Rotation::cur())
-            }
-            Any::Fixed => queries.add_fixed(Column::new(column.index, Fixed), Rotation::cur()),
-            Any::Advice(advice) => {
-                queries.add_advice(Column::new(column.index, advice), Rotation::cur())
-            }
+        assert_eq!(happened, expected);
+    }
+
+    #[test]
+    fn iter_product() {
+        let exprs: Vec<Expression<Fr>> = vec![
+            Expression::Constant(1.into()),
+            Expression::Constant(2.into()),
+            Expression::Constant(3.into()),
+        ];
+        let happened: Expression<Fr> = exprs.into_iter().product();
+        let expected: Expression<Fr> = Expression::Product(
+            Box::new(Expression::Product(
+                Box::new(Expression::Constant(1.into())),
+                Box::new(Expression::Constant(2.into())),
+            )),
+            Box::new(Expression::Constant(3.into())),
+        );
+
+        assert_eq!(happened, expected);
+    }
+
+    #[test]
+    fn rotation_set() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let f = meta.fixed_column();
+        meta.create_gate("gate", |meta| {
+            let a_prev = meta.query_advice(a, Rotation::prev());
+            let a_cur = meta.query_advice(a, Rotation::cur());
+            let f_cur = meta.query_fixed(f, Rotation::cur());
+            vec![f_cur * (a_cur - a_prev)]
+        });
+
+        assert_eq!(meta.rotation_set(Any::advice()), BTreeSet::from([-1, 0]));
+        assert_eq!(meta.rotation_set(Any::Fixed), BTreeSet::from([0]));
+        assert_eq!(meta.rotation_set(Any::Instance), BTreeSet::new());
+    }
+
+    #[test]
+    fn all_query_rotations() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        assert_eq!(meta.all_query_rotations(), BTreeSet::new());
+
+        let a = meta.advice_column();
+        let f = meta.fixed_column();
+        let i = meta.instance_column();
+        meta.create_gate("gate", |meta| {
+            let a_cur = meta.query_advice(a, Rotation::cur());
+            let a_next = meta.query_advice(a, Rotation::next());
+            let f_prev = meta.query_fixed(f, Rotation::prev());
+            let i_cur = meta.query_instance(i, Rotation::cur());
+            vec![f_prev * (a_next - a_cur - i_cur)]
+        });
+
+        assert_eq!(meta.all_query_rotations(), BTreeSet::from([-1, 0, 1]));
+    }
+
+    #[test]
+    fn instance_len() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let i = meta.instance_column();
+        let j = meta.instance_column();
+        meta.create_gate("gate", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let i_next = meta.query_instance(i, Rotation::next());
+            vec![a - i_next]
+        });
+
+        assert_eq!(meta.instance_len(i.index(), 10), 11);
+        assert_eq!(meta.instance_len(j.index(), 10), 10);
+    }
+
+    #[test]
+    fn optimize() {
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let c = Column::new(2, Advice::default());
+        let two = Expression::Constant(Fr::from(2));
+        let three = Expression::Constant(Fr::from(3));
+        let one = Expression::Constant(Fr::ONE);
+        // a * b + a * (c * 1) + (2 * 3 - 6)
+        let expr = a.cur::<Fr>() * b.cur()
+            + a.cur::<Fr>() * (c.cur::<Fr>() * one)
+            + (two * three - Expression::Constant(Fr::from(6)));
+        let optimized = expr.optimize();
+
+        assert!(optimized.num_nodes() < expr.num_nodes());
+        assert_eq!(
+            optimized.identifier(),
+            "(advice[0][0]*(advice[1][0]+advice[2][0]))"
+        );
+
+        let values = [Fr::from(5), Fr::from(7), Fr::from(11)];
+        let eval = |expr: &Expression<Fr>| {
+            expr.evaluate(
+                &|constant| constant,
+                &|_| unreachable!(),
+                &|_| unreachable!(),
+                &|query| values[query.column_index],
+                &|_| unreachable!(),
+                &|_| unreachable!(),
+                &|a| -a,
+                &|a, b| a + b,
+                &|a, b| a * b,
+                &|a, f| a * f,
+            )
         };
+        assert_eq!(eval(&optimized), eval(&expr));
+    }
+
+    #[test]
+    fn split_columns() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let f = meta.fixed_column();
+        let i = meta.instance_column();
+        let b = meta.advice_column();
+        let columns: Vec<Column<Any>> = vec![b.into(), f.into(), i.into(), a.into()];
+
+        let split = super::split_columns(&columns);
+        assert_eq!(split.advice, vec![b, a]);
+        assert_eq!(split.fixed, vec![f]);
+        assert_eq!(split.instance, vec![i]);
+    }
+
+    #[test]
+    fn linear_terms() {
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let expr = a.cur::<Fr>() * Fr::from(2) - b.cur::<Fr>() * Fr::from(3);
+        assert!(expr.is_degree_one());
+        assert_eq!(
+            expr.linear_terms(),
+            Some(vec![
+                (a.into(), Rotation::cur(), Fr::from(2)),
+                (b.into(), Rotation::cur(), -Fr::from(3)),
+            ])
+        );
+
+        let product = a.cur::<Fr>() * b.cur();
+        assert!(!product.is_degree_one());
+        assert_eq!(product.linear_terms(), None);
+    }
+
+    #[test]
+    fn total_multiplications() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        let f = meta.fixed_column();
+        meta.create_gate("mul", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::cur());
+            let f = meta.query_fixed(f, Rotation::cur());
+            // 2 multiplications
+            vec![f * (a * b)]
+        });
+        meta.create_gate("cube", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let f = meta.query_fixed(f, Rotation::cur());
+            // 3 multiplications, plus 1 for the scaling
+            vec![f * (a.clone() * a.clone() * a - Expression::Constant(Fr::ONE)) * Fr::from(2)]
+        });
+
+        assert_eq!(
+            meta.gates[0].polynomials()[0].op_counts().multiplications,
+            2
+        );
+        assert_eq!(
+            meta.gates[1].polynomials()[0].op_counts().multiplications,
+            4
+        );
+        assert_eq!(meta.total_multiplications(), 6);
+    }
+
+    #[test]
+    fn common_subexpressions() {
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let c = Column::new(2, Advice::default());
+        let ab = a.cur::<Fr>() * b.cur();
+        let expr = ab.clone() + ab * c.cur();
+
+        let common = expr.common_subexpressions();
+        assert!(common.contains(&("(advice[0][0]*advice[1][0])".to_string(), 2)));
+        assert!(common
+            .iter()
+            .all(|(identifier, _)| identifier != "advice[2][0]"));
+        assert!(common.windows(2).all(|pair| pair[0].1 >= pair[1].1));
     }
 
-    let mut num_advice_queries = vec![0; cs2.num_advice_columns];
-    for (column, _) in queries.advice.iter() {
-        num_advice_queries[column.index()] += 1;
+    #[test]
+    fn assert_well_formed() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column_in(SecondPhase);
+        let f = meta.fixed_column();
+        let theta = meta.challenge_usable_after(FirstPhase);
+        meta.create_gate("gate", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::cur());
+            let theta = meta.query_challenge(theta);
+            vec![theta * a - b]
+        });
+        meta.lookup_any("lookup", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let f = meta.query_fixed(f, Rotation::cur());
+            vec![(a, f)]
+        });
+        assert_eq!(meta.assert_well_formed(), Ok(()));
+
+        meta.lookups[0].table_expressions.pop();
+        let errors = meta.assert_well_formed().unwrap_err();
+        assert_eq!(errors.len(), 1);
+
+        assert!(errors[0].contains("lookup \"lookup\""));
+
+        // Multiplying a challenge by advice of a later phase is the usual multi-phase
+        // pattern and is not reported.
+        meta.create_gate("multi-phase gate", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::cur());
+            let theta = meta.query_challenge(theta);
+            vec![a * theta * b]
+        });
+        assert!(!meta.challenge_phase_violations().is_empty());
+        assert_eq!(meta.assert_well_formed().unwrap_err(), errors);
     }
 
-    let queries = Queries {
-        advice: queries.advice,
-        instance: queries.instance,
-        fixed: queries.fixed,
-        num_advice_queries,
-    };
-    (queries, gates, lookups, shuffles)
-}
+    #[test]
+    fn instance_query_at() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let _ = meta.instance_column();
+        let i = meta.instance_column();
 
-/// This is a description of the circuit environment, such as the gate, column and
-/// permutation arrangements.
-#[derive(Debug, Clone)]
-pub struct ConstraintSystem<F: Field> {
-    pub num_fixed_columns: usize,
-    pub num_advice_columns: usize,
-    pub num_instance_columns: usize,
-    pub num_selectors: usize,
-    pub num_challenges: usize,
+        match i.query_at::<Fr>(Rotation::next()) {
+            Expression::Instance(query) => {
+                assert_eq!(query.index, None);
+                assert_eq!(query.column_index(), 1);
+                assert_eq!(query.rotation(), Rotation::next());
+            }
+            _ => panic!("expected an instance query"),
+        }
+    }
 
-    /// Contains the index of each advice column that is left unblinded.
-    pub unblinded_advice_columns: Vec<usize>,
+    #[test]
+    fn arguments_referencing_column() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let advice: Vec<_> = (0..3).map(|_| meta.advice_column()).collect();
+        let f = meta.fixed_column();
+        meta.lookup_any("reads advice 0", |meta| {
+            let a = meta.query_advice(advice[0], Rotation::cur());
+            let f = meta.query_fixed(f, Rotation::cur());
+            vec![(a, f)]
+        });
+        meta.lookup_any("reads advice 2", |meta| {
+            let a = meta.query_advice(advice[2], Rotation::next());
+            let f = meta.query_fixed(f, Rotation::cur());
+            vec![(a, f)]
+        });
+        meta.shuffle("shuffles advice 2", |meta| {
+            let a = meta.query_advice(advice[1], Rotation::cur());
+            let b = meta.query_advice(advice[2], Rotation::cur());
+            vec![(a, b)]
+        });
 
-    /// Contains the phase for each advice column. Should have same length as num_advice_columns.
-    pub advice_column_phase: Vec<sealed::Phase>,
-    /// Contains the phase for each challenge. Should have same length as num_challenges.
-    pub challenge_phase: Vec<sealed::Phase>,
+        assert_eq!(meta.lookups_referencing_column(advice[2].into()), vec![1]);
+        assert_eq!(meta.lookups_referencing_column(f.into()), vec![0, 1]);
+        assert_eq!(meta.shuffles_referencing_column(advice[2].into()), vec![0]);
+        assert!(meta
+            .shuffles_referencing_column(advice[0].into())
+            .is_empty());
+    }
 
-    /// This is a cached vector that maps virtual selectors to the concrete
-    /// fixed column that they were compressed into. This is just used by dev
-    /// tooling right now.
-    pub selector_map: Vec<Column<Fixed>>,
+    #[test]
+    fn into_from_parts() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let f = meta.fixed_column();
+        meta.enable_equality(a);
+        meta.annotate_lookup_any_column(f, || "table");
+        meta.create_gate("gate", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let f = meta.query_fixed(f, Rotation::next());
+            vec![a * f]
+        });
+        meta.lookup_any("lookup", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let f = meta.query_fixed(f, Rotation::cur());
+            vec![(a, f)]
+        });
 
-    pub gates: Vec<Gate<F>>,
-    pub advice_queries: Vec<(Column<Advice>, Rotation)>,
-    // Contains an integer for each advice column
-    // identifying how many distinct queries it has
-    // so far; should be same length as num_advice_columns.
-    pub num_advice_queries: Vec<usize>,
-    pub instance_queries: Vec<(Column<Instance>, Rotation)>,
-    pub fixed_queries: Vec<(Column<Fixed>, Rotation)>,
+        let rebuilt = ConstraintSystem::from_parts(meta.clone().into_parts());
+        assert_eq!(format!("{:?}", rebuilt), format!("{:?}", meta));
+    }
 
-    // Permutation argument for performing equality constraints
-    pub permutation: permutation::Argument,
+    #[test]
+    fn sort_gates() {
+        fn build(reversed: bool) -> ConstraintSystem<Fr> {
+            let mut meta = ConstraintSystem::<Fr>::default();
+            let a = meta.advice_column();
+            let b = meta.advice_column();
+            let mut gates: Vec<(&str, Expression<Fr>)> = vec![
+                ("mul", a.cur::<Fr>() * b.next()),
+                ("add", a.prev::<Fr>() + b.cur()),
+                (
+                    "bool",
+                    a.cur::<Fr>() * (a.cur::<Fr>() - Expression::Constant(Fr::ONE)),
+                ),
+            ];
+            if reversed {
+                gates.reverse();
+            }
+            for (name, poly) in gates {
+                meta.create_gate(name, |_| vec![poly]);
+            }
+            meta
+        }
 
-    // Vector of lookup arguments, where each corresponds to a sequence of
-    // input expressions and a sequence of table expressions involved in the lookup.
-    pub lookups: Vec<lookup::Argument<F>>,
+        let mut forward = build(false);
+        let mut backward = build(true);
+        assert_ne!(
+            format!("{:?}", forward.gates),
+            format!("{:?}", backward.gates)
+        );
 
-    // Vector of shuffle arguments, where each corresponds to a sequence of
-    // input expressions and a sequence of shuffle expressions involved in the shuffle.
-    pub shuffles: Vec<shuffle::Argument<F>>,
+        forward.sort_gates();
+        backward.sort_gates();
+        assert_eq!(
+            format!("{:?}", forward.gates),
+            format!("{:?}", backward.gates)
+        );
+        assert_eq!(forward.advice_queries, backward.advice_queries);
+    }
 
-    // List of indexes of Fixed columns which are associated to a circuit-general Column tied to their annotation.
-    pub general_column_annotations: HashMap<metadata::Column, String>,
+    #[test]
+    fn evaluate_rows() {
+        let a = Column::new(0, Advice::default());
+        let expr = a.next::<Fr>() - a.cur();
+        let column = [1u64, 4, 9, 16].map(Fr::from);
+
+        let values = expr.evaluate_rows(
+            column.len(),
+            &|row, _| column[row],
+            &|_, _| unreachable!(),
+            &|_, _| unreachable!(),
+            &|_| unreachable!(),
+        );
+        // The last row wraps around to the first one.
+        assert_eq!(
+            values,
+            vec![Fr::from(3), Fr::from(5), Fr::from(7), -Fr::from(15)]
+        );
+    }
 
-    // Vector of fixed columns, which can be used to store constant values
-    // that are copied into advice columns.
-    pub constants: Vec<Column<Fixed>>,
+    #[test]
+    fn shift_phases() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let theta = meta.challenge_usable_after(FirstPhase);
+        let b = meta.advice_column_in(SecondPhase);
+        meta.enable_equality(b);
+        meta.create_gate("gate", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::cur());
+            let theta = meta.query_challenge(theta);
+            vec![a * theta - b]
+        });
 
-    pub minimum_degree: Option<usize>,
-}
+        let mut overflow = meta.clone();
+        assert!(overflow.shift_phases(2).is_err());
+        assert_eq!(overflow.advice_column_phase(), vec![0, 1]);
+
+        meta.shift_phases(1).unwrap();
+        assert_eq!(meta.advice_column_phase(), vec![1, 2]);
+        assert_eq!(meta.challenge_phase(), vec![1]);
+        let b_shifted: Column<Any> = Column::new(1, Any::Advice(Advice::new(2)));
+        assert_eq!(meta.permutation.get_columns(), vec![b_shifted]);
+        // `enable_equality` queries `b` before the gate queries `a`.
+        assert_eq!(
+            meta.advice_queries,
+            vec![
+                (Column::new(1, Advice::new(2)), Rotation::cur()),
+                (Column::new(0, Advice::new(1)), Rotation::cur()),
+            ]
+        );
+        assert_eq!(
+            meta.gates[0].polynomials()[0].identifier(),
+            "((advice[0][0][1]*challenge[0])+(-advice[1][0][2]))"
+        );
+        assert!(meta.gates[0]
+            .queried_cells()
+            .iter()
+            .any(|cell| cell.column == b_shifted));
+        assert!(meta.shift_phases(1).is_err());
+    }
 
-impl<F: Field> From<ConstraintSystemV2Backend<F>> for ConstraintSystem<F> {
-    fn from(cs2: ConstraintSystemV2Backend<F>) -> Self {
-        let (queries, gates, lookups, shuffles) = collect_queries(&cs2);
-        ConstraintSystem {
-            num_fixed_columns: cs2.num_fixed_columns,
-            num_advice_columns: cs2.num_advice_columns,
-            num_instance_columns: cs2.num_instance_columns,
-            num_selectors: 0,
-            num_challenges: cs2.num_challenges,
-            unblinded_advice_columns: cs2.unblinded_advice_columns,
-            advice_column_phase: cs2
-                .advice_column_phase
-                .into_iter()
-                .map(sealed::Phase)
-                .collect(),
-            challenge_phase: cs2.challenge_phase.into_iter().map(sealed::Phase).collect(),
-            selector_map: Vec::new(),
-            gates,
-            advice_queries: queries.advice,
-            num_advice_queries: queries.num_advice_queries,
-            instance_queries: queries.instance,
-            fixed_queries: queries.fixed,
-            permutation: cs2.permutation.into(),
-            lookups,
-            shuffles,
-            general_column_annotations: cs2.general_column_annotations,
-            constants: Vec::new(),
-            minimum_degree: None,
-        }
+    #[test]
+    fn max_advice_query_count() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        assert_eq!(meta.max_advice_query_count(), 1);
+
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        meta.create_gate("gate", |meta| {
+            let a_prev = meta.query_advice(a, Rotation::prev());
+            let a_cur = meta.query_advice(a, Rotation::cur());
+            let a_next = meta.query_advice(a, Rotation::next());
+            let a_far = meta.query_advice(a, Rotation(2));
+            let b = meta.query_advice(b, Rotation::cur());
+            vec![a_prev + a_cur + a_next + a_far - b]
+        });
+        assert_eq!(meta.num_advice_queries, vec![4, 1]);
+        assert_eq!(meta.max_advice_query_count(), 4);
+        assert_eq!(meta.blinding_factors(), 6);
     }
-}
 
-/// Represents the minimal parameters that determine a `ConstraintSystem`.
-#[allow(dead_code)]
-pub struct PinnedConstraintSystem<'a, F: Field> {
-    num_fixed_columns: &'a usize,
-    num_advice_columns: &'a usize,
-    num_instance_columns: &'a usize,
-    num_selectors: &'a usize,
-    num_challenges: &'a usize,
-    advice_column_phase: &'a Vec<sealed::Phase>,
-    challenge_phase: &'a Vec<sealed::Phase>,
-    gates: PinnedGates<'a, F>,
-    advice_queries: &'a Vec<(Column<Advice>, Rotation)>,
-    instance_queries: &'a Vec<(Column<Instance>, Rotation)>,
-    fixed_queries: &'a Vec<(Column<Fixed>, Rotation)>,
-    permutation: &'a permutation::Argument,
-    lookups: &'a Vec<lookup::Argument<F>>,
-    shuffles: &'a Vec<shuffle::Argument<F>>,
-    constants: &'a Vec<Column<Fixed>>,
-    minimum_degree: &'a Option<usize>,
-}
+    #[test]
+    fn permute_instance_columns() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let i0 = meta.instance_column();
+        let i1 = meta.instance_column();
+        meta.enable_equality(i1);
+        meta.annotate_lookup_any_column(i0, || "first");
+        meta.annotate_lookup_any_column(i1, || "second");
+        meta.create_gate("gate", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let i0 = meta.query_instance(i0, Rotation::cur());
+            let i1 = meta.query_instance(i1, Rotation::next());
+            vec![a.clone() * i0 - a * i1]
+        });
+        // `enable_equality` queries `i1` at the current row before the gate does.
+        assert_eq!(
+            meta.instance_queries,
+            vec![
+                (i1, Rotation::cur()),
+                (i0, Rotation::cur()),
+                (i1, Rotation::next())
+            ]
+        );
 
-impl<'a, F: Field> std::fmt::Debug for PinnedConstraintSystem<'a, F> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut debug_struct = f.debug_struct("PinnedConstraintSystem");
-        debug_struct
-            .field("num_fixed_columns", self.num_fixed_columns)
-            .field("num_advice_columns", self.num_advice_columns)
-            .field("num_instance_columns", self.num_instance_columns)
-            .field("num_selectors", self.num_selectors);
-        // Only show multi-phase related fields if it's used.
-        if *self.num_challenges > 0 {
-            debug_struct
-                .field("num_challenges", self.num_challenges)
-                .field("advice_column_phase", self.advice_column_phase)
-                .field("challenge_phase", self.challenge_phase);
-        }
-        debug_struct
-            .field("gates", &self.gates)
-            .field("advice_queries", self.advice_queries)
-            .field("instance_queries", self.instance_queries)
-            .field("fixed_queries", self.fixed_queries)
-            .field("permutation", self.permutation)
-            .field("lookups", self.lookups);
-        if !self.shuffles.is_empty() {
-            debug_struct.field("shuffles", self.shuffles);
-        }
-        debug_struct
-            .field("constants", self.constants)
-            .field("minimum_degree", self.minimum_degree);
-        debug_struct.finish()
+        assert!(meta.permute_instance_columns(&[0, 0]).is_err());
+        assert!(meta.permute_instance_columns(&[1]).is_err());
+        assert!(meta.permute_instance_columns(&[0, 2]).is_err());
+
+        meta.permute_instance_columns(&[1, 0]).unwrap();
+        assert_eq!(
+            meta.instance_queries,
+            vec![
+                (i0, Rotation::cur()),
+                (i1, Rotation::cur()),
+                (i0, Rotation::next())
+            ]
+        );
+        assert_eq!(meta.get_instance_query_index(i0, Rotation::cur()), 0);
+        assert_eq!(meta.get_instance_query_index(i1, Rotation::cur()), 1);
+        assert_eq!(meta.get_instance_query_index(i0, Rotation::next()), 2);
+        assert_eq!(meta.permutation.get_columns(), vec![i0.into()]);
+
+        let expr = &meta.gates[0].polynomials()[0];
+        assert_eq!(
+            expr.queried_cells(),
+            BTreeSet::from([
+                (a.into(), Rotation::cur()),
+                (i1.into(), Rotation::cur()),
+                (i0.into(), Rotation::next()),
+            ])
+        );
+        let concat = |mut a: Vec<(usize, Option<usize>)>, b| {
+            a.extend(b);
+            a
+        };
+        let instance_leaves = expr.evaluate(
+            &|_| vec![],
+            &|_| vec![],
+            &|_| vec![],
+            &|_| vec![],
+            &|query| vec![(query.column_index, query.index)],
+            &|_| vec![],
+            &|a| a,
+            &concat,
+            &concat,
+            &|a, _| a,
+        );
+        assert_eq!(instance_leaves, vec![(1, Some(1)), (0, Some(2))]);
+        let gate_cells: Vec<_> = meta.gates[0]
+            .queried_cells()
+            .iter()
+            .map(|cell| (cell.column, cell.rotation))
+            .collect();
+        assert!(gate_cells.contains(&(i0.into(), Rotation::next())));
+        assert!(gate_cells.contains(&(i1.into(), Rotation::cur())));
+
+        let annotation = |column: Column<Instance>| {
+            meta.general_column_annotations()
+                .get(&metadata::Column::from((Any::Instance, column.index())))
+                .map(String::as_str)
+        };
+        assert_eq!(annotation(i1), Some("first"));
+        assert_eq!(annotation(i0), Some("second"));
     }
-}
 
-struct PinnedGates<'a, F: Field>(&'a Vec<Gate<F>>);
+    #[test]
+    fn prune_unused_challenges() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let _ = meta.advice_column_in(SecondPhase);
+        let unused = meta.challenge_usable_after(FirstPhase);
+        let alpha = meta.challenge_usable_after(FirstPhase);
+        let beta = meta.challenge_usable_after(SecondPhase);
+        meta.create_gate("gate", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let alpha = meta.query_challenge(alpha);
+            let beta = meta.query_challenge(beta);
+            vec![alpha * a.clone() + beta * a]
+        });
+        assert_eq!(unused.index(), 0);
 
-impl<'a, F: Field> std::fmt::Debug for PinnedGates<'a, F> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        f.debug_list()
-            .entries(self.0.iter().flat_map(|gate| gate.polynomials().iter()))
-            .finish()
+        assert_eq!(meta.prune_unused_challenges(), 1);
+        assert_eq!(meta.num_challenges, 2);
+        assert_eq!(
+            meta.challenge_phase,
+            vec![super::sealed::Phase(0), super::sealed::Phase(1)]
+        );
+
+        let mut challenges = vec![];
+        meta.visit_challenges_mut(&mut |challenge| {
+            challenges.push((challenge.index(), challenge.phase()))
+        });
+        assert_eq!(challenges, vec![(0, 0), (1, 1)]);
     }
-}
 
-impl<F: Field> Default for ConstraintSystem<F> {
-    fn default() -> ConstraintSystem<F> {
-        ConstraintSystem {
-            num_fixed_columns: 0,
-            num_advice_columns: 0,
-            num_instance_columns: 0,
-            num_selectors: 0,
-            num_challenges: 0,
-            unblinded_advice_columns: Vec::new(),
-            advice_column_phase: Vec::new(),
-            challenge_phase: Vec::new(),
-            selector_map: vec![],
-            gates: vec![],
-            fixed_queries: Vec::new(),
-            advice_queries: Vec::new(),
-            num_advice_queries: Vec::new(),
-            instance_queries: Vec::new(),
-            permutation: permutation::Argument::default(),
-            lookups: Vec::new(),
-            shuffles: Vec::new(),
-            general_column_annotations: HashMap::new(),
-            constants: vec![],
-            minimum_degree: None,
-        }
+    #[test]
+    fn commit_schedule() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let _ = meta.advice_column();
+        let _ = meta.advice_column_in(SecondPhase);
+        let _ = meta.advice_column();
+        let _ = meta.challenge_usable_after(SecondPhase);
+        let _ = meta.challenge_usable_after(FirstPhase);
+
+        assert_eq!(
+            meta.commit_schedule(),
+            vec![
+                PhaseStep {
+                    phase: 0,
+                    advice_columns: vec![0, 2],
+                    challenges: vec![1],
+                },
+                PhaseStep {
+                    phase: 1,
+                    advice_columns: vec![1],
+                    challenges: vec![0],
+                },
+            ]
+        );
     }
-}
 
-impl<F: Field> ConstraintSystem<F> {
-    /// Obtain a pinned version of this constraint system; a structure with the
-    /// minimal parameters needed to determine the rest of the constraint
-    /// system.
-    pub fn pinned(&self) -> PinnedConstraintSystem<'_, F> {
-        PinnedConstraintSystem {
-            num_fixed_columns: &self.num_fixed_columns,
-            num_advice_columns: &self.num_advice_columns,
-            num_instance_columns: &self.num_instance_columns,
-            num_selectors: &self.num_selectors,
-            num_challenges: &self.num_challenges,
-            advice_column_phase: &self.advice_column_phase,
-            challenge_phase: &self.challenge_phase,
-            gates: PinnedGates(&self.gates),
-            fixed_queries: &self.fixed_queries,
-            advice_queries: &self.advice_queries,
-            instance_queries: &self.instance_queries,
-            permutation: &self.permutation,
-            lookups: &self.lookups,
-            shuffles: &self.shuffles,
-            constants: &self.constants,
-            minimum_degree: &self.minimum_degree,
+    #[test]
+    fn is_compatible_with() {
+        fn build(names: [&str; 2], extra_gate: bool) -> ConstraintSystem<Fr> {
+            let mut meta = ConstraintSystem::<Fr>::default();
+            let a = meta.advice_column();
+            let f = meta.fixed_column();
+            meta.enable_equality(a);
+            meta.annotate_lookup_any_column(f, || names[0]);
+            meta.create_gate(names[0], |meta| {
+                let a = meta.query_advice(a, Rotation::cur());
+                let f = meta.query_fixed(f, Rotation::cur());
+                vec![f * a]
+            });
+            meta.lookup_any(names[1], |meta| {
+                let a = meta.query_advice(a, Rotation::cur());
+                let f = meta.query_fixed(f, Rotation::cur());
+                vec![(a, f)]
+            });
+            if extra_gate {
+                meta.create_gate("extra", |meta| {
+                    let a = meta.query_advice(a, Rotation::next());
+                    vec![a]
+                });
+            }
+            meta
         }
+
+        let meta = build(["gate", "lookup"], false);
+        assert!(meta.is_compatible_with(&build(["renamed gate", "renamed lookup"], false)));
+        assert!(!meta.is_compatible_with(&build(["gate", "lookup"], true)));
+
+        let mut min_degree = build(["gate", "lookup"], false);
+        min_degree.set_minimum_degree(5);
+        assert!(!meta.is_compatible_with(&min_degree));
+
+        // The order in which queries were registered does not matter.
+        let mut reordered = build(["gate", "lookup"], true);
+        reordered.advice_queries.reverse();
+        assert!(build(["gate", "lookup"], true).is_compatible_with(&reordered));
+
+        let permuted = |swapped: bool| {
+            let mut meta = ConstraintSystem::<Fr>::default();
+            let a = meta.advice_column();
+            let b = meta.advice_column();
+            let (first, second) = if swapped { (b, a) } else { (a, b) };
+            meta.enable_equality(first);
+            meta.enable_equality(second);
+            meta
+        };
+        assert!(permuted(false).is_compatible_with(&permuted(false)));
+        assert!(!permuted(false).is_compatible_with(&permuted(true)));
     }
 
-    /// Enables this fixed column to be used for global constant assignments.
-    ///
-    /// # Side-effects
-    ///
-    /// The column will be equality-enabled.
-    pub fn enable_constant(&mut self, column: Column<Fixed>) {
-        if !self.constants.contains(&column) {
-            self.constants.push(column);
-            self.enable_equality(column);
-        }
+    #[test]
+    fn queried_columns() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column_in(SecondPhase);
+        let f = meta.fixed_column();
+        let i = meta.instance_column();
+        let theta = meta.challenge_usable_after(FirstPhase);
+        meta.create_gate("gate", |meta| {
+            let a_cur = meta.query_advice(a, Rotation::cur());
+            let a_next = meta.query_advice(a, Rotation::next());
+            let b = meta.query_advice(b, Rotation::prev());
+            let f = meta.query_fixed(f, Rotation::cur());
+            let i = meta.query_instance(i, Rotation::cur());
+            let theta = meta.query_challenge(theta);
+            vec![f * (a_cur - a_next) + theta * b * Fr::from(3) - i + Expression::Constant(Fr::ONE)]
+        });
+        let expr = &meta.gates[0].polynomials()[0];
+
+        let a: Column<Any> = a.into();
+        let b: Column<Any> = b.into();
+        let f: Column<Any> = f.into();
+        let i: Column<Any> = i.into();
+        assert_eq!(b.column_type, Any::Advice(Advice::new(1)));
+        assert_eq!(
+            expr.queried_columns().into_iter().collect::<Vec<_>>(),
+            vec![i, a, b, f]
+        );
+        assert_eq!(
+            expr.queried_cells(),
+            BTreeSet::from([
+                (a, Rotation::cur()),
+                (a, Rotation::next()),
+                (b, Rotation::prev()),
+                (f, Rotation::cur()),
+                (i, Rotation::cur()),
+            ])
+        );
+
+        let constant = Expression::<Fr>::Constant(Fr::ONE);
+        assert!(constant.queried_columns().is_empty());
     }
 
-    /// Enable the ability to enforce equality over cells in this column
-    pub fn enable_equality<C: Into<Column<Any>>>(&mut self, column: C) {
-        let column = column.into();
-        self.query_any_index(column, Rotation::cur());
-        self.permutation.add_column(column);
+    #[test]
+    fn max_min_rotation() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let f = meta.fixed_column();
+        let theta = meta.challenge_usable_after(FirstPhase);
+        meta.create_gate("gate", |meta| {
+            let a_prev = meta.query_advice(a, Rotation::prev());
+            let a_cur = meta.query_advice(a, Rotation::cur());
+            let f = meta.query_fixed(f, Rotation(5));
+            vec![f * (a_cur - a_prev)]
+        });
+        let expr = &meta.gates[0].polynomials()[0];
+        assert_eq!(expr.max_rotation(), Rotation(5));
+        assert_eq!(expr.min_rotation(), Rotation::prev());
+
+        // Only positive rotations.
+        let expr = Column::new(0, Instance).rot::<Fr>(2) * Column::new(0, Fixed).rot(3);
+        assert_eq!(expr.min_rotation(), Rotation(2));
+        assert_eq!(expr.max_rotation(), Rotation(3));
+
+        // Only challenges and constants.
+        let expr = Expression::<Fr>::Challenge(theta) * Fr::from(2) + Expression::Constant(Fr::ONE);
+        assert_eq!(expr.max_rotation(), Rotation(0));
+        assert_eq!(expr.min_rotation(), Rotation(0));
     }
 
-    /// Add a lookup argument for some input expressions and table columns.
-    ///
-    /// `table_map` returns a map between input expressions and the table columns
-    /// they need to match.
-    pub fn lookup<S: AsRef<str>>(
-        &mut self,
-        name: S,
-        table_map: impl FnOnce(&mut VirtualCells<'_, F>) -> Vec<(Expression<F>, TableColumn)>,
-    ) -> usize {
-        let mut cells = VirtualCells::new(self);
-        let table_map = table_map(&mut cells)
-            .into_iter()
-            .map(|(mut input, table)| {
-                if input.contains_simple_selector() {
-                    panic!("expression containing simple selector supplied to lookup argument");
+    #[test]
+    fn first_violation() {
+        use std::cell::Cell;
+
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let q = meta.fixed_column();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        meta.create_gate("fibonacci", |meta| {
+            let q = meta.query_fixed(q, Rotation::cur());
+            let a_cur = meta.query_advice(a, Rotation::cur());
+            let a_next = meta.query_advice(a, Rotation::next());
+            let b_cur = meta.query_advice(b, Rotation::cur());
+            let b_next = meta.query_advice(b, Rotation::next());
+            vec![
+                q.clone() * (a_next - b_cur.clone()),
+                q * (b_next - a_cur - b_cur),
+            ]
+        });
+        let gate = &meta.gates[0];
+
+        let num_rows = 8;
+        let selector: Vec<Fr> = (0..num_rows)
+            .map(|row| {
+                if row < num_rows - 1 {
+                    Fr::ONE
+                } else {
+                    Fr::ZERO
                 }
-                let mut table = cells.query_fixed(table.inner(), Rotation::cur());
-                input.query_cells(&mut cells);
-                table.query_cells(&mut cells);
-                (input, table)
             })
             .collect();
-        let index = self.lookups.len();
+        let mut columns = vec![vec![Fr::ONE], vec![Fr::ONE]];
+        for row in 1..num_rows {
+            let (a, b) = (columns[0][row - 1], columns[1][row - 1]);
+            columns[0].push(b);
+            columns[1].push(a + b);
+        }
 
-        self.lookups
-            .push(lookup::Argument::new(name.as_ref(), table_map));
+        let last_row = Cell::new(0);
+        let check = |columns: &Vec<Vec<Fr>>| {
+            gate.first_violation(
+                num_rows,
+                &|row, query| columns[query.column_index][row],
+                &|row, _| {
+                    last_row.set(row);
+                    selector[row]
+                },
+                &|_, _| unreachable!(),
+                &|_| unreachable!(),
+            )
+        };
+        assert_eq!(check(&columns), None);
 
-        index
+        // Breaking `b` at row 4 violates the second constraint at row 3 first, and the
+        // rows after it are not evaluated.
+        columns[1][4] += Fr::ONE;
+        assert_eq!(check(&columns), Some((3, 1)));
+        assert_eq!(last_row.get(), 3);
     }
 
-    /// Add a lookup argument for some input expressions and table expressions.
-    ///
-    /// `table_map` returns a map between input expressions and the table expressions
-    /// they need to match.
-    pub fn lookup_any<S: AsRef<str>>(
-        &mut self,
-        name: S,
-        table_map: impl FnOnce(&mut VirtualCells<'_, F>) -> Vec<(Expression<F>, Expression<F>)>,
-    ) -> usize {
-        let mut cells = VirtualCells::new(self);
-        let table_map = table_map(&mut cells)
-            .into_iter()
-            .map(|(mut input, mut table)| {
-                if input.contains_simple_selector() {
-                    panic!("expression containing simple selector supplied to lookup argument");
-                }
-                if table.contains_simple_selector() {
-                    panic!("expression containing simple selector supplied to lookup argument");
-                }
-                input.query_cells(&mut cells);
-                table.query_cells(&mut cells);
-                (input, table)
-            })
-            .collect();
-        let index = self.lookups.len();
+    #[test]
+    fn num_nodes() {
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let c = Column::new(0, Fixed);
+
+        let expr: Expression<Fr> = (a.cur() + b.cur()) * c.cur();
+        assert_eq!(expr.num_nodes(), 5);
+        assert_eq!(Expression::<Fr>::Constant(Fr::ONE).num_nodes(), 1);
+        assert_eq!((-(a.cur::<Fr>() * Fr::from(2))).num_nodes(), 3);
+
+        let chain = (0..10_000).fold(a.cur::<Fr>(), |acc, _| {
+            Expression::Sum(Box::new(acc), Box::new(b.cur()))
+        });
+        assert_eq!(chain.num_nodes(), 20_001);
+    }
 
-        self.lookups
-            .push(lookup::Argument::new(name.as_ref(), table_map));
+    #[test]
+    fn count_operations() {
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let c = Column::new(0, Fixed);
+        let d = Column::new(1, Fixed);
+
+        let expr: Expression<Fr> = a.cur() * b.cur() + c.cur() * d.cur();
+        assert_eq!(expr.count_multiplications(), 2);
+        assert_eq!(expr.count_additions(), 1);
+
+        let expr = -(a.cur::<Fr>() * Fr::from(2)) - b.cur();
+        assert_eq!(expr.count_multiplications(), 1);
+        assert_eq!(expr.count_additions(), 1);
+        assert_eq!(
+            expr.op_counts(),
+            OpCounts {
+                additions: 1,
+                multiplications: 1,
+                negations: 2,
+            }
+        );
 
-        index
+        let chain = (0..10_000).fold(a.cur::<Fr>(), |acc, _| {
+            Expression::Sum(Box::new(acc), Box::new(b.cur()))
+        });
+        assert_eq!(
+            chain.op_counts(),
+            OpCounts {
+                additions: 10_000,
+                ..OpCounts::default()
+            }
+        );
     }
 
-    /// Add a shuffle argument for some input expressions and table expressions.
-    pub fn shuffle<S: AsRef<str>>(
-        &mut self,
-        name: S,
-        shuffle_map: impl FnOnce(&mut VirtualCells<'_, F>) -> Vec<(Expression<F>, Expression<F>)>,
-    ) -> usize {
-        let mut cells = VirtualCells::new(self);
-        let shuffle_map = shuffle_map(&mut cells)
-            .into_iter()
-            .map(|(mut input, mut table)| {
-                input.query_cells(&mut cells);
-                table.query_cells(&mut cells);
-                (input, table)
-            })
-            .collect();
-        let index = self.shuffles.len();
+    #[test]
+    fn evaluate_cse() {
+        use std::cell::Cell;
+
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let c = Column::new(0, Fixed);
+        let ab = a.cur::<Fr>() * b.cur();
+        let expr = (ab.clone() + c.cur()) * (ab.clone() + c.cur()) * Fr::from(3) - ab * c.next()
+            + Expression::Constant(Fr::from(5));
+
+        let values = [Fr::from(2), Fr::from(7)];
+        let products = Cell::new(0);
+        let evaluate = |cse: bool| {
+            let closures = (
+                &|c: Fr| c,
+                &|_: super::Selector| -> Fr { unreachable!() },
+                &|q: super::FixedQuery| Fr::from(11 + q.rotation.0 as u64),
+                &|q: super::AdviceQuery| values[q.column_index],
+                &|_: super::InstanceQuery| -> Fr { unreachable!() },
+                &|_: Challenge| -> Fr { unreachable!() },
+                &|a: Fr| -a,
+                &|a: Fr, b: Fr| a + b,
+                &|a: Fr, b: Fr| {
+                    products.set(products.get() + 1);
+                    a * b
+                },
+                &|a: Fr, f: Fr| a * f,
+            );
+            let (c, s, f, a, i, ch, neg, sum, prod, scaled) = closures;
+            products.set(0);
+            let value = if cse {
+                expr.evaluate_cse(c, s, f, a, i, ch, neg, sum, prod, scaled)
+            } else {
+                expr.evaluate(c, s, f, a, i, ch, neg, sum, prod, scaled)
+            };
+            (value, products.get())
+        };
 
-        self.shuffles
-            .push(shuffle::Argument::new(name.as_ref(), shuffle_map));
+        let (value, products_cse) = evaluate(true);
+        assert_eq!(value, evaluate(false).0);
+        assert_eq!(value, Fr::from((14 + 11) * (14 + 11) * 3 - 14 * 12 + 5));
+        // `a * b` and `(a * b + c)` are evaluated once each.
+        assert_eq!(evaluate(false).1, 5);
+        assert_eq!(products_cse, 3);
 
-        index
+        // Deep expressions do not overflow the stack.
+        let chain = (0..10_000).fold(a.cur::<Fr>(), |acc, _| {
+            Expression::Sum(Box::new(acc), Box::new(b.cur()))
+        });
+        let count = chain.evaluate_cse(
+            &|_| 0,
+            &|_| 0,
+            &|_| 0,
+            &|_| 1,
+            &|_| 0,
+            &|_| 0,
+            &|a| a,
+            &|a, b| a + b,
+            &|a, b| a * b,
+            &|a, _| a,
+        );
+        assert_eq!(count, 10_001);
+
+        // Distinct constants and scalings land in distinct buckets.
+        let hash = |key: super::CseKey<Fr>| {
+            use std::hash::{Hash, Hasher};
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish()
+        };
+        let one = Fr::ONE;
+        let two = Fr::from(2);
+        assert_ne!(
+            hash(super::CseKey::Constant(one)),
+            hash(super::CseKey::Constant(two))
+        );
+        assert_ne!(
+            hash(super::CseKey::Scaled(0, one)),
+            hash(super::CseKey::Scaled(0, two))
+        );
     }
 
-    fn query_fixed_index(&mut self, column: Column<Fixed>, at: Rotation) -> usize {
-        // Return existing query, if it exists
-        for (index, fixed_query) in self.fixed_queries.iter().enumerate() {
-            if fixed_query == &(column, at) {
-                return index;
-            }
-        }
+    #[test]
+    fn evaluate_many() {
+        use std::cell::Cell;
+
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let c = Column::new(0, Fixed);
+        let exprs = [
+            a.cur::<Fr>() * b.cur(),
+            a.cur() + c.cur(),
+            -(b.cur::<Fr>() * c.next()),
+            a.cur::<Fr>() * a.cur() * Fr::from(3),
+        ];
 
-        // Make a new query
-        let index = self.fixed_queries.len();
-        self.fixed_queries.push((column, at));
+        let values = [Fr::from(2), Fr::from(7)];
+        let leaves = Cell::new(0);
+        let closures = (
+            &|c: Fr| c,
+            &|_: super::Selector| -> Fr { unreachable!() },
+            &|q: super::FixedQuery| {
+                leaves.set(leaves.get() + 1);
+                Fr::from(11 + q.rotation.0 as u64)
+            },
+            &|q: super::AdviceQuery| {
+                leaves.set(leaves.get() + 1);
+                values[q.column_index]
+            },
+            &|_: super::InstanceQuery| -> Fr { unreachable!() },
+            &|_: Challenge| -> Fr { unreachable!() },
+            &|a: Fr| -a,
+            &|a: Fr, b: Fr| a + b,
+            &|a: Fr, b: Fr| a * b,
+            &|a: Fr, f: Fr| a * f,
+        );
+        let (c, s, f, a, i, ch, neg, sum, prod, scaled) = closures;
 
-        index
+        let expected: Vec<Fr> = exprs
+            .iter()
+            .map(|expr| expr.evaluate(c, s, f, a, i, ch, neg, sum, prod, scaled))
+            .collect();
+        assert_eq!(leaves.get(), 8);
+        leaves.set(0);
+        let values = super::evaluate_many(&exprs, c, s, f, a, i, ch, neg, sum, prod, scaled);
+        assert_eq!(values, expected);
+        assert_eq!(
+            values,
+            vec![Fr::from(14), Fr::from(13), -Fr::from(84), Fr::from(12)]
+        );
+        // `a`, `b`, `c` and `c` at the next row are computed once each.
+        assert_eq!(leaves.get(), 4);
     }
 
-    pub(crate) fn query_advice_index(&mut self, column: Column<Advice>, at: Rotation) -> usize {
-        // Return existing query, if it exists
-        for (index, advice_query) in self.advice_queries.iter().enumerate() {
-            if advice_query == &(column, at) {
-                return index;
+    #[test]
+    fn degree_excluding() {
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let f = Column::new(0, Fixed);
+        let expr: Expression<Fr> = a.cur() * b.cur();
+        assert_eq!(expr.degree_excluding(&HashSet::new()), 2);
+
+        let constant_columns = HashSet::from([(Any::advice(), 1)]);
+        assert_eq!(expr.degree_excluding(&constant_columns), 1);
+
+        // Only the column type and index are matched, at any rotation.
+        let expr: Expression<Fr> = f.cur() * b.next() * b.prev() + a.cur() * a.next();
+        assert_eq!(expr.degree_excluding(&constant_columns), 2);
+        let constant_columns = HashSet::from([(Any::advice(), 1), (Any::Fixed, 0)]);
+        assert_eq!(expr.degree_excluding(&constant_columns), 2);
+        let constant_columns = HashSet::from([(Any::advice(), 0), (Any::Fixed, 0)]);
+        assert_eq!(expr.degree_excluding(&constant_columns), 2);
+        let constant_columns = HashSet::from([(Any::advice(), 0), (Any::advice(), 1)]);
+        assert_eq!(expr.degree_excluding(&constant_columns), 1);
+
+        // The phase of an advice column type does not matter.
+        let c = Column::new(2, Advice::new(1));
+        let expr: Expression<Fr> = a.cur() * c.cur();
+        let constant_columns = HashSet::from([(Any::advice(), 2)]);
+        assert_eq!(expr.degree_excluding(&constant_columns), 1);
+        let constant_columns = HashSet::from([(Any::Advice(Advice::new(1)), 0)]);
+        assert_eq!(expr.degree_excluding(&constant_columns), 1);
+    }
+
+    #[test]
+    fn expression_visitor() {
+        use super::{AdviceQuery, ExpressionVisitor, FixedQuery, InstanceQuery};
+
+        /// Computes the degree, counting the visited leaves along the way.
+        struct Degree {
+            leaves: usize,
+        }
+
+        impl ExpressionVisitor<Fr> for Degree {
+            type Output = usize;
+
+            fn constant(&mut self, _: Fr) -> usize {
+                self.leaves += 1;
+                0
+            }
+            fn fixed(&mut self, _: FixedQuery) -> usize {
+                self.leaves += 1;
+                1
+            }
+            fn advice(&mut self, _: AdviceQuery) -> usize {
+                self.leaves += 1;
+                1
+            }
+            fn instance(&mut self, _: InstanceQuery) -> usize {
+                self.leaves += 1;
+                1
+            }
+            fn challenge(&mut self, _: Challenge) -> usize {
+                self.leaves += 1;
+                0
+            }
+            fn negated(&mut self, a: usize) -> usize {
+                a
+            }
+            fn sum(&mut self, a: usize, b: usize) -> usize {
+                a.max(b)
+            }
+            fn product(&mut self, a: usize, b: usize) -> usize {
+                a + b
+            }
+            fn scaled(&mut self, a: usize, _: Fr) -> usize {
+                a
             }
         }
 
-        // Make a new query
-        let index = self.advice_queries.len();
-        self.advice_queries.push((column, at));
-        self.num_advice_queries[column.index] += 1;
+        let a = Column::new(0, Advice::default());
+        let f = Column::new(0, Fixed);
+        let i = Column::new(0, Instance);
+        let expr: Expression<Fr> =
+            f.cur() * (a.cur() * a.next() - i.cur()) * Fr::from(2) + Expression::Constant(Fr::ONE);
 
-        index
+        let mut degree = Degree { leaves: 0 };
+        assert_eq!(expr.accept(&mut degree), expr.degree());
+        assert_eq!(degree.leaves, 5);
     }
 
-    fn query_instance_index(&mut self, column: Column<Instance>, at: Rotation) -> usize {
-        // Return existing query, if it exists
-        for (index, instance_query) in self.instance_queries.iter().enumerate() {
-            if instance_query == &(column, at) {
-                return index;
-            }
+    #[test]
+    #[should_panic(expected = "this visitor does not handle selectors")]
+    fn expression_visitor_selector() {
+        use super::{AdviceQuery, ExpressionVisitor, FixedQuery, InstanceQuery};
+
+        struct Unit;
+
+        impl ExpressionVisitor<Fr> for Unit {
+            type Output = ();
+
+            fn constant(&mut self, _: Fr) {}
+            fn fixed(&mut self, _: FixedQuery) {}
+            fn advice(&mut self, _: AdviceQuery) {}
+            fn instance(&mut self, _: InstanceQuery) {}
+            fn challenge(&mut self, _: Challenge) {}
+            fn negated(&mut self, _: ()) {}
+            fn sum(&mut self, _: (), _: ()) {}
+            fn product(&mut self, _: (), _: ()) {}
+            fn scaled(&mut self, _: (), _: Fr) {}
         }
 
-        // Make a new query
-        let index = self.instance_queries.len();
-        self.instance_queries.push((column, at));
+        Expression::<Fr>::Selector(super::Selector(0, false)).accept(&mut Unit);
+    }
 
-        index
+    #[test]
+    fn substitute_challenges() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let theta = meta.challenge_usable_after(FirstPhase);
+        let gamma = meta.challenge_usable_after(FirstPhase);
+        let b = meta.advice_column_in(SecondPhase);
+        meta.create_gate("gate", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::cur());
+            let theta = meta.query_challenge(theta);
+            let gamma = meta.query_challenge(gamma);
+            vec![(a + theta.clone()) * (b + gamma) * Fr::from(3) - theta]
+        });
+        let expr = &meta.gates[0].polynomials()[0];
+
+        let challenges = HashMap::from([(theta, Fr::from(5)), (gamma, Fr::from(7))]);
+        let evaluate = |expr: &Expression<Fr>| {
+            expr.evaluate(
+                &|constant| constant,
+                &|_| unreachable!(),
+                &|_| unreachable!(),
+                &|query| Fr::from(query.column_index as u64 + 10),
+                &|_| unreachable!(),
+                &|challenge| challenges[&challenge],
+                &|a| -a,
+                &|a, b| a + b,
+                &|a, b| a * b,
+                &|a, f| a * f,
+            )
+        };
+
+        let substituted = expr.substitute_challenge(theta, Fr::from(5));
+        assert_eq!(evaluate(&substituted), evaluate(expr));
+        assert_eq!(
+            substituted.to_string(),
+            "3*(a0[0] + 5) * (a1[0] + challenge1) - 5"
+        );
+
+        let substituted = expr.substitute_challenges(&challenges);
+        assert_eq!(evaluate(&substituted), evaluate(expr));
+        assert_eq!(substituted.to_string(), "3*(a0[0] + 5) * (a1[0] + 7) - 5");
     }
 
-    fn query_any_index(&mut self, column: Column<Any>, at: Rotation) -> usize {
-        match column.column_type() {
-            Any::Advice(_) => {
-                self.query_advice_index(Column::<Advice>::try_from(column).unwrap(), at)
-            }
-            Any::Fixed => self.query_fixed_index(Column::<Fixed>::try_from(column).unwrap(), at),
-            Any::Instance => {
-                self.query_instance_index(Column::<Instance>::try_from(column).unwrap(), at)
-            }
-        }
+    #[test]
+    fn expand() {
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let f = Column::new(0, Fixed);
+        let theta = Expression::<Fr>::Challenge(Challenge { index: 0, phase: 0 });
+        let values = [Fr::from(3), Fr::from(5)];
+        let evaluate = |expr: &Expression<Fr>| {
+            expr.evaluate(
+                &|constant| constant,
+                &|_| unreachable!(),
+                &|query| Fr::from(7 + query.rotation.0 as u64),
+                &|query| values[query.column_index],
+                &|_| unreachable!(),
+                &|_| Fr::from(11),
+                &|a| -a,
+                &|a, b| a + b,
+                &|a, b| a * b,
+                &|a, f| a * f,
+            )
+        };
+
+        // (a + b) * (a - b) * 2 = 2 * a^2 - 2 * b^2
+        let expr: Expression<Fr> = (a.cur() + b.cur()) * (a.cur() - b.cur()) * Fr::from(2);
+        let expanded = expr.expand();
+        assert_eq!(evaluate(&expanded), evaluate(&expr));
+        assert_eq!(expanded.degree(), expr.degree());
+        assert_eq!(expanded.to_string(), "2*a0[0] * a0[0] + -2*a1[0] * a1[0]");
+
+        // Expressions equal as polynomials expand identically.
+        let other: Expression<Fr> =
+            -(b.cur::<Fr>() * b.cur() * Fr::from(2)) + a.cur() * (a.cur() * Fr::from(2));
+        assert_eq!(other.expand(), expanded);
+
+        // Challenges and constants, with a term cancelling out.
+        let expr: Expression<Fr> = (theta.clone() + f.next())
+            * (f.cur() - Expression::Constant(Fr::from(4)))
+            + f.next::<Fr>() * Fr::from(4)
+            - Expression::Constant(Fr::ONE);
+        let expanded = expr.expand();
+        assert_eq!(evaluate(&expanded), evaluate(&expr));
+        assert_eq!(expanded, expanded.expand());
+        assert_eq!(
+            expanded.to_string(),
+            "-1 + -4*challenge0 + challenge0 * f0[0] + f0[0] * f0[1]"
+        );
+
+        let empty = (a.cur::<Fr>() - a.cur()).expand();
+        assert_eq!(empty, Expression::Constant(Fr::ZERO));
+
+        // A product of 10 binomials has 2^10 monomials.
+        let product = Expression::balanced_product(
+            (0..10).map(|rotation| a.rot::<Fr>(rotation) + b.rot(rotation)),
+        );
+        assert!(product.try_expand(1 << 9).is_none());
+        let expanded = product.try_expand(1 << 10).unwrap();
+        assert_eq!(expanded.op_counts().additions, (1 << 10) - 1);
     }
 
-    pub(crate) fn get_advice_query_index(&self, column: Column<Advice>, at: Rotation) -> usize {
-        for (index, advice_query) in self.advice_queries.iter().enumerate() {
-            if advice_query == &(column, at) {
-                return index;
-            }
-        }
+    #[test]
+    fn scalar_multiple_of() {
+        let a = Column::new(0, Advice::default()).cur::<Fr>();
+        let b = Column::new(1, Advice::default()).cur::<Fr>();
+        let c = Column::new(0, Fixed).next::<Fr>();
+
+        let diff = a.clone() - b.clone();
+        let tripled = -(b.clone() * Fr::from(3)) + a.clone() + a.clone() + a.clone();
+        assert_eq!(tripled.scalar_multiple_of(&diff), Some(Fr::from(3)));
+        assert_eq!(
+            diff.scalar_multiple_of(&tripled),
+            Some(Fr::from(3).invert().unwrap())
+        );
+        let product = (a.clone() - b.clone()) * c.clone();
+        assert_eq!(
+            product.scalar_multiple_of(&(c.clone() * a.clone() - b.clone() * c.clone())),
+            Some(Fr::ONE)
+        );
+        assert_eq!(
+            Expression::Constant(Fr::ZERO).scalar_multiple_of(&diff),
+            Some(Fr::ZERO)
+        );
 
-        panic!("get_advice_query_index called for non-existent query");
+        assert_eq!((a.clone() + b.clone()).scalar_multiple_of(&diff), None);
+        assert_eq!((a.clone() * c.clone()).scalar_multiple_of(&diff), None);
+        assert_eq!(a.scalar_multiple_of(&(b.clone() - b)), None);
     }
 
-    pub(crate) fn get_fixed_query_index(&self, column: Column<Fixed>, at: Rotation) -> usize {
-        for (index, fixed_query) in self.fixed_queries.iter().enumerate() {
-            if fixed_query == &(column, at) {
-                return index;
-            }
-        }
+    #[test]
+    fn coefficients() {
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let f = Column::new(0, Fixed);
+
+        let expr: Expression<Fr> = a.cur::<Fr>() * b.cur() * Fr::from(2) + a.cur() * Fr::from(3);
+        assert_eq!(
+            expr.coefficients(),
+            vec![
+                (vec![(a.into(), Rotation::cur())], Fr::from(3)),
+                (
+                    vec![(a.into(), Rotation::cur()), (b.into(), Rotation::cur())],
+                    Fr::from(2)
+                ),
+            ]
+        );
+
+        // Repeated cells, constant terms and challenges folded into the coefficients.
+        let theta = Challenge { index: 0, phase: 0 };
+        let expr: Expression<Fr> = (f.next::<Fr>() - Expression::Challenge(theta))
+            * (f.next::<Fr>() + Expression::Constant(Fr::from(2)));
+        assert_eq!(
+            expr.coefficients_with_challenges(&|_| Fr::from(5)),
+            vec![
+                (vec![], -Fr::from(10)),
+                (vec![(f.into(), Rotation::next())], -Fr::from(3)),
+                (
+                    vec![(f.into(), Rotation::next()), (f.into(), Rotation::next())],
+                    Fr::ONE
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn equals_constant_is_boolean() {
+        let a = Column::new(0, Advice::default());
+        let evaluate = |expr: &Expression<Fr>, value: u64| {
+            expr.evaluate(
+                &|constant| constant,
+                &|_| unreachable!(),
+                &|_| unreachable!(),
+                &|_| Fr::from(value),
+                &|_| unreachable!(),
+                &|_| unreachable!(),
+                &|a| -a,
+                &|a, b| a + b,
+                &|a, b| a * b,
+                &|a, f| a * f,
+            )
+        };
+
+        let expr = super::equals_constant(a.cur(), Fr::from(5));
+        assert_eq!(evaluate(&expr, 5), Fr::ZERO);
+        assert_eq!(evaluate(&expr, 6), Fr::ONE);
+
+        let expr = super::is_boolean(a.cur());
+        assert_eq!(evaluate(&expr, 0), Fr::ZERO);
+        assert_eq!(evaluate(&expr, 1), Fr::ZERO);
+        assert_eq!(evaluate(&expr, 2), -Fr::from(2));
+    }
+
+    #[test]
+    fn running_sum_product() {
+        let acc = Column::new(0, Advice::default());
+        let value = Column::new(1, Advice::default());
+        let check = |expr: &Expression<Fr>, accs: [u64; 5]| {
+            let values = [1, 2, 3, 4, 0];
+            let evaluations = expr.evaluate_rows(
+                5,
+                &|row, query| match query.column_index {
+                    0 => Fr::from(accs[row]),
+                    _ => Fr::from(values[row]),
+                },
+                &|_, _| unreachable!(),
+                &|_, _| unreachable!(),
+                &|_| unreachable!(),
+            );
+            // The last row wraps around to the first one, so it is not checked.
+            evaluations[..4].to_vec()
+        };
+
+        let expr = super::running_sum(acc, value.cur());
+        assert_eq!(check(&expr, [0, 1, 3, 6, 10]), vec![Fr::ZERO; 4]);
+        assert_eq!(
+            check(&expr, [0, 1, 4, 6, 10]),
+            vec![Fr::ZERO, Fr::ONE, -Fr::ONE, Fr::ZERO]
+        );
+
+        let expr = super::running_product(acc, value.cur());
+        assert_eq!(check(&expr, [1, 1, 2, 6, 24]), vec![Fr::ZERO; 4]);
+        assert_eq!(
+            check(&expr, [1, 1, 3, 6, 24]),
+            vec![Fr::ZERO, Fr::ONE, -Fr::from(3), Fr::ZERO]
+        );
+    }
+
+    #[test]
+    fn as_constant() {
+        let a = Column::new(0, Advice::default());
+        let constant = |n: u64| Expression::<Fr>::Constant(Fr::from(n));
+
+        let expr = Expression::Negated(Box::new(constant(5)));
+        assert_eq!(expr.as_constant(), Some(-Fr::from(5)));
+        assert!(expr.is_constant() && !expr.is_zero());
+
+        let expr = (constant(2) + constant(3)) * constant(4) * Fr::from(5) - constant(100);
+        assert_eq!(expr.as_constant(), Some(Fr::ZERO));
+        assert!(expr.is_zero());
 
-        panic!("get_fixed_query_index called for non-existent query");
+        // Multiplying by a constant zero cancels the queries out.
+        let expr = a.cur::<Fr>() * constant(0) + a.next::<Fr>() * Fr::ZERO;
+        assert!(expr.is_zero());
+
+        let expr = a.cur::<Fr>() + constant(0);
+        assert_eq!(expr.as_constant(), None);
+        assert!(!expr.is_constant() && !expr.is_zero());
+        let theta = Expression::<Fr>::Challenge(Challenge { index: 0, phase: 0 });
+        assert!(!(theta * Fr::from(2)).is_constant());
     }
 
-    pub(crate) fn get_instance_query_index(&self, column: Column<Instance>, at: Rotation) -> usize {
-        for (index, instance_query) in self.instance_queries.iter().enumerate() {
-            if instance_query == &(column, at) {
-                return index;
-            }
-        }
+    #[test]
+    fn hash_consistent_with_eq() {
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let build = || (a.cur::<Fr>() + b.next()) * a.cur() * Fr::from(3);
 
-        panic!("get_instance_query_index called for non-existent query");
+        let mut set = HashSet::new();
+        assert!(set.insert(build()));
+        assert!(!set.insert(build()));
+        assert!(set.insert(build() + Expression::Constant(Fr::ONE)));
+        assert!(set.insert((b.next::<Fr>() + a.cur()) * a.cur() * Fr::from(3)));
+        assert_eq!(set.len(), 3);
+        assert!(set.contains(&build()));
     }
 
-    pub fn get_any_query_index(&self, column: Column<Any>, at: Rotation) -> usize {
-        match column.column_type() {
-            Any::Advice(_) => {
-                self.get_advice_query_index(Column::<Advice>::try_from(column).unwrap(), at)
-            }
-            Any::Fixed => {
-                self.get_fixed_query_index(Column::<Fixed>::try_from(column).unwrap(), at)
-            }
-            Any::Instance => {
-                self.get_instance_query_index(Column::<Instance>::try_from(column).unwrap(), at)
-            }
-        }
+    #[test]
+    fn positive_negative_rotation() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        meta.create_gate("gate", |meta| {
+            let a_prev = meta.query_advice(a, Rotation::prev());
+            let a_next = meta.query_advice(a, Rotation::next());
+            vec![a_next - a_prev]
+        });
+        let expr = &meta.gates[0].polynomials()[0];
+        assert_eq!(expr.max_positive_rotation(), 1);
+        assert_eq!(expr.min_negative_rotation(), -1);
+
+        // Only forward reads.
+        let a = Column::new(0, Advice::default());
+        let expr: Expression<Fr> = a.rot(2) * a.rot(3);
+        assert_eq!(expr.max_positive_rotation(), 3);
+        assert_eq!(expr.min_negative_rotation(), 0);
+        let expr = Expression::Constant(Fr::ONE);
+        assert_eq!(expr.max_positive_rotation(), 0);
+        assert_eq!(expr.min_negative_rotation(), 0);
     }
 
-    /// Sets the minimum degree required by the circuit, which can be set to a
-    /// larger amount than actually needed. This can be used, for example, to
-    /// force the permutation argument to involve more columns in the same set.
-    pub fn set_minimum_degree(&mut self, degree: usize) {
-        self.minimum_degree = Some(degree);
+    #[test]
+    fn identifier_advice_phase() {
+        let first = Column::new(0, Advice::new(0));
+        let second = Column::new(0, Advice::new(1));
+
+        let expr: Expression<Fr> = first.next();
+        assert_eq!(expr.identifier(), "advice[0][1]");
+        let expr: Expression<Fr> = second.next();
+        assert_eq!(expr.identifier(), "advice[0][1][1]");
+
+        let expr: Expression<Fr> = first.cur() * second.cur();
+        assert_ne!(
+            expr.identifier(),
+            (first.cur::<Fr>() * first.cur()).identifier()
+        );
+        assert_eq!(
+            expr.template_identifier(),
+            "(advice[$0][0]*advice[$0][0][1])"
+        );
     }
 
-    /// Creates a new gate.
-    ///
-    /// # Panics
-    ///
-    /// A gate is required to contain polynomial constraints. This method will panic if
-    /// `constraints` returns an empty iterator.
-    pub fn create_gate<C: Into<Constraint<F>>, Iter: IntoIterator<Item = C>, S: AsRef<str>>(
-        &mut self,
-        name: S,
-        constraints: impl FnOnce(&mut VirtualCells<'_, F>) -> Iter,
-    ) {
-        let mut cells = VirtualCells::new(self);
-        let constraints = constraints(&mut cells);
-        let (constraint_names, polys): (_, Vec<_>) = constraints
-            .into_iter()
-            .map(|c| c.into())
-            .map(|mut c: Constraint<F>| {
-                c.poly.query_cells(&mut cells);
-                (c.name, c.poly)
-            })
-            .unzip();
+    #[test]
+    fn linear_combination() {
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let c = Column::new(2, Advice::default());
+        let values = [Fr::from(5), Fr::from(7), Fr::from(11)];
+
+        let expr = Expression::linear_combination([
+            (Fr::from(2), a.cur()),
+            (Fr::ZERO, c.cur()),
+            (Fr::from(3), b.cur()),
+        ]);
+        let value = expr.evaluate(
+            &|constant| constant,
+            &|_| unreachable!(),
+            &|_| unreachable!(),
+            &|query| values[query.column_index],
+            &|_| unreachable!(),
+            &|_| unreachable!(),
+            &|a| -a,
+            &|a, b| a + b,
+            &|a, b| a * b,
+            &|a, f| a * f,
+        );
+        assert_eq!(value, Fr::from(2 * 5 + 3 * 7));
+        // The zero coefficient term is dropped.
+        assert_eq!(expr.distinct_columns(), 2);
+        assert_eq!(expr.to_string(), "2*a0[0] + 3*a1[0]");
 
-        let queried_selectors = cells.queried_selectors;
-        let queried_cells = cells.queried_cells;
+        // A coefficient of one leaves the term unscaled.
+        let expr = Expression::<Fr>::linear_combination([(Fr::ONE, a.cur())]);
+        assert_eq!(expr, a.cur());
 
-        assert!(
-            !polys.is_empty(),
-            "Gates must contain at least one constraint."
+        let empty = Expression::<Fr>::linear_combination([]);
+        assert_eq!(empty, Expression::Constant(Fr::ZERO));
+    }
+
+    #[test]
+    fn balanced_sum() {
+        let terms = (1..=1000u64).map(|i| Expression::Constant(Fr::from(i)));
+        let sum = Expression::balanced_sum(terms);
+
+        let value = sum.evaluate(
+            &|constant| constant,
+            &|_| unreachable!(),
+            &|_| unreachable!(),
+            &|_| unreachable!(),
+            &|_| unreachable!(),
+            &|_| unreachable!(),
+            &|a| -a,
+            &|a, b| a + b,
+            &|a, b| a * b,
+            &|a, f| a * f,
         );
+        assert_eq!(value, Fr::from(500500));
+        // ceil(log2(1000)) levels of sums above the leaves.
+        assert_eq!(sum.depth(), 11);
+
+        let empty = Expression::<Fr>::balanced_sum(vec![]);
+        assert_eq!(
+            empty.identifier(),
+            Expression::Constant(Fr::ZERO).identifier()
+        );
+    }
 
-        self.gates.push(Gate {
-            name: name.as_ref().to_string(),
-            constraint_names,
-            polys,
-            queried_selectors,
-            queried_cells,
-        });
+    #[test]
+    fn balanced_product() {
+        let columns: Vec<_> = (0..6).map(|i| Column::new(i, Advice::default())).collect();
+        let product = Expression::balanced_product(columns.iter().map(|column| column.cur::<Fr>()));
+
+        let value = product.evaluate(
+            &|constant| constant,
+            &|_| unreachable!(),
+            &|_| unreachable!(),
+            &|query| Fr::from(query.column_index as u64 + 1),
+            &|_| unreachable!(),
+            &|_| unreachable!(),
+            &|a| -a,
+            &|a, b| a + b,
+            &|a, b| a * b,
+            &|a, f| a * f,
+        );
+        assert_eq!(value, Fr::from(720));
+        assert_eq!(product.degree(), 6);
+        assert_eq!(product.depth(), 4);
+
+        let empty = Expression::<Fr>::balanced_product(vec![]);
+        assert_eq!(
+            empty.identifier(),
+            Expression::Constant(Fr::ONE).identifier()
+        );
     }
 
-    /// This will compress selectors together depending on their provided
-    /// assignments. This `ConstraintSystem` will then be modified to add new
-    /// fixed columns (representing the actual selectors) and will return the
-    /// polynomials for those columns. Finally, an internal map is updated to
-    /// find which fixed column corresponds with a given `Selector`.
-    ///
-    /// Do not call this twice. Yes, this should be a builder pattern instead.
-    pub fn compress_selectors(mut self, selectors: Vec<Vec<bool>>) -> (Self, Vec<Vec<F>>) {
-        // The number of provided selector assignments must be the number we
-        // counted for this constraint system.
-        assert_eq!(selectors.len(), self.num_selectors);
+    #[test]
+    fn read_argument_rejects_malformed_input() {
+        use crate::helpers::SerdeFormat;
+        use std::io;
+
+        let read = |bytes: Vec<u8>| {
+            super::read_argument::<Fr, _>(&mut &bytes[..], SerdeFormat::RawBytes)
+                .map(|_| ())
+                .unwrap_err()
+                .kind()
+        };
 
-        // Compute the maximal degree of every selector. We only consider the
-        // expressions in gates, as lookup arguments cannot support simple
-        // selectors. Selectors that are complex or do not appear in any gates
-        // will have degree zero.
-        let mut degrees = vec![0; selectors.len()];
-        for expr in self.gates.iter().flat_map(|gate| gate.polys.iter()) {
-            if let Some(selector) = expr.extract_simple_selector() {
-                degrees[selector.0] = max(degrees[selector.0], expr.degree());
-            }
-        }
+        // A huge name length is rejected before allocating.
+        assert_eq!(
+            read(u32::MAX.to_le_bytes().to_vec()),
+            io::ErrorKind::InvalidData
+        );
+        // A name shorter than its length.
+        let mut bytes = 10u32.to_le_bytes().to_vec();
+        bytes.extend(b"abc");
+        assert_eq!(read(bytes), io::ErrorKind::UnexpectedEof);
+        // A deeply nested chain of negations.
+        let mut bytes = [0u32.to_le_bytes(), 1u32.to_le_bytes()].concat();
+        bytes.extend(vec![5u8; 100_000]);
+        assert_eq!(read(bytes), io::ErrorKind::InvalidData);
+
+        // Writing accepts exactly the expressions that reading does.
+        let a = Column::new(0, Advice::default());
+        let nested = |depth: usize| {
+            (1..depth).fold(a.cur::<Fr>(), |acc, _| Expression::Negated(Box::new(acc)))
+        };
+        let write = |expr: &Expression<Fr>| {
+            let mut bytes = vec![];
+            super::write_argument(
+                "deep",
+                [std::slice::from_ref(expr), &[]],
+                &mut bytes,
+                SerdeFormat::RawBytes,
+            )
+            .map(|_| bytes)
+        };
+        let expr = nested(super::MAX_EXPRESSION_DEPTH);
+        let bytes = write(&expr).unwrap();
+        let (name, [exprs, _]) =
+            super::read_argument::<Fr, _>(&mut &bytes[..], SerdeFormat::RawBytes).unwrap();
+        assert_eq!(name, "deep");
+        assert_eq!(exprs, vec![expr]);
+        assert_eq!(
+            write(&nested(super::MAX_EXPRESSION_DEPTH + 1))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
 
-        // We will not increase the degree of the constraint system, so we limit
-        // ourselves to the largest existing degree constraint.
-        let max_degree = self.degree();
+    #[test]
+    fn expression_mid_roundtrip() {
+        use halo2_middleware::circuit::ExpressionMid;
+
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let f = meta.fixed_column();
+        let i = meta.instance_column();
+        let theta = meta.challenge_usable_after(FirstPhase);
+        meta.create_gate("gate", |meta| {
+            let a = meta.query_advice(a, Rotation::next());
+            let f = meta.query_fixed(f, Rotation::cur());
+            let i = meta.query_instance(i, Rotation::prev());
+            let theta = meta.query_challenge(theta);
+            vec![(a * f - i) * theta + Expression::Constant(Fr::from(5)) * Fr::from(3)]
+        });
+        let expr = meta.gates[0].polynomials()[0].clone();
+
+        let query_indices = |expr: &Expression<Fr>| {
+            expr.evaluate(
+                &|_| vec![],
+                &|_| vec![],
+                &|query| vec![query.index],
+                &|query| vec![query.index],
+                &|query| vec![query.index],
+                &|_| vec![],
+                &|a| a,
+                &|a, b| [a, b].concat(),
+                &|a, b| [a, b].concat(),
+                &|a, _| a,
+            )
+        };
+        assert_eq!(query_indices(&expr), vec![Some(0), Some(0), Some(0)]);
 
-        let mut new_columns = vec![];
-        let (polys, selector_assignment) = compress_selectors::process(
-            selectors
-                .into_iter()
-                .zip(degrees)
-                .enumerate()
-                .map(
-                    |(i, (activations, max_degree))| compress_selectors::SelectorDescription {
-                        selector: i,
-                        activations,
-                        max_degree,
-                    },
-                )
-                .collect(),
-            max_degree,
-            || {
-                let column = self.fixed_column();
-                new_columns.push(column);
-                Expression::Fixed(FixedQuery {
-                    index: Some(self.query_fixed_index(column, Rotation::cur())),
-                    column_index: column.index,
-                    rotation: Rotation::cur(),
-                })
-            },
-        );
+        let mid: ExpressionMid<Fr> = expr.clone().into();
+        let back: Expression<Fr> = mid.clone().into();
+        assert_eq!(query_indices(&back), vec![None, None, None]);
+        assert_eq!(back.identifier(), expr.identifier());
+        assert_eq!(back.degree(), expr.degree());
+        assert_eq!(ExpressionMid::from(back), mid);
+    }
 
-        let mut selector_map = vec![None; selector_assignment.len()];
-        let mut selector_replacements = vec![None; selector_assignment.len()];
-        for assignment in selector_assignment {
-            selector_replacements[assignment.selector] = Some(assignment.expression);
-            selector_map[assignment.selector] = Some(new_columns[assignment.combination_index]);
-        }
+    #[test]
+    fn roundtrip_v2() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        let f = meta.fixed_column();
+        meta.enable_equality(a);
+        meta.enable_equality(b);
+        meta.create_gate("mul", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::next());
+            let f = meta.query_fixed(f, Rotation::cur());
+            vec![f * (a * b)]
+        });
+        meta.lookup_any("lookup", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let f = meta.query_fixed(f, Rotation::cur());
+            vec![(a, f)]
+        });
 
-        self.selector_map = selector_map
-            .into_iter()
-            .map(|a| a.unwrap())
-            .collect::<Vec<_>>();
-        let selector_replacements = selector_replacements
-            .into_iter()
-            .map(|a| a.unwrap())
-            .collect::<Vec<_>>();
-        self.replace_selectors_with_fixed(&selector_replacements);
+        let roundtrip = meta.roundtrip_v2();
+        assert!(meta.is_compatible_with(&roundtrip));
+        assert_eq!(roundtrip.gates[0].name(), "mul");
+        assert_eq!(roundtrip.lookups[0].name(), "lookup");
 
-        (self, polys)
+        let twice = roundtrip.roundtrip_v2();
+        assert!(meta.is_compatible_with(&twice));
+        assert_eq!(twice.gates[0].name(), "mul");
+        assert_eq!(twice.gates[0].constraint_name(0), "");
+        let cs2: ConstraintSystemV2Backend<Fr> = twice.into();
+        assert_eq!(cs2.gates[0].name(), "mul");
     }
 
-    /// Does not combine selectors and directly replaces them everywhere with fixed columns.
-    pub fn directly_convert_selectors_to_fixed(
-        mut self,
-        selectors: Vec<Vec<bool>>,
-    ) -> (Self, Vec<Vec<F>>) {
-        // The number of provided selector assignments must be the number we
-        // counted for this constraint system.
-        assert_eq!(selectors.len(), self.num_selectors);
+    #[test]
+    fn roundtrip_v2_minimum_degree() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        meta.create_gate("square", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![a.clone() * a]
+        });
+        assert_eq!(meta.degree(), 3);
 
-        let (polys, selector_replacements): (Vec<_>, Vec<_>) = selectors
-            .into_iter()
-            .map(|selector| {
-                let poly = selector
-                    .iter()
-                    .map(|b| if *b { F::ONE } else { F::ZERO })
-                    .collect::<Vec<_>>();
-                let column = self.fixed_column();
-                let rotation = Rotation::cur();
-                let expr = Expression::Fixed(FixedQuery {
-                    index: Some(self.query_fixed_index(column, rotation)),
-                    column_index: column.index,
-                    rotation,
-                });
-                (poly, expr)
-            })
-            .unzip();
+        meta.set_minimum_degree(8);
+        let cs2: ConstraintSystemV2Backend<Fr> = meta.clone().into();
+        assert_eq!(cs2.minimum_degree, Some(8));
+        let roundtrip: ConstraintSystem<Fr> = cs2.into();
+        assert_eq!(roundtrip.minimum_degree, Some(8));
+        assert_eq!(roundtrip.degree(), 8);
+    }
 
-        self.replace_selectors_with_fixed(&selector_replacements);
-        self.num_selectors = 0;
+    #[test]
+    fn advice_query_index_range() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        let c = meta.advice_column();
+        meta.create_gate("gate", |meta| {
+            let a_cur = meta.query_advice(a, Rotation::cur());
+            let a_next = meta.query_advice(a, Rotation::next());
+            let b_cur = meta.query_advice(b, Rotation::cur());
+            let c_cur = meta.query_advice(c, Rotation::cur());
+            let b_next = meta.query_advice(b, Rotation::next());
+            vec![a_cur * a_next + b_cur * c_cur - b_next]
+        });
 
-        (self, polys)
+        assert_eq!(meta.advice_query_index_range(a.index()), Some(0..2));
+        assert_eq!(meta.advice_query_index_range(c.index()), Some(3..4));
+        // The queries of `b` are interleaved with the query of `c`.
+        assert_eq!(meta.advice_query_index_range(b.index()), None);
     }
 
-    fn replace_selectors_with_fixed(&mut self, selector_replacements: &[Expression<F>]) {
-        fn replace_selectors<F: Field>(
-            expr: &mut Expression<F>,
-            selector_replacements: &[Expression<F>],
-            must_be_nonsimple: bool,
-        ) {
-            *expr = expr.evaluate(
-                &|constant| Expression::Constant(constant),
-                &|selector| {
-                    if must_be_nonsimple {
-                        // Simple selectors are prohibited from appearing in
-                        // expressions in the lookup argument by
-                        // `ConstraintSystem`.
-                        assert!(!selector.is_simple());
-                    }
-
-                    selector_replacements[selector.0].clone()
-                },
-                &|query| Expression::Fixed(query),
-                &|query| Expression::Advice(query),
-                &|query| Expression::Instance(query),
-                &|challenge| Expression::Challenge(challenge),
-                &|a| -a,
-                &|a, b| a + b,
-                &|a, b| a * b,
-                &|a, f| a * f,
-            );
-        }
+    #[test]
+    fn value_bound() {
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let c = Column::new(2, Advice::default());
+        let expr = a.cur::<Fr>() * b.cur() + c.cur();
+
+        // `a` is a byte, `b` a bit and `c` a 16-bit limb.
+        let bound = expr.value_bound(&|leaf| match leaf {
+            Expression::Advice(query) => [255, 1, 65535][query.column_index],
+            _ => unreachable!(),
+        });
+        assert_eq!(bound, 255 + 65535);
 
-        // Substitute selectors for the real fixed columns in all gates
-        for expr in self.gates.iter_mut().flat_map(|gate| gate.polys.iter_mut()) {
-            replace_selectors(expr, selector_replacements, false);
-        }
+        let saturated = (a.cur::<Fr>() * a.cur()).value_bound(&|_| u64::MAX);
+        assert_eq!(saturated, u64::MAX);
+    }
 
-        // Substitute non-simple selectors for the real fixed columns in all
-        // lookup expressions
-        for expr in self.lookups.iter_mut().flat_map(|lookup| {
-            lookup
-                .input_expressions
-                .iter_mut()
-                .chain(lookup.table_expressions.iter_mut())
-        }) {
-            replace_selectors(expr, selector_replacements, true);
-        }
+    #[test]
+    fn gate_metadata() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        meta.create_gate("gate", |meta| vec![meta.query_advice(a, Rotation::cur())]);
 
-        for expr in self.shuffles.iter_mut().flat_map(|shuffle| {
-            shuffle
-                .input_expressions
-                .iter_mut()
-                .chain(shuffle.shuffle_expressions.iter_mut())
-        }) {
-            replace_selectors(expr, selector_replacements, true);
-        }
+        let gate = &mut meta.gates[0];
+        assert_eq!(gate.get_metadata("source"), None);
+        assert_eq!(gate.set_metadata("source", "gadget.rs:42"), None);
+        assert_eq!(gate.get_metadata("source"), Some("gadget.rs:42"));
     }
 
-    /// Allocate a new (simple) selector. Simple selectors cannot be added to
-    /// expressions nor multiplied by other expressions containing simple
-    /// selectors. Also, simple selectors may not appear in lookup argument
-    /// inputs.
-    pub fn selector(&mut self) -> Selector {
-        let index = self.num_selectors;
-        self.num_selectors += 1;
-        Selector(index, true)
+    #[test]
+    fn unnamed_constraints() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        meta.create_gate("gate", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![
+                (
+                    "boolean",
+                    a.clone() * (a.clone() - Expression::Constant(Fr::ONE)),
+                ),
+                ("", a),
+            ]
+        });
+
+        assert_eq!(meta.unnamed_constraints(), vec![(0, 1)]);
+        assert_eq!(meta.max_constraint_name_len(), "gate:boolean".len());
     }
 
-    /// Allocate a new complex selector that can appear anywhere
-    /// within expressions.
-    pub fn complex_selector(&mut self) -> Selector {
-        let index = self.num_selectors;
-        self.num_selectors += 1;
-        Selector(index, false)
+    #[test]
+    fn gates_exceeding() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        meta.create_gate("degree 2", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![a.clone() * a]
+        });
+        meta.create_gate("degrees 3 and 5", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![a.clone().pow(3), a.pow(5)]
+        });
+
+        assert_eq!(meta.gates_exceeding(3), vec![(1, 1, 5)]);
+        assert!(meta.gates_exceeding(5).is_empty());
     }
 
-    /// Allocates a new fixed column that can be used in a lookup table.
-    pub fn lookup_table_column(&mut self) -> TableColumn {
-        TableColumn {
-            inner: self.fixed_column(),
+    #[test]
+    fn query_constructors() {
+        let fixed = Expression::<Fr>::fixed(2, Rotation::next());
+        assert_eq!(fixed, Column::new(2, Fixed).next());
+        let advice = Expression::<Fr>::advice(1, Rotation::prev(), 1);
+        assert_eq!(advice, Column::new(1, Advice::new(1)).prev());
+        let instance = Expression::<Fr>::instance(0, Rotation::cur());
+        assert_eq!(instance, Column::new(0, Instance).cur());
+
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        meta.create_gate("gate", |_| {
+            vec![Expression::advice(a.index(), Rotation::cur(), 0)]
+        });
+        match &meta.gates[0].polynomials()[0] {
+            Expression::Advice(query) => assert_eq!(query.index, Some(0)),
+            _ => panic!("expected an advice query"),
         }
+        assert_eq!(meta.advice_queries, vec![(a, Rotation::cur())]);
     }
 
-    /// Annotate a Lookup column.
-    pub fn annotate_lookup_column<A, AR>(&mut self, column: TableColumn, annotation: A)
-    where
-        A: Fn() -> AR,
-        AR: Into<String>,
-    {
-        // We don't care if the table has already an annotation. If it's the case we keep the new one.
-        self.general_column_annotations.insert(
-            metadata::Column::from((Any::Fixed, column.inner().index)),
-            annotation().into(),
-        );
-    }
+    #[test]
+    fn ungated_gates() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let q = meta.selector();
+        let f = meta.fixed_column();
+        meta.create_gate("selector", |meta| {
+            let q = meta.query_selector(q);
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![q * a]
+        });
+        meta.create_gate("fixed", |meta| {
+            let f = meta.query_fixed(f, Rotation::cur());
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![f * a]
+        });
+        meta.add_gate_expr("bare", a.cur() * a.next());
+        meta.add_global_gate("global", a.cur() - a.next());
+        // Querying a fixed column is not enough to gate the constraint.
+        meta.create_gate("fixed operand", |meta| {
+            let f = meta.query_fixed(f, Rotation::next());
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![a * f]
+        });
 
-    /// Annotate an Instance column.
-    pub fn annotate_lookup_any_column<A, AR, T>(&mut self, column: T, annotation: A)
-    where
-        A: Fn() -> AR,
-        AR: Into<String>,
-        T: Into<Column<Any>>,
-    {
-        let col_any = column.into();
-        // We don't care if the table has already an annotation. If it's the case we keep the new one.
-        self.general_column_annotations.insert(
-            metadata::Column::from((col_any.column_type, col_any.index)),
-            annotation().into(),
-        );
+        assert!(!meta.gates[2].is_global());
+        assert!(meta.gates[3].is_global());
+        assert_eq!(meta.ungated_gates(), vec![2, 4]);
     }
 
-    /// Allocate a new fixed column
-    pub fn fixed_column(&mut self) -> Column<Fixed> {
-        let tmp = Column {
-            index: self.num_fixed_columns,
-            column_type: Fixed,
-        };
-        self.num_fixed_columns += 1;
-        tmp
+    #[test]
+    fn gates_by_cost() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        meta.create_gate("cheap", |meta| vec![meta.query_advice(a, Rotation::cur())]);
+        meta.create_gate("expensive", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![a.clone() * a.clone() * a]
+        });
+        meta.create_gate("moderate", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![a.clone() + a]
+        });
+
+        assert_eq!(meta.gates_by_cost(), vec![(1, 63), (2, 17), (0, 1)]);
     }
 
-    /// Allocate a new unblinded advice column at `FirstPhase`
-    pub fn unblinded_advice_column(&mut self) -> Column<Advice> {
-        self.unblinded_advice_column_in(FirstPhase)
+    #[test]
+    fn distinct_columns() {
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let expr = a.cur::<Fr>() + a.cur() + b.cur();
+        assert_eq!(expr.distinct_columns(), 2);
+
+        let rotated = a.cur::<Fr>() * a.next();
+        assert_eq!(rotated.distinct_columns(), 2);
+    }
+
+    fn render_tree(expr: &Expression<Fr>, iterative: bool) -> String {
+        let closures = (
+            &|c: Fr| format!("{c:?}"),
+            &|s: super::Selector| format!("s{}", s.0),
+            &|q: super::FixedQuery| format!("f{}@{}", q.column_index, q.rotation.0),
+            &|q: super::AdviceQuery| format!("a{}@{}", q.column_index, q.rotation.0),
+            &|q: super::InstanceQuery| format!("i{}@{}", q.column_index, q.rotation.0),
+            &|c: Challenge| format!("c{}", c.index()),
+            &|a: String| format!("(-{a})"),
+            &|a: String, b: String| format!("({a}+{b})"),
+            &|a: String, b: String| format!("({a}*{b})"),
+            &|a: String, f: Fr| format!("({a}*{f:?})"),
+        );
+        let (c, s, f, a, i, ch, neg, sum, prod, scaled) = closures;
+        if iterative {
+            expr.evaluate_iter(c, s, f, a, i, ch, neg, sum, prod, scaled)
+        } else {
+            expr.evaluate(c, s, f, a, i, ch, neg, sum, prod, scaled)
+        }
     }
 
-    /// Allocate a new advice column at `FirstPhase`
-    pub fn advice_column(&mut self) -> Column<Advice> {
-        self.advice_column_in(FirstPhase)
+    fn arb_expression() -> impl Strategy<Value = Expression<Fr>> {
+        let leaf = prop_oneof![
+            any::<u64>().prop_map(|c| Expression::Constant(Fr::from(c))),
+            (0..4usize, -2..3i32)
+                .prop_map(|(i, rot)| Column::new(i, Advice::default()).rot::<Fr>(rot)),
+            (0..4usize, -2..3i32).prop_map(|(i, rot)| Column::new(i, Fixed).rot::<Fr>(rot)),
+            (0..2usize, -2..3i32).prop_map(|(i, rot)| Column::new(i, Instance).rot::<Fr>(rot)),
+            (0..3usize).prop_map(|index| Expression::Challenge(Challenge { index, phase: 0 })),
+        ];
+        leaf.prop_recursive(8, 64, 2, |inner| {
+            prop_oneof![
+                inner.clone().prop_map(|a| Expression::Negated(Box::new(a))),
+                (inner.clone(), inner.clone())
+                    .prop_map(|(a, b)| Expression::Sum(Box::new(a), Box::new(b))),
+                (inner.clone(), inner.clone())
+                    .prop_map(|(a, b)| Expression::Product(Box::new(a), Box::new(b))),
+                (inner, any::<u64>())
+                    .prop_map(|(a, f)| Expression::Scaled(Box::new(a), Fr::from(f))),
+            ]
+        })
     }
 
-    /// Allocate a new unblinded advice column in given phase. This allows for the generation of deterministic commitments to advice columns
-    /// which can be used to split large circuits into smaller ones, whose proofs can then be "joined" together by their common witness commitments.
-    pub fn unblinded_advice_column_in<P: Phase>(&mut self, phase: P) -> Column<Advice> {
-        let phase = phase.to_sealed();
-        if let Some(previous_phase) = phase.prev() {
-            self.assert_phase_exists(
-                previous_phase,
-                format!("Column<Advice> in later phase {phase:?}").as_str(),
-            );
+    proptest! {
+        #[test]
+        fn evaluate_iter_matches_evaluate(expr in arb_expression()) {
+            prop_assert_eq!(render_tree(&expr, true), render_tree(&expr, false));
         }
+    }
 
-        let tmp = Column {
-            index: self.num_advice_columns,
-            column_type: Advice { phase: phase.0 },
-        };
-        self.unblinded_advice_columns.push(tmp.index);
-        self.num_advice_columns += 1;
-        self.num_advice_queries.push(0);
-        self.advice_column_phase.push(phase);
-        tmp
+    #[test]
+    fn evaluate_iter_deep() {
+        const DEPTH: u64 = 100_000;
+
+        // Both the recursive evaluation used as a reference and the recursive `Drop` of
+        // the tree need a large stack.
+        std::thread::Builder::new()
+            .stack_size(1 << 30)
+            .spawn(|| {
+                let a = Column::new(0, Advice::default());
+                let mut expr = a.cur::<Fr>();
+                for i in 0..DEPTH {
+                    expr = match i % 3 {
+                        0 => Expression::Sum(
+                            Box::new(expr),
+                            Box::new(Expression::Constant(Fr::from(i))),
+                        ),
+                        1 => Expression::Product(
+                            Box::new(Expression::Constant(Fr::from(i))),
+                            Box::new(expr),
+                        ),
+                        _ => Expression::Negated(Box::new(expr)),
+                    };
+                }
+
+                let eval = |iterative: bool| {
+                    let closures = (
+                        &|c: Fr| c,
+                        &|_: super::Selector| -> Fr { unreachable!() },
+                        &|_: super::FixedQuery| -> Fr { unreachable!() },
+                        &|_: super::AdviceQuery| Fr::from(7),
+                        &|_: super::InstanceQuery| -> Fr { unreachable!() },
+                        &|_: Challenge| -> Fr { unreachable!() },
+                        &|a: Fr| -a,
+                        &|a: Fr, b: Fr| a + b,
+                        &|a: Fr, b: Fr| a * b,
+                        &|a: Fr, f: Fr| a * f,
+                    );
+                    let (c, s, f, a, i, ch, neg, sum, prod, scaled) = closures;
+                    if iterative {
+                        expr.evaluate_iter(c, s, f, a, i, ch, neg, sum, prod, scaled)
+                    } else {
+                        expr.evaluate(c, s, f, a, i, ch, neg, sum, prod, scaled)
+                    }
+                };
+                assert_eq!(eval(true), eval(false));
+            })
+            .unwrap()
+            .join()
+            .unwrap();
     }
 
-    /// Allocate a new advice column in given phase
-    ///
-    /// # Panics
-    ///
-    /// It panics if previous phase before the given one doesn't have advice column allocated.
-    pub fn advice_column_in<P: Phase>(&mut self, phase: P) -> Column<Advice> {
-        let phase = phase.to_sealed();
-        if let Some(previous_phase) = phase.prev() {
-            self.assert_phase_exists(
-                previous_phase,
-                format!("Column<Advice> in later phase {phase:?}").as_str(),
-            );
+    #[test]
+    fn simplify() {
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let constant = |c: u64| Expression::Constant(Fr::from(c));
+        let eval = |expr: &Expression<Fr>| {
+            expr.evaluate(
+                &|c| c,
+                &|_| unreachable!(),
+                &|_| unreachable!(),
+                &|query| Fr::from(query.column_index as u64 + 3),
+                &|_| unreachable!(),
+                &|_| unreachable!(),
+                &|a| -a,
+                &|a, b| a + b,
+                &|a, b| a * b,
+                &|a, f| a * f,
+            )
+        };
+
+        let exprs = [
+            // a + 0
+            a.cur::<Fr>() + constant(0),
+            // b * 1 + (2 + 3)
+            b.cur::<Fr>() * constant(1) + (constant(2) + constant(3)),
+            // -(-a) * 0 + b
+            Expression::Negated(Box::new(-a.cur::<Fr>())) * Fr::ZERO + b.cur(),
+            // (a * 2) * 3
+            a.cur::<Fr>() * constant(2) * constant(3),
+        ];
+        let expected = [
+            "advice[0][0]".to_string(),
+            format!("(advice[1][0]+{:?})", Fr::from(5)),
+            "advice[1][0]".to_string(),
+            format!("advice[0][0]*{:?}", Fr::from(6)),
+        ];
+        for (expr, expected) in exprs.iter().zip(expected) {
+            let simplified = expr.simplify();
+            assert!(simplified.num_nodes() < expr.num_nodes());
+            assert_eq!(simplified.identifier(), expected);
+            assert_eq!(simplified.simplify().identifier(), simplified.identifier());
+            assert_eq!(eval(&simplified), eval(expr));
         }
+    }
 
-        let tmp = Column {
-            index: self.num_advice_columns,
-            column_type: Advice { phase: phase.0 },
+    #[test]
+    fn fold_scalars() {
+        let a = Column::new(0, Advice::default()).cur::<Fr>();
+        let b = Column::new(1, Advice::default()).cur::<Fr>();
+        let constant = |c: u64| Expression::Constant(Fr::from(c));
+        let eval = |expr: &Expression<Fr>| {
+            expr.evaluate(
+                &|c| c,
+                &|_| unreachable!(),
+                &|_| unreachable!(),
+                &|query| Fr::from(query.column_index as u64 + 3),
+                &|_| unreachable!(),
+                &|_| unreachable!(),
+                &|a| -a,
+                &|a, b| a + b,
+                &|a, b| a * b,
+                &|a, f| a * f,
+            )
         };
-        self.num_advice_columns += 1;
-        self.num_advice_queries.push(0);
-        self.advice_column_phase.push(phase);
-        tmp
+
+        let doubly_scaled = a.clone() * Fr::from(2) * Fr::from(3);
+        let folded = doubly_scaled.fold_scalars();
+        assert_eq!(folded, a.clone() * Fr::from(6));
+        assert!(folded.complexity() < doubly_scaled.complexity());
+        assert!(folded.num_nodes() < doubly_scaled.num_nodes());
+        assert_eq!(eval(&folded), eval(&doubly_scaled));
+
+        let exprs = [
+            constant(2) * b.clone(),
+            (a.clone() * Fr::from(2)) * (b.clone() * Fr::from(3)) + constant(4) * constant(5),
+            -(a.clone() * Fr::from(2) * constant(3)),
+        ];
+        let expected = [
+            format!("advice[1][0]*{:?}", Fr::from(2)),
+            format!(
+                "((advice[0][0]*advice[1][0])*{:?}+{:?})",
+                Fr::from(6),
+                Fr::from(20)
+            ),
+            format!("(-advice[0][0]*{:?})", Fr::from(6)),
+        ];
+        for (expr, expected) in exprs.iter().zip(expected) {
+            let folded = expr.fold_scalars();
+            assert_eq!(folded.identifier(), expected);
+            assert!(folded.complexity() <= expr.complexity());
+            assert_eq!(eval(&folded), eval(expr));
+        }
     }
 
-    /// Allocate a new instance column
-    pub fn instance_column(&mut self) -> Column<Instance> {
-        let tmp = Column {
-            index: self.num_instance_columns,
-            column_type: Instance,
+    #[test]
+    fn push_negations() {
+        let a = Column::new(0, Advice::default()).cur::<Fr>();
+        let b = Column::new(1, Advice::default()).cur::<Fr>();
+        let c = Column::new(2, Advice::default()).cur::<Fr>();
+        let eval = |expr: &Expression<Fr>| {
+            expr.evaluate(
+                &|c| c,
+                &|_| unreachable!(),
+                &|_| unreachable!(),
+                &|query| Fr::from(query.column_index as u64 + 3),
+                &|_| unreachable!(),
+                &|_| unreachable!(),
+                &|a| -a,
+                &|a, b| a + b,
+                &|a, b| a * b,
+                &|a, f| a * f,
+            )
         };
-        self.num_instance_columns += 1;
-        tmp
-    }
 
-    /// Requests a challenge that is usable after the given phase.
-    ///
-    /// # Panics
-    ///
-    /// It panics if the given phase doesn't have advice column allocated.
-    pub fn challenge_usable_after<P: Phase>(&mut self, phase: P) -> Challenge {
-        let phase = phase.to_sealed();
-        self.assert_phase_exists(
-            phase,
-            format!("Challenge usable after phase {phase:?}").as_str(),
+        let negated_sum = -(a.clone() + b.clone());
+        let sum_of_negations = -a.clone() + -b.clone();
+        assert_eq!(
+            negated_sum.push_negations(),
+            sum_of_negations.push_negations()
         );
+        assert_eq!(negated_sum.push_negations(), sum_of_negations);
 
-        let tmp = Challenge {
-            index: self.num_challenges,
-            phase: phase.0,
-        };
-        self.num_challenges += 1;
-        self.challenge_phase.push(phase);
-        tmp
-    }
-
-    /// Helper funciotn to assert phase exists, to make sure phase-aware resources
-    /// are allocated in order, and to avoid any phase to be skipped accidentally
-    /// to cause unexpected issue in the future.
-    fn assert_phase_exists(&self, phase: sealed::Phase, resource: &str) {
-        self.advice_column_phase
-            .iter()
-            .find(|advice_column_phase| **advice_column_phase == phase)
-            .unwrap_or_else(|| {
-                panic!(
-                    "No Column<Advice> is used in phase {phase:?} while allocating a new {resource:?}"
-                )
-            });
+        let exprs = [
+            negated_sum,
+            -(-(a.clone() * b.clone())),
+            -(a.clone() * b.clone() + c.clone() * Fr::from(2)),
+            -(Expression::Constant(Fr::from(5)) - c.clone()),
+        ];
+        let expected = [
+            "((-advice[0][0])+(-advice[1][0]))".to_string(),
+            "(advice[0][0]*advice[1][0])".to_string(),
+            format!(
+                "(((-advice[0][0])*advice[1][0])+advice[2][0]*{:?})",
+                -Fr::from(2)
+            ),
+            format!("({:?}+advice[2][0])", -Fr::from(5)),
+        ];
+        for (expr, expected) in exprs.iter().zip(expected) {
+            let normalized = expr.push_negations();
+            assert_eq!(normalized.identifier(), expected);
+            assert_eq!(eval(&normalized), eval(expr));
+        }
     }
 
-    /// Returns the list of phases
-    pub fn phases(&self) -> impl Iterator<Item = sealed::Phase> {
-        let max_phase = self
-            .advice_column_phase
-            .iter()
-            .max()
-            .map(|phase| phase.0)
-            .unwrap_or_default();
-        (0..=max_phase).map(sealed::Phase)
+    #[test]
+    fn min_k() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        meta.create_gate("gate", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![a.clone() * a]
+        });
+        assert_eq!(meta.minimum_rows(), 8);
+        // Row-dominated: 100 + 8 rows need 2^7 = 128.
+        assert_eq!(meta.min_k(100), 7);
+        assert_eq!(meta.min_k(120), 7);
+        assert_eq!(meta.min_k(121), 8);
+
+        meta.create_gate("high degree", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![a.pow(40)]
+        });
+        // Degree-dominated: 8 rows would fit in 2^3, but degree 40 needs 2^6 = 64.
+        assert_eq!(meta.degree(), 40);
+        assert_eq!(meta.min_k(0), 6);
     }
 
-    /// Compute the degree of the constraint system (the maximum degree of all
-    /// constraints).
-    pub fn degree(&self) -> usize {
-        // The permutation argument will serve alongside the gates, so must be
-        // accounted for.
-        let mut degree = self.permutation.required_degree();
-
-        // The lookup argument also serves alongside the gates and must be accounted
-        // for.
-        degree = std::cmp::max(
-            degree,
-            self.lookups
-                .iter()
-                .map(|l| l.required_degree())
-                .max()
-                .unwrap_or(1),
+    #[test]
+    fn apply_rules() {
+        let x = Column::new(0, Advice::default());
+        let one = Expression::Constant(Fr::ONE);
+        let rules = [RewriteRule {
+            pattern: x.cur::<Fr>() * one.clone(),
+            replacement: x.cur(),
+        }];
+
+        let b = Column::new(1, Advice::default());
+        let c = Column::new(2, Advice::default());
+        let expr = b.cur::<Fr>() * one.clone() + c.cur::<Fr>() * one.clone();
+        assert_eq!(
+            expr.apply_rules(&rules).identifier(),
+            "(advice[1][0]+advice[2][0])"
         );
 
-        // The lookup argument also serves alongside the gates and must be accounted
-        // for.
-        degree = std::cmp::max(
-            degree,
-            self.shuffles
-                .iter()
-                .map(|l| l.required_degree())
-                .max()
-                .unwrap_or(1),
+        // The rotation is part of the template, so `c@next * 1` is left unchanged.
+        let rotated = c.next::<Fr>() * one;
+        assert_eq!(
+            rotated.apply_rules(&rules).identifier(),
+            rotated.identifier()
         );
+    }
 
-        // Account for each gate to ensure our quotient polynomial is the
-        // correct degree and that our extended domain is the right size.
-        degree = std::cmp::max(
-            degree,
-            self.gates
-                .iter()
-                .flat_map(|gate| gate.polynomials().iter().map(|poly| poly.degree()))
-                .max()
-                .unwrap_or(0),
-        );
+    #[cfg(all(feature = "serde", feature = "derive_serde"))]
+    #[test]
+    fn serde_round_trip() {
+        use halo2_middleware::circuit::ExpressionMid;
+
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column_in(FirstPhase);
+        let b = meta.advice_column_in(SecondPhase);
+        let f = meta.fixed_column();
+        let i = meta.instance_column();
+        let theta = meta.challenge_usable_after(FirstPhase);
+        meta.create_gate("mixed", |meta| {
+            let a = meta.query_advice(a, Rotation::prev());
+            let b = meta.query_advice(b, Rotation::cur());
+            let f = meta.query_fixed(f, Rotation::next());
+            let i = meta.query_instance(i, Rotation::cur());
+            let theta = meta.query_challenge(theta);
+            vec![f * (a * Fr::from(3) - b) + theta * i + Expression::Constant(Fr::from(7))]
+        });
+        let expr = meta.gates[0].polynomials()[0].clone();
 
-        std::cmp::max(degree, self.minimum_degree.unwrap_or(1))
+        let json = serde_json::to_string(&expr).unwrap();
+        let read: Expression<Fr> = serde_json::from_str(&json).unwrap();
+        assert_eq!(read, expr);
+
+        let expr_mid: ExpressionMid<Fr> = expr.into();
+        let json = serde_json::to_string(&expr_mid).unwrap();
+        let read: ExpressionMid<Fr> = serde_json::from_str(&json).unwrap();
+        assert_eq!(read, expr_mid);
     }
 
-    /// Compute the number of blinding factors necessary to perfectly blind
-    /// each of the prover's witness polynomials.
-    pub fn blinding_factors(&self) -> usize {
-        // All of the prover's advice columns are evaluated at no more than
-        let factors = *self.num_advice_queries.iter().max().unwrap_or(&1);
-        // distinct points during gate checks.
+    #[test]
+    fn add_gate_expr() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        let (a_cur, b_next): (Expression<Fr>, Expression<Fr>) = (a.cur(), b.next());
 
-        // - The permutation argument witness polynomials are evaluated at most 3 times.
-        // - Each lookup argument has independent witness polynomials, and they are
-        //   evaluated at most 2 times.
-        let factors = std::cmp::max(3, factors);
+        meta.add_gate_expr("square", a_cur.clone().square())
+            .add_gate_expr("copy", a_cur - b_next);
 
-        // Each polynomial is evaluated at most an additional time during
-        // multiopen (at x_3 to produce q_evals):
-        let factors = factors + 1;
+        assert_eq!(meta.gates().len(), 2);
+        assert_eq!(meta.gates()[1].name(), "copy");
+        assert_eq!(meta.advice_queries().len(), 2);
+    }
 
-        // h(x) is derived by the other evaluations so it does not reveal
-        // anything; in fact it does not even appear in the proof.
+    #[test]
+    fn render() {
+        let advice_names = HashMap::from([(0, "x")]);
+        let fixed_names = HashMap::from([(1, "sel")]);
+        let name_advice = |index: usize| advice_names[&index].to_string();
+        let name_fixed = |index: usize| fixed_names[&index].to_string();
+        let name_instance = |index: usize| format!("instance{index}");
+        let name_challenge = |index: usize| format!("challenge{index}");
+
+        let x = Column::new(0, Advice::default());
+        let sel = Column::new(1, Fixed);
+        let expr: Expression<Fr> = x.cur() - sel.cur();
+        assert_eq!(
+            expr.render(&name_advice, &name_fixed, &name_instance, &name_challenge),
+            "x - sel"
+        );
 
-        // h(x_3) is also not revealed; the verifier only learns a single
-        // evaluation of a polynomial in x_1 which has h(x_3) and another random
-        // polynomial evaluated at x_3 as coefficients -- this random polynomial
-        // is "random_poly" in the vanishing argument.
+        let expr = sel.cur::<Fr>() * (x.next() + x.prev());
+        assert_eq!(
+            expr.render(&name_advice, &name_fixed, &name_instance, &name_challenge),
+            "sel * (x@1 + x@-1)"
+        );
 
-        // Add an additional blinding factor as a slight defense against
-        // off-by-one errors.
-        factors + 1
+        // Constants, scalings and precedence follow the `Display` implementation.
+        let expr = -(x.cur::<Fr>() + sel.cur()) * Fr::from(3) + Expression::Constant(-Fr::ONE);
+        assert_eq!(
+            expr.render(&name_advice, &name_fixed, &name_instance, &name_challenge),
+            "3*-(x + sel) + -1"
+        );
+        assert_eq!(expr.to_string(), "3*-(a0[0] + f1[0]) + -1");
     }
 
-    /// Returns the minimum necessary rows that need to exist in order to
-    /// account for e.g. blinding factors.
-    pub fn minimum_rows(&self) -> usize {
-        self.blinding_factors() // m blinding factors
-            + 1 // for l_{-(m + 1)} (l_last)
-            + 1 // for l_0 (just for extra breathing room for the permutation
-                // argument, to essentially force a separation in the
-                // permutation polynomial between the roles of l_last, l_0
-                // and the interstitial values.)
-            + 1 // for at least one row
+    #[test]
+    fn display() {
+        let s = Expression::<Fr>::Selector(super::Selector(0, false));
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let c = Column::new(2, Fixed);
+        let i = Column::new(0, Instance);
+
+        let expr: Expression<Fr> = s * (a.cur() - b.next()) + c.prev::<Fr>() * Fr::from(3);
+        assert_eq!(expr.to_string(), "s0 * (a0[0] - a1[1]) + 3*f2[-1]");
+
+        // Nested negations and negative constants.
+        let expr: Expression<Fr> = -(-a.cur::<Fr>()) + Expression::Constant(-Fr::ONE);
+        assert_eq!(expr.to_string(), "-(-a0[0]) + -1");
+        let expr: Expression<Fr> = -(a.cur::<Fr>() * b.cur());
+        assert_eq!(expr.to_string(), "-(a0[0] * a1[0])");
+
+        // Parentheses only where precedence requires them.
+        let expr: Expression<Fr> = a.cur::<Fr>() * b.cur() + (a.cur::<Fr>() + b.rot(-2)) * i.cur();
+        assert_eq!(expr.to_string(), "a0[0] * a1[0] + (a0[0] + a1[-2]) * i0[0]");
+        let expr: Expression<Fr> = a.cur::<Fr>() - (b.cur::<Fr>() - c.cur());
+        assert_eq!(expr.to_string(), "a0[0] - (a1[0] - f2[0])");
+
+        // Challenges and large constants.
+        let theta = Expression::<Fr>::Challenge(Challenge { index: 1, phase: 0 });
+        let large = Fr::from(1 << 20);
+        let expr = (theta + i.next()) * large;
+        assert_eq!(expr.to_string(), format!("{large:?}*(challenge1 + i0[1])"));
     }
 
-    /// Returns number of fixed columns
-    pub fn num_fixed_columns(&self) -> usize {
-        self.num_fixed_columns
-    }
+    #[test]
+    fn max_queries_per_column() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let f = meta.fixed_column();
+        meta.create_gate("gate", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let f_prev = meta.query_fixed(f, Rotation::prev());
+            let f_cur = meta.query_fixed(f, Rotation::cur());
+            let f_next = meta.query_fixed(f, Rotation::next());
+            vec![a * (f_prev + f_cur + f_next)]
+        });
 
-    /// Returns number of advice columns
-    pub fn num_advice_columns(&self) -> usize {
-        self.num_advice_columns
+        assert_eq!(meta.max_queries_per_column(), 3);
     }
 
-    /// Returns number of instance columns
-    pub fn num_instance_columns(&self) -> usize {
-        self.num_instance_columns
+    #[test]
+    fn column_annotation_lookup() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        meta.annotate_lookup_any_column(a, || "a");
+
+        let column: Column<Any> = a.into();
+        let key = metadata::Column::from(column);
+        assert_eq!(
+            meta.general_column_annotations()
+                .get(&key)
+                .map(String::as_str),
+            Some("a")
+        );
     }
 
-    /// Returns number of selectors
-    pub fn num_selectors(&self) -> usize {
-        self.num_selectors
-    }
+    #[test]
+    fn subset_with_gates() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        let f = meta.fixed_column();
+        meta.create_gate("a", |meta| vec![meta.query_advice(a, Rotation::next())]);
+        meta.create_gate("b", |meta| {
+            let b = meta.query_advice(b, Rotation::cur());
+            let f = meta.query_fixed(f, Rotation::cur());
+            vec![f * b]
+        });
+        meta.create_gate("f", |meta| vec![meta.query_fixed(f, Rotation::prev())]);
 
-    /// Returns number of challenges
-    pub fn num_challenges(&self) -> usize {
-        self.num_challenges
+        let subset = meta.subset_with_gates(&[1]);
+        assert_eq!(subset.gates().len(), 1);
+        assert_eq!(subset.gates()[0].name(), "b");
+        assert_eq!(subset.num_advice_columns(), 2);
+        assert_eq!(subset.num_fixed_columns(), 1);
+        assert_eq!(subset.advice_queries(), &vec![(b, Rotation::cur())]);
+        assert_eq!(subset.fixed_queries(), &vec![(f, Rotation::cur())]);
+        assert_eq!(subset.num_advice_queries, vec![0, 1]);
     }
 
-    /// Returns phase of advice columns
-    pub fn advice_column_phase(&self) -> Vec<u8> {
-        self.advice_column_phase
-            .iter()
-            .map(|phase| phase.0)
-            .collect()
+    #[test]
+    #[should_panic]
+    fn subset_with_gates_out_of_range() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        meta.add_gate_expr("gate", a.cur());
+        meta.subset_with_gates(&[1]);
     }
 
-    /// Returns phase of challenges
-    pub fn challenge_phase(&self) -> Vec<u8> {
-        self.challenge_phase.iter().map(|phase| phase.0).collect()
+    #[test]
+    fn lookups_with_shared_tables() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        let range = meta.lookup_table_column();
+        let other = meta.lookup_table_column();
+        meta.lookup("a in range", |meta| {
+            vec![(meta.query_advice(a, Rotation::cur()), range)]
+        });
+        meta.lookup("b in other", |meta| {
+            vec![(meta.query_advice(b, Rotation::cur()), other)]
+        });
+        meta.lookup("b in range", |meta| {
+            vec![(meta.query_advice(b, Rotation::cur()), range)]
+        });
+
+        assert_eq!(meta.lookups_with_shared_tables(), vec![vec![0, 2], vec![1]]);
     }
 
-    /// Returns gates
-    pub fn gates(&self) -> &Vec<Gate<F>> {
-        &self.gates
+    #[test]
+    fn substitute_fixed() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        let s = meta.fixed_column();
+        meta.create_gate("gate", |meta| {
+            let s = meta.query_fixed(s, Rotation::cur());
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::cur());
+            vec![s * (a - b)]
+        });
+        let gate = &meta.gates()[0].polynomials()[0];
+        assert_eq!(gate.degree(), 2);
+
+        let substituted = gate.substitute_fixed(&HashMap::from([((s.index(), 0), Fr::ONE)]));
+        let expected = Expression::Product(
+            Box::new(Expression::Constant(Fr::ONE)),
+            Box::new(a.cur() - b.cur()),
+        );
+        assert_eq!(substituted, expected);
+        assert_eq!(substituted.degree(), 1);
+
+        // Queries at other rotations are left untouched.
+        let untouched = gate.substitute_fixed(&HashMap::from([((s.index(), 1), Fr::ONE)]));
+        assert_eq!(untouched.degree(), 2);
     }
 
-    /// Returns general column annotations
-    pub fn general_column_annotations(&self) -> &HashMap<metadata::Column, String> {
-        &self.general_column_annotations
+    #[test]
+    fn constraint_names() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        meta.create_gate("bool", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![a.clone() * (Expression::Constant(Fr::ONE) - a)]
+        });
+        meta.create_gate("pair", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::cur());
+            vec![("sum", a.clone() + b.clone()), ("product", a * b)]
+        });
+
+        assert_eq!(
+            meta.constraint_names(),
+            vec!["bool", "pair:sum", "pair:product"]
+        );
     }
 
-    /// Returns advice queries
-    pub fn advice_queries(&self) -> &Vec<(Column<Advice>, Rotation)> {
-        &self.advice_queries
+    #[test]
+    fn template_identifier() {
+        let advice = |index| Column::new(index, Advice::default());
+        let lhs = advice(0).cur::<Fr>() * advice(1).cur();
+        let rhs = advice(2).cur::<Fr>() * advice(3).cur();
+        assert_ne!(lhs.identifier(), rhs.identifier());
+        assert_eq!(lhs.template_identifier(), rhs.template_identifier());
+        assert_eq!(lhs.template_identifier(), "(advice[$0][0]*advice[$1][0])");
+
+        let squared = advice(2).cur::<Fr>() * advice(2).cur();
+        assert_ne!(lhs.template_identifier(), squared.template_identifier());
     }
 
-    /// Returns instance queries
-    pub fn instance_queries(&self) -> &Vec<(Column<Instance>, Rotation)> {
-        &self.instance_queries
+    #[test]
+    fn lookup_role() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        let c = meta.advice_column();
+        let table = meta.fixed_column();
+        meta.lookup_any("range", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let table = meta.query_fixed(table, Rotation::cur());
+            vec![(a, table)]
+        });
+        meta.lookup_any("dynamic", |meta| {
+            let a = meta.query_advice(a, Rotation::next());
+            let b = meta.query_advice(b, Rotation::cur());
+            vec![(a, b)]
+        });
+        meta.lookup_any("self", |meta| {
+            let b_cur = meta.query_advice(b, Rotation::cur());
+            let b_next = meta.query_advice(b, Rotation::next());
+            vec![(b_next, b_cur)]
+        });
+
+        let table_role = meta.lookup_role(table.into());
+        assert_eq!(table_role, LookupRole::TABLE);
+        assert!(table_role.is_table() && !table_role.is_input());
+        assert_eq!(meta.lookup_role(a.into()), LookupRole::INPUT);
+        let b_role = meta.lookup_role(b.into());
+        assert_eq!(b_role, LookupRole::INPUT | LookupRole::TABLE);
+        assert!(b_role.is_input() && b_role.is_table());
+        assert_eq!(meta.lookup_role(c.into()), LookupRole::NONE);
+        assert!(LookupRole::NONE.contains(LookupRole::NONE));
     }
 
-    /// Returns fixed queries
-    pub fn fixed_queries(&self) -> &Vec<(Column<Fixed>, Rotation)> {
-        &self.fixed_queries
+    #[test]
+    fn validate_constants() {
+        use halo2curves::serde::SerdeObject;
+
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let f = meta.fixed_column();
+        meta.create_gate("scale", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let f = meta.query_fixed(f, Rotation::cur());
+            vec![("scaled", a.clone() * Fr::from(3) - f), ("plain", a)]
+        });
+        meta.lookup_any("lookup", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let f = meta.query_fixed(f, Rotation::cur());
+            vec![(a + Expression::Constant(-Fr::ONE), f)]
+        });
+        assert_eq!(meta.validate_constants(), Ok(()));
+
+        // Montgomery limbs larger than the modulus, as could be read unchecked.
+        let non_canonical = Fr::from_raw_bytes_unchecked(&[0xff; 32]);
+        let mut invalid = meta.clone();
+        invalid.gates[0].polys[1] = invalid.gates[0].polys[1].clone() * non_canonical;
+        let err = invalid.validate_constants().unwrap_err();
+        assert!(
+            err.contains("gate \"scale\" constraint 1 (\"plain\")"),
+            "{err}"
+        );
+
+        // A system rebuilt from the backend representation, as when deserialized.
+        let mut invalid = meta.roundtrip_v2();
+        invalid.gates[1].polys[0] = invalid.gates[1].polys[0].clone() * non_canonical;
+        let err = invalid.validate_constants().unwrap_err();
+        assert!(err.contains("gate \"scale:plain\" constraint 0"), "{err}");
+        invalid.gates[1].constraint_names.clear();
+        let err = invalid.validate_constants().unwrap_err();
+        assert!(
+            err.contains("gate \"scale:plain\" constraint 0 (\"scale:plain\")"),
+            "{err}"
+        );
+
+        let mut invalid = meta;
+        invalid.lookups[0].table_expressions[0] = Expression::Constant(non_canonical);
+        let err = invalid.validate_constants().unwrap_err();
+        assert!(
+            err.contains("lookup \"lookup\" table expression 0"),
+            "{err}"
+        );
     }
 
-    /// Returns permutation argument
-    pub fn permutation(&self) -> &permutation::Argument {
-        &self.permutation
+    #[test]
+    fn short_id() {
+        let build = |num_gates: usize| {
+            let mut meta = ConstraintSystem::<Fr>::default();
+            let a = meta.advice_column();
+            let f = meta.fixed_column();
+            for _ in 0..num_gates {
+                meta.create_gate("gate", |meta| {
+                    let a = meta.query_advice(a, Rotation::cur());
+                    let f = meta.query_fixed(f, Rotation::cur());
+                    vec![f * a]
+                });
+            }
+            meta.lookup_any("lookup", |meta| {
+                let a = meta.query_advice(a, Rotation::cur());
+                let f = meta.query_fixed(f, Rotation::cur());
+                vec![(a, f)]
+            });
+            meta
+        };
+
+        let short_id = build(1).short_id();
+        assert_eq!(short_id, build(1).short_id());
+        assert!(short_id.starts_with("cs-"));
+        assert!(short_id.ends_with("-g1-l1-s0"));
+        assert_eq!(short_id.len(), "cs-0000-g1-l1-s0".len());
+
+        let other = build(2);
+        assert_ne!(other.digest(), build(1).digest());
+        assert_ne!(other.short_id(), short_id);
+        assert!(other.short_id().ends_with("-g2-l1-s0"));
     }
 
-    /// Returns lookup arguments
-    pub fn lookups(&self) -> &Vec<lookup::Argument<F>> {
-        &self.lookups
+    #[test]
+    fn num_lookup_shuffle_constraints() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        let f = meta.fixed_column();
+        let g = meta.fixed_column();
+        assert_eq!(meta.num_lookup_constraints(), 0);
+        assert_eq!(meta.num_shuffle_constraints(), 0);
+
+        meta.lookup_any("pair", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::cur());
+            let f = meta.query_fixed(f, Rotation::cur());
+            let g = meta.query_fixed(g, Rotation::cur());
+            vec![(a, f), (b, g)]
+        });
+        meta.shuffle("shuffle", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::cur());
+            vec![(a, b)]
+        });
+        assert_eq!(meta.num_lookup_constraints(), 2);
+        assert_eq!(meta.num_shuffle_constraints(), 1);
     }
 
-    /// Returns shuffle arguments
-    pub fn shuffles(&self) -> &Vec<shuffle::Argument<F>> {
-        &self.shuffles
+    #[test]
+    fn num_permutation_z_polys() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        let c = meta.advice_column();
+        meta.create_gate("quartic", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::cur());
+            let c = meta.query_advice(c, Rotation::cur());
+            vec![a.clone() * a * b * c]
+        });
+        // No columns in the permutation, no product polynomials.
+        assert_eq!(meta.num_permutation_z_polys(), 0);
+
+        let columns: Vec<_> = (0..5).map(|_| meta.advice_column()).collect();
+        for column in columns.iter().chain([a, b, c].iter()) {
+            meta.enable_equality(*column);
+        }
+        // 8 columns at degree 4 are packed in chunks of 2.
+        assert_eq!(meta.degree(), 4);
+        assert_eq!(meta.num_permutation_z_polys(), 4);
+
+        let f = meta.fixed_column();
+        meta.enable_equality(f);
+        assert_eq!(meta.num_permutation_z_polys(), (9 + 1) / 2);
     }
 
-    /// Returns constants
-    pub fn constants(&self) -> &Vec<Column<Fixed>> {
-        &self.constants
+    #[test]
+    fn num_advice_columns_per_phase() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        meta.advice_column();
+        meta.advice_column_in(SecondPhase);
+        meta.advice_column_in(SecondPhase);
+        meta.advice_column_in(ThirdPhase);
+
+        assert_eq!(meta.advice_column_phase(), vec![0, 1, 1, 2]);
+        assert_eq!(meta.num_advice_columns_per_phase(), vec![1, 2, 1]);
     }
-}
 
-/// Exposes the "virtual cells" that can be queried while creating a custom gate or lookup
-/// table.
-#[derive(Debug)]
-pub struct VirtualCells<'a, F: Field> {
-    meta: &'a mut ConstraintSystem<F>,
-    queried_selectors: Vec<Selector>,
-    queried_cells: Vec<VirtualCell>,
-}
+    #[test]
+    fn max_degree_path() {
+        let advice = |index| Column::new(index, Advice::default());
+        let (a, b, c) = (advice(0), advice(1), advice(2));
+        let expr = a.cur::<Fr>() * b.cur() * c.cur() + a.cur();
+
+        let step = |kind, degree| DegreeStep { kind, degree };
+        assert_eq!(
+            expr.max_degree_path(),
+            vec![
+                step(ExpressionKind::Sum, 0),
+                step(ExpressionKind::Product, 1),
+                step(ExpressionKind::Product, 1),
+                step(ExpressionKind::Advice, 1),
+            ]
+        );
+        let degree: usize = expr.max_degree_path().iter().map(|step| step.degree).sum();
+        assert_eq!(degree, expr.degree());
+    }
 
-impl<'a, F: Field> VirtualCells<'a, F> {
-    fn new(meta: &'a mut ConstraintSystem<F>) -> Self {
-        VirtualCells {
-            meta,
-            queried_selectors: vec![],
-            queried_cells: vec![],
+    #[test]
+    fn constraint_system_builder() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        let f = meta.fixed_column();
+
+        let mut builder = ConstraintSystemBuilder::new(meta);
+        builder
+            .add_gate("mul", vec![f.cur::<Fr>() * a.cur() * b.cur()])
+            .add_gate("next", vec![a.next() - b.cur(), f.prev()]);
+        let cs = builder.build();
+
+        assert_eq!(cs.gates().len(), 2);
+        assert_eq!(cs.advice_queries().len(), 3);
+        assert_eq!(cs.fixed_queries().len(), 2);
+        for poly in cs.gates().iter().flat_map(|gate| gate.polynomials()) {
+            let resolved = poly.evaluate(
+                &|_| true,
+                &|_| true,
+                &|query| {
+                    let column = Column::new(query.column_index, Fixed);
+                    query.index.map(|index| cs.fixed_queries()[index])
+                        == Some((column, query.rotation))
+                },
+                &|query| {
+                    let column = Column::new(query.column_index, Advice::new(query.phase()));
+                    query.index.map(|index| cs.advice_queries()[index])
+                        == Some((column, query.rotation))
+                },
+                &|_| true,
+                &|_| true,
+                &|a| a,
+                &|a, b| a && b,
+                &|a, b| a && b,
+                &|a, _| a,
+            );
+            assert!(resolved);
         }
     }
 
-    /// Query a selector at the current position.
-    pub fn query_selector(&mut self, selector: Selector) -> Expression<F> {
-        self.queried_selectors.push(selector);
-        Expression::Selector(selector)
+    #[test]
+    fn lower_to_gates() {
+        let advice = |index| Column::new(index, Advice::default());
+        let (a, b, c) = (advice(0).cur::<Fr>(), advice(1).cur(), advice(2).cur());
+        let expr = a.clone() * b.clone() + c.clone();
+
+        let mut next_wire = 10;
+        let (out, gates) = expr.lower_to_gates(&mut next_wire);
+        let input = |leaf, wire| ArithGate {
+            op: ArithOp::Input(leaf),
+            lhs: wire,
+            rhs: wire,
+            out: wire,
+        };
+        let gate = |op, lhs, rhs, out| ArithGate { op, lhs, rhs, out };
+        assert_eq!(
+            gates,
+            vec![
+                input(a, 10),
+                input(b, 11),
+                gate(ArithOp::Mul, 10, 11, 12),
+                input(c, 13),
+                gate(ArithOp::Add, 12, 13, 14),
+            ]
+        );
+        assert_eq!(out, 14);
+        assert_eq!(next_wire, 15);
     }
 
-    /// Query a fixed column at a relative position
-    pub fn query_fixed(&mut self, column: Column<Fixed>, at: Rotation) -> Expression<F> {
-        self.queried_cells.push((column, at).into());
-        Expression::Fixed(FixedQuery {
-            index: Some(self.meta.query_fixed_index(column, at)),
-            column_index: column.index,
-            rotation: at,
-        })
-    }
+    #[test]
+    fn apply_to_gates() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        meta.create_gate("gate", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::next());
+            vec![(a * b) * Expression::Constant(Fr::ONE)]
+        });
 
-    /// Query an advice column at a relative position
-    pub fn query_advice(&mut self, column: Column<Advice>, at: Rotation) -> Expression<F> {
-        self.queried_cells.push((column, at).into());
-        Expression::Advice(AdviceQuery {
-            index: Some(self.meta.query_advice_index(column, at)),
-            column_index: column.index,
-            rotation: at,
-            phase: sealed::Phase(column.column_type().phase),
-        })
+        // Drop multiplications by one.
+        let report = meta.apply_to_gates(|expr| match expr {
+            Expression::Product(a, b) if **b == Expression::Constant(Fr::ONE) => (**a).clone(),
+            expr => expr.clone(),
+        });
+
+        assert_eq!(report.degree_before, 2);
+        assert_eq!(report.degree_after, 2);
+        assert_eq!(report.nodes_before, 5);
+        assert_eq!(report.nodes_after, 3);
+        assert_eq!(meta.advice_queries().len(), 2);
+        assert_eq!(meta.gates[0].queried_cells().len(), 2);
+
+        // Keep only the left factor, dropping the query of `b`.
+        meta.apply_to_gates(|expr| match expr {
+            Expression::Product(a, _) => (**a).clone(),
+            expr => expr.clone(),
+        });
+        assert_eq!(meta.advice_queries(), &vec![(a, Rotation::cur())]);
+        let cells: Vec<_> = meta.gates[0]
+            .queried_cells()
+            .iter()
+            .map(|cell| (cell.column, cell.rotation))
+            .collect();
+        assert_eq!(cells, vec![(Column::<Any>::from(a), Rotation::cur())]);
+        assert!(meta.gates[0].queried_selectors().is_empty());
     }
 
-    /// Query an instance column at a relative position
-    pub fn query_instance(&mut self, column: Column<Instance>, at: Rotation) -> Expression<F> {
-        self.queried_cells.push((column, at).into());
-        Expression::Instance(InstanceQuery {
-            index: Some(self.meta.query_instance_index(column, at)),
-            column_index: column.index,
-            rotation: at,
-        })
+    #[test]
+    fn challenge() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        meta.advice_column();
+        meta.advice_column_in(SecondPhase);
+        meta.challenge_usable_after(FirstPhase);
+        let theta = meta.challenge_usable_after(SecondPhase);
+
+        assert_eq!(meta.challenge(1), Some(theta));
+        assert_eq!(
+            meta.challenge(1).map(|challenge| challenge.phase()),
+            Some(1)
+        );
+        assert_eq!(meta.challenge(2), None);
     }
 
-    /// Query an Any column at a relative position
-    pub fn query_any<C: Into<Column<Any>>>(&mut self, column: C, at: Rotation) -> Expression<F> {
-        let column = column.into();
-        match column.column_type() {
-            Any::Advice(_) => self.query_advice(Column::<Advice>::try_from(column).unwrap(), at),
-            Any::Fixed => self.query_fixed(Column::<Fixed>::try_from(column).unwrap(), at),
-            Any::Instance => self.query_instance(Column::<Instance>::try_from(column).unwrap(), at),
-        }
+    #[test]
+    fn gate_selectors() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let s = meta.fixed_column();
+        let t = meta.fixed_column();
+        meta.create_gate("bool", |meta| {
+            let s = meta.query_fixed(s, Rotation::cur());
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![s * (a.clone() * a.clone() - a)]
+        });
+        meta.create_gate("pair", |meta| {
+            let s = meta.query_fixed(s, Rotation::cur());
+            let a_cur = meta.query_advice(a, Rotation::cur());
+            let a_next = meta.query_advice(a, Rotation::next());
+            vec![s.clone() * a_cur, s * a_next]
+        });
+        meta.create_gate("mixed", |meta| {
+            let s = meta.query_fixed(s, Rotation::cur());
+            let t = meta.query_fixed(t, Rotation::cur());
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![s * a.clone(), t * a]
+        });
+
+        assert_eq!(meta.gate_selectors(), vec![Some(s), Some(s), None]);
     }
 
-    /// Query a challenge
-    pub fn query_challenge(&mut self, challenge: Challenge) -> Expression<F> {
-        Expression::Challenge(challenge)
+    #[test]
+    fn selector_fixed_columns() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let table = meta.fixed_column();
+        let s = meta.fixed_column();
+        meta.create_gate("bool", |meta| {
+            let s = meta.query_fixed(s, Rotation::cur());
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![s * (a.clone() * a.clone() - a)]
+        });
+        meta.lookup_any("range", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let table = meta.query_fixed(table, Rotation::cur());
+            vec![(a, table)]
+        });
+
+        assert_eq!(meta.selector_fixed_columns(), BTreeSet::from([s.index()]));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::Expression;
-    use halo2curves::bn256::Fr;
+    #[test]
+    fn extended_domain_k() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        meta.create_gate("quartic", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![a.clone().square() * a.square()]
+        });
+
+        assert_eq!(meta.degree(), 4);
+        assert_eq!(meta.extended_k_shift(), 2);
+        assert_eq!(meta.extended_domain_k(10), 12);
+    }
 
     #[test]
-    fn iter_sum() {
-        let exprs: Vec<Expression<Fr>> = vec![
-            Expression::Constant(1.into()),
-            Expression::Constant(2.into()),
-            Expression::Constant(3.into()),
-        ];
-        let happened: Expression<Fr> = exprs.into_iter().sum();
-        let expected: Expression<Fr> = Expression::Sum(
-            Box::new(Expression::Sum(
-                Box::new(Expression::Constant(1.into())),
-                Box::new(Expression::Constant(2.into())),
-            )),
-            Box::new(Expression::Constant(3.into())),
+    fn pow() {
+        let a = Column::new(0, Advice::default()).cur::<Fr>();
+        let evaluate = |expr: &Expression<Fr>| {
+            expr.evaluate(
+                &|scalar| scalar,
+                &|_| panic!("no selectors"),
+                &|_| panic!("no fixed columns"),
+                &|_| Fr::from(3),
+                &|_| panic!("no instance columns"),
+                &|_| panic!("no challenges"),
+                &|a| -a,
+                &|a, b| a + b,
+                &|a, b| a * b,
+                &|a, f| a * f,
+            )
+        };
+
+        assert_eq!(a.clone().pow(0), Expression::Constant(Fr::ONE));
+        assert_eq!(a.clone().pow(1), a);
+        assert_eq!(a.clone().pow(3).degree(), 3);
+        assert_eq!(
+            evaluate(&a.clone().pow(3)),
+            evaluate(&(a.clone() * a.clone() * a.clone()))
         );
+        assert_eq!(evaluate(&a.clone().pow(3)), Fr::from(27));
+        assert_eq!(evaluate(&a.clone().pow(10)), Fr::from(59049));
 
-        assert_eq!(happened, expected);
+        let b = Column::new(1, Advice::default()).cur::<Fr>();
+        let ab = a.clone() * b;
+        assert_eq!(ab.clone().pow(4).degree(), 4 * ab.degree());
+        assert_eq!(a.clone().pow(8), a.clone().square().square().square());
+        assert_eq!(a.clone().square(), a.clone() * a.clone());
+        assert_eq!(a.clone().pow(1000).num_nodes(), 2 * 1000 - 1);
     }
 
     #[test]
-    fn iter_product() {
-        let exprs: Vec<Expression<Fr>> = vec![
-            Expression::Constant(1.into()),
-            Expression::Constant(2.into()),
-            Expression::Constant(3.into()),
-        ];
-        let happened: Expression<Fr> = exprs.into_iter().product();
-        let expected: Expression<Fr> = Expression::Product(
-            Box::new(Expression::Product(
-                Box::new(Expression::Constant(1.into())),
-                Box::new(Expression::Constant(2.into())),
-            )),
-            Box::new(Expression::Constant(3.into())),
-        );
+    fn challenge_phase_violations() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column_in(SecondPhase);
+        let theta = meta.challenge_usable_after(FirstPhase);
+        meta.create_gate("first phase", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let theta = meta.query_challenge(theta);
+            vec![theta * a]
+        });
+        meta.create_gate("second phase", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::cur());
+            let theta = meta.query_challenge(theta);
+            vec![a + theta * b]
+        });
 
-        assert_eq!(happened, expected);
+        assert_eq!(meta.challenge_phase_violations(), vec![(1, 0, 1)]);
     }
 }
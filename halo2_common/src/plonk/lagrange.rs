@@ -0,0 +1,58 @@
+//! Helpers to reference the boundary Lagrange polynomials from custom gates.
+//!
+//! The backend builds `l_0`, `l_last` and `l_blind` internally, so they cannot be
+//! queried directly. Instead, a circuit that needs them assigns each one to a fixed
+//! column of its own (`1` on the rows where the polynomial is nonzero, `0` elsewhere)
+//! and references it through the helpers below, which always query that column at the
+//! current row:
+//!
+//! - `l_0` is `1` on the first row only.
+//! - `l_last` is `1` on the last usable row only, i.e. row `n - blinding_factors - 1`.
+//! - `l_blind` is `1` on the blinding rows only, i.e. the `blinding_factors` rows
+//!   following `l_last`.
+
+use super::circuit::{Column, Expression};
+use halo2_middleware::circuit::Fixed;
+use halo2_middleware::ff::Field;
+use halo2_middleware::poly::Rotation;
+
+/// Returns an expression for `l_0`, assigned to the fixed column `column`.
+pub fn l0_query<F: Field>(column: Column<Fixed>) -> Expression<F> {
+    column.query_cell(Rotation::cur())
+}
+
+/// Returns an expression for `l_last`, assigned to the fixed column `column`.
+pub fn l_last_query<F: Field>(column: Column<Fixed>) -> Expression<F> {
+    column.query_cell(Rotation::cur())
+}
+
+/// Returns an expression for `l_blind`, assigned to the fixed column `column`.
+pub fn l_blind_query<F: Field>(column: Column<Fixed>) -> Expression<F> {
+    column.query_cell(Rotation::cur())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{l0_query, l_blind_query, l_last_query};
+    use crate::plonk::{ConstraintSystem, Expression};
+    use halo2_middleware::poly::Rotation;
+    use halo2curves::bn256::Fr;
+
+    #[test]
+    fn lagrange_queries() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let _ = meta.fixed_column();
+        let l_0 = meta.fixed_column();
+
+        for expr in [l0_query::<Fr>(l_0), l_last_query(l_0), l_blind_query(l_0)] {
+            match expr {
+                Expression::Fixed(query) => {
+                    assert_eq!(query.index, None);
+                    assert_eq!(query.column_index(), 1);
+                    assert_eq!(query.rotation(), Rotation::cur());
+                }
+                _ => panic!("expected a fixed query"),
+            }
+        }
+    }
+}
@@ -1,6 +1,8 @@
-use super::circuit::Expression;
+use super::circuit::{read_argument, write_argument, Expression};
+use crate::helpers::{SerdeFormat, SerdePrimeField};
 use halo2_middleware::ff::Field;
 use std::fmt::{self, Debug};
+use std::io;
 
 /// Expressions involved in a lookup argument, with a name as metadata.
 /// TODO: possible to move to "halo2_backend", if moved, pub(crate) fields.
@@ -81,6 +83,26 @@ impl<F: Field> Argument<F> {
         )
     }
 
+    /// Returns whether this argument and `other` compute the same expressions, as
+    /// given by their identifiers. Names and query indices are ignored.
+    pub fn same_as(&self, other: &Self) -> bool {
+        fn identifiers<F: Field>(exprs: &[Expression<F>]) -> Vec<String> {
+            exprs.iter().map(|expr| expr.identifier()).collect()
+        }
+
+        identifiers(&self.input_expressions) == identifiers(&other.input_expressions)
+            && identifiers(&self.table_expressions) == identifiers(&other.table_expressions)
+    }
+
+    /// Returns the `(input, table)` pairs of this argument, as given to
+    /// [`Argument::new`].
+    pub fn table_map(&self) -> Vec<(&Expression<F>, &Expression<F>)> {
+        self.input_expressions
+            .iter()
+            .zip(self.table_expressions.iter())
+            .collect()
+    }
+
     /// Returns input of this argument
     pub fn input_expressions(&self) -> &Vec<Expression<F>> {
         &self.input_expressions
@@ -96,3 +118,113 @@ impl<F: Field> Argument<F> {
         &self.name
     }
 }
+
+impl<F: SerdePrimeField> Argument<F> {
+    /// Writes this argument to a buffer. The name is stored alongside the input and
+    /// table expressions, which are lowered to `ExpressionMid` and encoded with field
+    /// elements serialized according to `format`. Selector queries are not supported, so
+    /// this should be called on arguments taken from a finalized constraint system.
+    pub fn write<W: io::Write>(&self, writer: &mut W, format: SerdeFormat) -> io::Result<()> {
+        write_argument(
+            &self.name,
+            [&self.input_expressions, &self.table_expressions],
+            writer,
+            format,
+        )
+    }
+
+    /// Reads an argument written by [`Argument::write`]. The returned expressions carry
+    /// no query indices.
+    pub fn read<R: io::Read>(reader: &mut R, format: SerdeFormat) -> io::Result<Self> {
+        let (name, [input_expressions, table_expressions]) = read_argument(reader, format)?;
+        if input_expressions.len() != table_expressions.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "mismatched number of input and table expressions",
+            ));
+        }
+        Ok(Argument {
+            name,
+            input_expressions,
+            table_expressions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Argument;
+    use crate::helpers::SerdeFormat;
+    use crate::plonk::ConstraintSystem;
+    use halo2_middleware::poly::Rotation;
+    use halo2curves::bn256::Fr;
+
+    #[test]
+    fn same_as() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let f = meta.fixed_column();
+        meta.lookup_any("lookup", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let f = meta.query_fixed(f, Rotation::cur());
+            vec![(a, f)]
+        });
+        let argument = &meta.lookups()[0];
+
+        // Expressions built outside of the constraint system carry no query indices.
+        let stripped = Argument::new("stripped", vec![(a.cur(), f.cur())]);
+        assert!(argument.same_as(&stripped));
+
+        let swapped = Argument::new("swapped", vec![(f.cur(), a.cur())]);
+        assert!(!argument.same_as(&swapped));
+    }
+
+    #[test]
+    fn table_map() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let (a, b) = (meta.advice_column(), meta.advice_column());
+        let (f, g) = (meta.fixed_column(), meta.fixed_column());
+        let argument =
+            Argument::<Fr>::new("two columns", vec![(a.cur(), f.cur()), (b.next(), g.cur())]);
+
+        let pairs: Vec<_> = argument
+            .table_map()
+            .into_iter()
+            .map(|(input, table)| (input.identifier(), table.identifier()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("advice[0][0]".to_string(), "fixed[0][0]".to_string()),
+                ("advice[1][1]".to_string(), "fixed[1][0]".to_string()),
+            ]
+        );
+        assert_eq!(argument.input_expressions().len(), 2);
+        assert_eq!(argument.table_expressions().len(), 2);
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        let f = meta.fixed_column();
+        let g = meta.fixed_column();
+        meta.lookup_any("two columns", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::next());
+            let f = meta.query_fixed(f, Rotation::cur());
+            let g = meta.query_fixed(g, Rotation::cur());
+            vec![(a * Fr::from(3), f), (-b, g)]
+        });
+        let argument = &meta.lookups()[0];
+
+        for format in [SerdeFormat::Processed, SerdeFormat::RawBytes] {
+            let mut bytes = vec![];
+            argument.write(&mut bytes, format).unwrap();
+            let read = Argument::<Fr>::read(&mut &bytes[..], format).unwrap();
+            assert_eq!(read.name(), "two columns");
+            assert!(argument.same_as(&read));
+        }
+    }
+}
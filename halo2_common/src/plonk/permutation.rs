@@ -1,8 +1,11 @@
 //! Implementation of permutation argument.
 
 use crate::plonk::{Column, Error};
-use halo2_middleware::circuit::{Any, Cell};
+use blake2b_simd::Params as Blake2bParams;
+use halo2_middleware::circuit::{Advice, Any, Cell};
 use halo2_middleware::permutation::ArgumentV2;
+use std::collections::HashMap;
+use std::io;
 
 /// A permutation argument.
 #[derive(Default, Debug, Clone)]
@@ -20,6 +23,16 @@ impl From<ArgumentV2> for Argument {
 }
 
 impl Argument {
+    /// Constructs a permutation argument over `columns`. Duplicate columns are
+    /// dropped, keeping the order in which they first appear.
+    pub fn from_columns(columns: Vec<Column<Any>>) -> Self {
+        let mut argument = Argument::default();
+        for column in columns {
+            argument.add_column(column);
+        }
+        argument
+    }
+
     /// Returns the minimum circuit degree required by the permutation argument.
     /// The argument may use larger degree gates depending on the actual
     /// circuit's degree and how many columns are involved in the permutation.
@@ -58,12 +71,68 @@ impl Argument {
         3
     }
 
+    /// Returns the actual degree of the permutation constraints for a circuit of degree
+    /// `circuit_degree`. Columns are packed in chunks of `circuit_degree - 2`, and a
+    /// chunk of `n` columns gives a constraint of degree `n + 2`, so the result may be
+    /// lower than `circuit_degree` when all columns fit in a shorter chunk. It is never
+    /// lower than [`Argument::required_degree`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `circuit_degree` is lower than [`Argument::required_degree`].
+    pub fn effective_degree(&self, circuit_degree: usize) -> usize {
+        assert!(circuit_degree >= self.required_degree());
+        let chunk_len = circuit_degree - 2;
+        std::cmp::max(
+            self.required_degree(),
+            2 + std::cmp::min(chunk_len, self.columns.len()),
+        )
+    }
+
+    /// Returns the number of chunks the columns are split into for a circuit of degree
+    /// `circuit_degree`, which is also the number of permutation product polynomials
+    /// `z` committed to by the prover.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `circuit_degree` is lower than [`Argument::required_degree`].
+    pub fn chunk_count(&self, circuit_degree: usize) -> usize {
+        assert!(circuit_degree >= self.required_degree());
+        let chunk_len = circuit_degree - 2;
+        (self.columns.len() + chunk_len - 1) / chunk_len
+    }
+
     pub(crate) fn add_column(&mut self, column: Column<Any>) {
         if !self.columns.contains(&column) {
             self.columns.push(column);
         }
     }
 
+    /// Returns a digest of the set of columns involved in the argument, which does not
+    /// depend on the order in which the columns were added.
+    pub fn digest(&self) -> u64 {
+        let mut columns = self.columns.clone();
+        columns.sort();
+
+        let mut hasher = Blake2bParams::new()
+            .hash_length(8)
+            .personal(b"Halo2-Perm-Args")
+            .to_state();
+        for column in columns {
+            let (column_type, phase) = match column.column_type {
+                Any::Instance => (0u8, 0u8),
+                Any::Advice(advice) => (1, advice.phase),
+                Any::Fixed => (2, 0),
+            };
+            hasher.update(&[column_type, phase]);
+            hasher.update(&(column.index as u64).to_le_bytes());
+        }
+
+        let mut digest = [0u8; 8];
+        digest.copy_from_slice(hasher.finalize().as_bytes());
+        u64::from_le_bytes(digest)
+    }
+
     /// Returns columns that participate on the permutation argument.
     pub fn get_columns(&self) -> Vec<Column<Any>> {
         self.columns.clone()
@@ -115,4 +184,236 @@ impl Assembly {
         ));
         Ok(())
     }
+
+    /// Writes this assembly to a buffer: `n`, the permuted columns, and the copy
+    /// constraints, with each cell stored as its column followed by its row.
+    pub fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&(self.n as u32).to_le_bytes())?;
+        writer.write_all(&(self.columns.len() as u32).to_le_bytes())?;
+        for column in self.columns.iter() {
+            write_column(writer, *column)?;
+        }
+        writer.write_all(&(self.copies.len() as u32).to_le_bytes())?;
+        for cell in self.copies.iter().flat_map(|(left, right)| [left, right]) {
+            write_column(writer, cell.column.into())?;
+            writer.write_all(&(cell.row as u32).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reads an assembly written by [`Assembly::write`], checking that every copied cell
+    /// lies in one of the permuted columns and in a row below `n`.
+    pub fn read<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+        let n = read_u32(reader)? as usize;
+        let columns = (0..read_u32(reader)?)
+            .map(|_| read_column(reader))
+            .collect::<io::Result<Vec<_>>>()?;
+        let num_copies = read_u32(reader)?;
+
+        let mut read_cell = || -> io::Result<Cell> {
+            let column = read_column(reader)?;
+            let row = read_u32(reader)? as usize;
+            if !columns.contains(&column) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("copy of column {:?} not in the permutation", column),
+                ));
+            }
+            if row >= n {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("copy of row {} out of bounds", row),
+                ));
+            }
+            Ok(Cell {
+                column: column.into(),
+                row,
+            })
+        };
+        let copies = (0..num_copies)
+            .map(|_| -> io::Result<(Cell, Cell)> { Ok((read_cell()?, read_cell()?)) })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Assembly { n, columns, copies })
+    }
+
+    /// Returns the equivalence classes of cells induced by the copy constraints. Cells
+    /// within a class, and the classes themselves, are sorted by column and row.
+    pub fn connected_components(&self) -> Vec<Vec<Cell>> {
+        fn find(parent: &mut [usize], mut i: usize) -> usize {
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        }
+
+        let mut cells: Vec<Cell> = Vec::new();
+        let mut parent: Vec<usize> = Vec::new();
+        let mut indices: HashMap<Cell, usize> = HashMap::new();
+        for (left, right) in self.copies.iter() {
+            let [left, right] = [left, right].map(|cell| {
+                *indices.entry(cell.clone()).or_insert_with(|| {
+                    cells.push(cell.clone());
+                    parent.push(parent.len());
+                    parent.len() - 1
+                })
+            });
+            let (left, right) = (find(&mut parent, left), find(&mut parent, right));
+            if left != right {
+                parent[left] = right;
+            }
+        }
+
+        let mut components: HashMap<usize, Vec<Cell>> = HashMap::new();
+        for (i, cell) in cells.into_iter().enumerate() {
+            let root = find(&mut parent, i);
+            components.entry(root).or_default().push(cell);
+        }
+
+        let key = |cell: &Cell| (cell.column.column_type, cell.column.index, cell.row);
+        let mut components: Vec<Vec<Cell>> = components
+            .into_values()
+            .map(|mut component| {
+                component.sort_by_key(key);
+                component
+            })
+            .collect();
+        components.sort_by_key(|component| key(&component[0]));
+        components
+    }
+}
+
+fn read_u32<R: io::Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn write_column<W: io::Write>(writer: &mut W, column: Column<Any>) -> io::Result<()> {
+    let (column_type, phase) = match column.column_type {
+        Any::Instance => (0u8, 0u8),
+        Any::Advice(advice) => (1, advice.phase),
+        Any::Fixed => (2, 0),
+    };
+    writer.write_all(&[column_type, phase])?;
+    writer.write_all(&(column.index as u32).to_le_bytes())
+}
+
+fn read_column<R: io::Read>(reader: &mut R) -> io::Result<Column<Any>> {
+    let mut tag = [0u8; 2];
+    reader.read_exact(&mut tag)?;
+    let column_type = match tag {
+        [0, 0] => Any::Instance,
+        [1, phase] => Any::Advice(Advice::new(phase)),
+        [2, 0] => Any::Fixed,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid column type",
+            ))
+        }
+    };
+    Ok(Column::new(read_u32(reader)? as usize, column_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Argument, Assembly};
+    use crate::plonk::Column;
+    use halo2_middleware::circuit::{Any, Cell};
+
+    #[test]
+    fn from_columns() {
+        let a = Column::new(0, Any::advice());
+        let f = Column::new(0, Any::Fixed);
+        let i = Column::new(1, Any::Instance);
+
+        let argument = Argument::from_columns(vec![a, f, a, i]);
+        assert_eq!(argument.get_columns(), vec![a, f, i]);
+    }
+
+    #[test]
+    fn effective_degree() {
+        let columns: Vec<Column<Any>> = (0..3).map(|i| Column::new(i, Any::Fixed)).collect();
+        let argument = Argument::from_columns(columns);
+
+        // All three columns fit in a single chunk.
+        assert_eq!(argument.effective_degree(5), 5);
+        assert_eq!(argument.effective_degree(8), 5);
+        // Chunks of a single column.
+        assert_eq!(argument.effective_degree(3), 3);
+        // No columns still incurs the degree 3 constraints.
+        assert_eq!(Argument::default().effective_degree(6), 3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn effective_degree_below_required() {
+        Argument::default().effective_degree(2);
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let a = Column::new(0, Any::advice());
+        let f = Column::new(1, Any::Fixed);
+        let i = Column::new(0, Any::Instance);
+        let mut assembly = Assembly::new(8, &Argument::from_columns(vec![a, f, i]));
+        assembly.copy(a, 0, f, 3).unwrap();
+        assembly.copy(i, 2, a, 5).unwrap();
+        assembly.copy(f, 7, i, 7).unwrap();
+
+        let mut bytes = vec![];
+        assembly.write(&mut bytes).unwrap();
+        let read = Assembly::read(&mut &bytes[..]).unwrap();
+        assert_eq!(read.n, assembly.n);
+        assert_eq!(read.columns, assembly.columns);
+        assert_eq!(read.copies, assembly.copies);
+
+        // A copy of a row outside of the assembly is rejected.
+        let mut out_of_bounds = assembly.clone();
+        out_of_bounds.copies[0].1.row = 8;
+        let mut bytes = vec![];
+        out_of_bounds.write(&mut bytes).unwrap();
+        assert!(Assembly::read(&mut &bytes[..]).is_err());
+    }
+
+    #[test]
+    fn digest_is_order_independent() {
+        let a = Column::new(0, Any::advice());
+        let b = Column::new(1, Any::advice_in(1));
+        let f = Column::new(0, Any::Fixed);
+
+        let lhs = Argument::from_columns(vec![a, b, f]);
+        let rhs = Argument::from_columns(vec![f, a, b]);
+        assert_eq!(lhs.digest(), rhs.digest());
+
+        let other = Argument::from_columns(vec![a, f]);
+        assert_ne!(lhs.digest(), other.digest());
+    }
+
+    #[test]
+    fn connected_components() {
+        let x = Column::new(0, Any::advice());
+        let y = Column::new(1, Any::advice());
+        let argument = Argument::from_columns(vec![x, y]);
+        let mut assembly = Assembly::new(4, &argument);
+        // a ~ b, c ~ d and b ~ c
+        assembly.copy(x, 0, x, 1).unwrap();
+        assembly.copy(y, 0, y, 1).unwrap();
+        assembly.copy(x, 1, y, 0).unwrap();
+        assembly.copy(x, 2, x, 3).unwrap();
+
+        let cell = |column: Column<Any>, row| Cell {
+            column: column.into(),
+            row,
+        };
+        assert_eq!(
+            assembly.connected_components(),
+            vec![
+                vec![cell(x, 0), cell(x, 1), cell(y, 0), cell(y, 1)],
+                vec![cell(x, 2), cell(x, 3)],
+            ]
+        );
+    }
 }
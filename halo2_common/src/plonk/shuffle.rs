@@ -1,6 +1,8 @@
-use super::circuit::Expression;
+use super::circuit::{read_argument, write_argument, Expression};
+use crate::helpers::{SerdeFormat, SerdePrimeField};
 use halo2_middleware::ff::Field;
 use std::fmt::{self, Debug};
+use std::io;
 
 /// Expressions involved in a shuffle argument, with a name as metadata.
 #[derive(Clone)]
@@ -48,6 +50,17 @@ impl<F: Field> Argument<F> {
         std::cmp::max(2 + shuffle_degree, 2 + input_degree)
     }
 
+    /// Returns whether this argument and `other` compute the same expressions, as
+    /// given by their identifiers. Names and query indices are ignored.
+    pub fn same_as(&self, other: &Self) -> bool {
+        fn identifiers<F: Field>(exprs: &[Expression<F>]) -> Vec<String> {
+            exprs.iter().map(|expr| expr.identifier()).collect()
+        }
+
+        identifiers(&self.input_expressions) == identifiers(&other.input_expressions)
+            && identifiers(&self.shuffle_expressions) == identifiers(&other.shuffle_expressions)
+    }
+
     /// Returns input of this argument
     pub fn input_expressions(&self) -> &Vec<Expression<F>> {
         &self.input_expressions
@@ -63,3 +76,63 @@ impl<F: Field> Argument<F> {
         &self.name
     }
 }
+
+impl<F: SerdePrimeField> Argument<F> {
+    /// Writes this argument to a buffer. The name is stored alongside the input and
+    /// shuffle expressions, which are lowered to `ExpressionMid` and encoded with field
+    /// elements serialized according to `format`. Selector queries are not supported, so
+    /// this should be called on arguments taken from a finalized constraint system.
+    pub fn write<W: io::Write>(&self, writer: &mut W, format: SerdeFormat) -> io::Result<()> {
+        write_argument(
+            &self.name,
+            [&self.input_expressions, &self.shuffle_expressions],
+            writer,
+            format,
+        )
+    }
+
+    /// Reads an argument written by [`Argument::write`]. The returned expressions carry
+    /// no query indices.
+    pub fn read<R: io::Read>(reader: &mut R, format: SerdeFormat) -> io::Result<Self> {
+        let (name, [input_expressions, shuffle_expressions]) = read_argument(reader, format)?;
+        if input_expressions.len() != shuffle_expressions.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "mismatched number of input and shuffle expressions",
+            ));
+        }
+        Ok(Argument {
+            name,
+            input_expressions,
+            shuffle_expressions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Argument;
+    use crate::plonk::ConstraintSystem;
+    use halo2_middleware::poly::Rotation;
+    use halo2curves::bn256::Fr;
+
+    #[test]
+    fn same_as() {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let a = meta.advice_column();
+        let f = meta.fixed_column();
+        meta.shuffle("shuffle", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let f = meta.query_fixed(f, Rotation::cur());
+            vec![(a, f)]
+        });
+        let argument = &meta.shuffles()[0];
+
+        // Expressions built outside of the constraint system carry no query indices.
+        let stripped = Argument::new("stripped", vec![(a.cur(), f.cur())]);
+        assert!(argument.same_as(&stripped));
+
+        let swapped = Argument::new("swapped", vec![(f.cur(), a.cur())]);
+        assert!(!argument.same_as(&swapped));
+    }
+}
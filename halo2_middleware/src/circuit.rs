@@ -6,6 +6,7 @@ use std::collections::HashMap;
 
 /// Query of fixed column at a certain relative location
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FixedQueryMid {
     /// Column index
     pub column_index: usize,
@@ -15,6 +16,7 @@ pub struct FixedQueryMid {
 
 /// Query of advice column at a certain relative location
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AdviceQueryMid {
     /// Column index
     pub column_index: usize,
@@ -26,6 +28,7 @@ pub struct AdviceQueryMid {
 
 /// Query of instance column at a certain relative location
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InstanceQueryMid {
     /// Column index
     pub column_index: usize,
@@ -35,6 +38,7 @@ pub struct InstanceQueryMid {
 
 /// A challenge squeezed from transcript after advice columns at the phase have been committed.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ChallengeMid {
     pub index: usize,
     pub phase: u8,
@@ -54,6 +58,7 @@ impl ChallengeMid {
 
 /// Low-degree expression representing an identity that must hold over the committed columns.
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ExpressionMid<F> {
     /// This is a constant polynomial
     Constant(F),
@@ -91,6 +96,47 @@ impl<F: Field> ExpressionMid<F> {
             Scaled(poly, _) => poly.degree(),
         }
     }
+
+    /// Evaluate the polynomial using the provided closures to perform the
+    /// operations.
+    #[allow(clippy::too_many_arguments)]
+    pub fn evaluate<T>(
+        &self,
+        constant: &impl Fn(F) -> T,
+        fixed_column: &impl Fn(FixedQueryMid) -> T,
+        advice_column: &impl Fn(AdviceQueryMid) -> T,
+        instance_column: &impl Fn(InstanceQueryMid) -> T,
+        challenge: &impl Fn(ChallengeMid) -> T,
+        negated: &impl Fn(T) -> T,
+        sum: &impl Fn(T, T) -> T,
+        product: &impl Fn(T, T) -> T,
+        scaled: &impl Fn(T, F) -> T,
+    ) -> T {
+        let evaluate = |expr: &ExpressionMid<F>| {
+            expr.evaluate(
+                constant,
+                fixed_column,
+                advice_column,
+                instance_column,
+                challenge,
+                negated,
+                sum,
+                product,
+                scaled,
+            )
+        };
+        match self {
+            ExpressionMid::Constant(scalar) => constant(*scalar),
+            ExpressionMid::Fixed(query) => fixed_column(*query),
+            ExpressionMid::Advice(query) => advice_column(*query),
+            ExpressionMid::Instance(query) => instance_column(*query),
+            ExpressionMid::Challenge(value) => challenge(*value),
+            ExpressionMid::Negated(a) => negated(evaluate(a)),
+            ExpressionMid::Sum(a, b) => sum(evaluate(a), evaluate(b)),
+            ExpressionMid::Product(a, b) => product(evaluate(a), evaluate(b)),
+            ExpressionMid::Scaled(a, f) => scaled(evaluate(a), *f),
+        }
+    }
 }
 
 /// A Gate contains a single polynomial identity with a name as metadata.
@@ -144,6 +190,52 @@ pub struct ConstraintSystemV2Backend<F: Field> {
 
     // List of indexes of Fixed columns which are associated to a circuit-general Column tied to their annotation.
     pub general_column_annotations: HashMap<metadata::Column, String>,
+
+    /// Minimum degree forced on the constraint system, if any, so that the evaluation
+    /// domain is at least as large as for a circuit of that degree.
+    pub minimum_degree: Option<usize>,
+}
+
+impl<F: Field> ConstraintSystemV2Backend<F> {
+    /// Returns the number of fixed columns
+    pub fn num_fixed_columns(&self) -> usize {
+        self.num_fixed_columns
+    }
+
+    /// Returns the number of advice columns
+    pub fn num_advice_columns(&self) -> usize {
+        self.num_advice_columns
+    }
+
+    /// Returns the number of instance columns
+    pub fn num_instance_columns(&self) -> usize {
+        self.num_instance_columns
+    }
+
+    /// Returns the number of challenges
+    pub fn num_challenges(&self) -> usize {
+        self.num_challenges
+    }
+
+    /// Returns gates
+    pub fn gates(&self) -> &[GateV2Backend<F>] {
+        &self.gates
+    }
+
+    /// Returns permutation argument
+    pub fn permutation(&self) -> &permutation::ArgumentV2 {
+        &self.permutation
+    }
+
+    /// Returns lookup arguments
+    pub fn lookups(&self) -> &[lookup::ArgumentV2<F>] {
+        &self.lookups
+    }
+
+    /// Returns shuffle arguments
+    pub fn shuffles(&self) -> &[shuffle::ArgumentV2<F>] {
+        &self.shuffles
+    }
 }
 
 /// Data that needs to be preprocessed from a circuit
@@ -161,6 +253,24 @@ pub struct CompiledCircuitV2<F: Field> {
     pub cs: ConstraintSystemV2Backend<F>,
 }
 
+impl<F: Field> CompiledCircuitV2<F> {
+    /// Returns the pairs `(i, j)` with `i < j` of fixed column indices whose assigned
+    /// values are element-wise equal. Such columns (typically selectors with the same
+    /// activation pattern) are redundant and could be merged.
+    pub fn identical_fixed_columns(&self) -> Vec<(usize, usize)> {
+        let fixed = &self.preprocessing.fixed;
+        let mut pairs = Vec::new();
+        for (i, lhs) in fixed.iter().enumerate() {
+            for (j, rhs) in fixed.iter().enumerate().skip(i + 1) {
+                if lhs == rhs {
+                    pairs.push((i, j));
+                }
+            }
+        }
+        pairs
+    }
+}
+
 // TODO: The query_cell method is only used in the frontend, which uses Expression.  By having this
 // trait implemented here we can only return ExpressionMid, which requires conversion to Expression
 // when used.  On the other hand, it's difficult to move ColumnType to the frontend because this
@@ -186,7 +296,7 @@ pub struct ColumnMid {
 }
 
 /// A cell identifies a position in the plonkish matrix identified by a column and a row offset.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Cell {
     pub column: ColumnMid,
     pub row: usize,
@@ -355,3 +465,132 @@ impl From<Instance> for Any {
         Any::Instance
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AdviceQueryMid, ChallengeMid, CompiledCircuitV2, ConstraintSystemV2Backend, ExpressionMid,
+        FixedQueryMid, GateV2Backend, InstanceQueryMid, PreprocessingV2,
+    };
+    use crate::permutation::{ArgumentV2, AssemblyMid};
+    use crate::poly::Rotation;
+    use ff::Field;
+    use halo2curves::bn256::Fr;
+    use std::collections::HashMap;
+
+    #[test]
+    fn v2_backend_getters() {
+        let poly = ExpressionMid::Product(
+            Box::new(ExpressionMid::Fixed(FixedQueryMid {
+                column_index: 0,
+                rotation: Rotation::cur(),
+            })),
+            Box::new(ExpressionMid::Advice(AdviceQueryMid {
+                column_index: 0,
+                rotation: Rotation::next(),
+                phase: 0,
+            })),
+        );
+        let cs = ConstraintSystemV2Backend::<Fr> {
+            num_fixed_columns: 1,
+            num_advice_columns: 1,
+            num_instance_columns: 0,
+            num_challenges: 0,
+            unblinded_advice_columns: vec![],
+            advice_column_phase: vec![0],
+            challenge_phase: vec![],
+            gates: vec![GateV2Backend {
+                name: "gate".to_string(),
+                poly: poly.clone(),
+            }],
+            permutation: ArgumentV2 { columns: vec![] },
+            lookups: vec![],
+            shuffles: vec![],
+            general_column_annotations: HashMap::new(),
+            minimum_degree: None,
+        };
+
+        assert_eq!(cs.num_fixed_columns(), 1);
+        assert_eq!(cs.num_advice_columns(), 1);
+        assert_eq!(cs.num_instance_columns(), 0);
+        assert_eq!(cs.gates().len(), 1);
+        assert_eq!(cs.gates()[0].name(), "gate");
+        assert_eq!(cs.gates()[0].polynomial(), &poly);
+        assert!(cs.lookups().is_empty());
+        assert!(cs.shuffles().is_empty());
+    }
+
+    #[test]
+    fn identical_fixed_columns() {
+        let selector = vec![Fr::ONE, Fr::ZERO, Fr::ONE, Fr::ZERO];
+        let distinct = vec![Fr::ONE, Fr::ONE, Fr::ZERO, Fr::ZERO];
+        let circuit = CompiledCircuitV2 {
+            preprocessing: PreprocessingV2 {
+                permutation: AssemblyMid { copies: vec![] },
+                fixed: vec![selector.clone(), distinct, selector],
+            },
+            cs: ConstraintSystemV2Backend {
+                num_fixed_columns: 3,
+                num_advice_columns: 0,
+                num_instance_columns: 0,
+                num_challenges: 0,
+                unblinded_advice_columns: vec![],
+                advice_column_phase: vec![],
+                challenge_phase: vec![],
+                gates: vec![],
+                permutation: ArgumentV2 { columns: vec![] },
+                lookups: vec![],
+                shuffles: vec![],
+                general_column_annotations: HashMap::new(),
+                minimum_degree: None,
+            },
+        };
+
+        assert_eq!(circuit.identical_fixed_columns(), vec![(0, 2)]);
+    }
+
+    #[test]
+    fn evaluate_degree() {
+        let fixed = ExpressionMid::<Fr>::Fixed(FixedQueryMid {
+            column_index: 0,
+            rotation: Rotation::cur(),
+        });
+        let advice = ExpressionMid::Advice(AdviceQueryMid {
+            column_index: 1,
+            rotation: Rotation::next(),
+            phase: 0,
+        });
+        let instance = ExpressionMid::Instance(InstanceQueryMid {
+            column_index: 0,
+            rotation: Rotation::prev(),
+        });
+        let challenge = ExpressionMid::Challenge(ChallengeMid { index: 0, phase: 0 });
+        let expr = ExpressionMid::Sum(
+            Box::new(ExpressionMid::Product(
+                Box::new(ExpressionMid::Product(
+                    Box::new(fixed),
+                    Box::new(ExpressionMid::Negated(Box::new(advice))),
+                )),
+                Box::new(ExpressionMid::Scaled(Box::new(instance), Fr::from(3))),
+            )),
+            Box::new(ExpressionMid::Product(
+                Box::new(challenge),
+                Box::new(ExpressionMid::Constant(Fr::ONE)),
+            )),
+        );
+
+        let degree = expr.evaluate(
+            &|_| 0,
+            &|_| 1,
+            &|_| 1,
+            &|_| 1,
+            &|_| 0,
+            &|a| a,
+            &|a, b| a.max(b),
+            &|a, b| a + b,
+            &|a, _| a,
+        );
+        assert_eq!(degree, 3);
+        assert_eq!(degree, expr.degree());
+    }
+}
@@ -0,0 +1,190 @@
+#[macro_use]
+extern crate criterion;
+
+use halo2_common::plonk::{evaluate_many, AdviceQuery, FixedQuery};
+use halo2_proofs::plonk::*;
+use halo2_proofs::poly::Rotation;
+use halo2curves::pasta::pallas;
+
+use criterion::{BenchmarkId, Criterion};
+
+fn criterion_benchmark(c: &mut Criterion) {
+    /// Builds a sum of `terms` scaled copies of `(a * b + f) * (a * b + f)`, so that
+    /// the same subexpressions are repeated many times.
+    fn repeated_expression(terms: u64) -> Expression<pallas::Base> {
+        let a = Column::new(0, Advice::default());
+        let b = Column::new(1, Advice::default());
+        let f = Column::new(0, Fixed);
+        let ab = a.query_cell::<pallas::Base>(Rotation::cur()) * b.query_cell(Rotation::cur());
+        let term =
+            (ab.clone() + f.query_cell(Rotation::cur())) * (ab + f.query_cell(Rotation::cur()));
+        Expression::balanced_sum((1..=terms).map(|i| term.clone() * pallas::Base::from(i)))
+    }
+
+    let size = 1 << 10;
+    let column = |seed: u64| -> Vec<pallas::Base> {
+        (0..size)
+            .map(|i| pallas::Base::from(seed * size + i))
+            .collect()
+    };
+    let (a, b, f) = (column(1), column(2), column(3));
+    let zip = |x: Vec<pallas::Base>,
+               y: Vec<pallas::Base>,
+               op: fn(pallas::Base, pallas::Base) -> pallas::Base| {
+        x.into_iter()
+            .zip(y)
+            .map(|(x, y)| op(x, y))
+            .collect::<Vec<_>>()
+    };
+
+    macro_rules! evaluate {
+        ($expr:expr, $method:ident) => {
+            $expr.$method(
+                &|constant| vec![constant; size as usize],
+                &|_| unreachable!(),
+                &|_| f.clone(),
+                &|query| {
+                    if query.column_index == 0 {
+                        a.clone()
+                    } else {
+                        b.clone()
+                    }
+                },
+                &|_| unreachable!(),
+                &|_| unreachable!(),
+                &|x| x.into_iter().map(|x| -x).collect(),
+                &|x, y| zip(x, y, |x, y| x + y),
+                &|x, y| zip(x, y, |x, y| x * y),
+                &|x, scalar| x.into_iter().map(|x| x * scalar).collect(),
+            )
+        };
+    }
+
+    let mut group = c.benchmark_group("expression-evaluate");
+    for terms in [4, 16, 64] {
+        let expr = repeated_expression(terms);
+        group.bench_with_input(BenchmarkId::new("evaluate", terms), &expr, |bench, expr| {
+            bench.iter(|| evaluate!(expr, evaluate));
+        });
+        group.bench_with_input(
+            BenchmarkId::new("evaluate_cse", terms),
+            &expr,
+            |bench, expr| {
+                bench.iter(|| evaluate!(expr, evaluate_cse));
+            },
+        );
+    }
+    group.finish();
+
+    /// Builds the polynomials of a gate set in the style of a standard PLONK circuit:
+    /// an arithmetic gate, boolean checks and accumulator constraints, all sharing the
+    /// same advice and fixed columns.
+    fn gate_set() -> Vec<Expression<pallas::Base>> {
+        let mut meta = ConstraintSystem::<pallas::Base>::default();
+        let [a, b, c] = [(); 3].map(|_| meta.advice_column());
+        let [sa, sb, sc, sm, s_bool, s_acc] = [(); 6].map(|_| meta.fixed_column());
+        meta.create_gate("Combined add-mult", |meta| {
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::cur());
+            let c = meta.query_advice(c, Rotation::cur());
+            let sa = meta.query_fixed(sa, Rotation::cur());
+            let sb = meta.query_fixed(sb, Rotation::cur());
+            let sc = meta.query_fixed(sc, Rotation::cur());
+            let sm = meta.query_fixed(sm, Rotation::cur());
+            vec![a.clone() * sa + b.clone() * sb + a * b * sm - (c * sc)]
+        });
+        meta.create_gate("Booleans", |meta| {
+            let s = meta.query_fixed(s_bool, Rotation::cur());
+            [a, b, c]
+                .map(|column| {
+                    let x = meta.query_advice(column, Rotation::cur());
+                    s.clone() * x.clone() * (Expression::Constant(pallas::Base::from(1)) - x)
+                })
+                .to_vec()
+        });
+        meta.create_gate("Running sum", |meta| {
+            let s = meta.query_fixed(s_acc, Rotation::cur());
+            let acc_cur = meta.query_advice(c, Rotation::cur());
+            let acc_next = meta.query_advice(c, Rotation::next());
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::cur());
+            vec![
+                s.clone() * (acc_next.clone() - acc_cur.clone() - a * b.clone()),
+                s * (acc_next - acc_cur * b),
+            ]
+        });
+        meta.gates()
+            .iter()
+            .flat_map(|gate| gate.polynomials().iter().cloned())
+            .collect()
+    }
+
+    let exprs = gate_set();
+    let at = |values: &[pallas::Base], row: usize, rotation: Rotation| {
+        values[(row as i64 + rotation.0 as i64).rem_euclid(size as i64) as usize]
+    };
+    let fixed: Vec<_> = (0..6).map(|i| column(10 + i)).collect();
+    let advice: Vec<_> = (0..3).map(|i| column(20 + i)).collect();
+
+    let mut group = c.benchmark_group("expression-evaluate-many");
+    group.bench_function("evaluate", |bench| {
+        bench.iter(|| {
+            (0..size as usize)
+                .map(|row| {
+                    let fixed_column =
+                        |query: FixedQuery| at(&fixed[query.column_index()], row, query.rotation());
+                    let advice_column = |query: AdviceQuery| {
+                        at(&advice[query.column_index()], row, query.rotation())
+                    };
+                    exprs
+                        .iter()
+                        .map(|expr| {
+                            expr.evaluate(
+                                &|constant| constant,
+                                &|_| unreachable!(),
+                                &fixed_column,
+                                &advice_column,
+                                &|_| unreachable!(),
+                                &|_| unreachable!(),
+                                &|x| -x,
+                                &|x, y| x + y,
+                                &|x, y| x * y,
+                                &|x, scalar| x * scalar,
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        });
+    });
+    group.bench_function("evaluate_many", |bench| {
+        bench.iter(|| {
+            (0..size as usize)
+                .map(|row| {
+                    let fixed_column =
+                        |query: FixedQuery| at(&fixed[query.column_index()], row, query.rotation());
+                    let advice_column = |query: AdviceQuery| {
+                        at(&advice[query.column_index()], row, query.rotation())
+                    };
+                    evaluate_many(
+                        &exprs,
+                        &|constant| constant,
+                        &|_| unreachable!(),
+                        &fixed_column,
+                        &advice_column,
+                        &|_| unreachable!(),
+                        &|_| unreachable!(),
+                        &|x| -x,
+                        &|x, y| x + y,
+                        &|x, y| x * y,
+                        &|x, scalar| x * scalar,
+                    )
+                })
+                .collect::<Vec<_>>()
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);